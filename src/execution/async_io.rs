@@ -0,0 +1,251 @@
+use super::{
+    suspend::{Execution, Resumable},
+    RuntimeResultCode,
+};
+use futures_io::{AsyncRead, AsyncWrite};
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    future::{poll_fn, Future},
+    io::{self, ErrorKind, Read, Write},
+    pin::{pin, Pin},
+    rc::Rc,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+};
+
+#[derive(Default)]
+struct Pipe {
+    buffer: VecDeque<u8>,
+    closed: bool,
+}
+
+struct PipeReader(Rc<RefCell<Pipe>>);
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = self.0.borrow_mut();
+        if pipe.buffer.is_empty() && !pipe.closed {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        pipe.buffer.read(buf)
+    }
+}
+
+struct PipeWriter(Rc<RefCell<Vec<u8>>>);
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Connects a suspendable program to async streams.
+/// The program has to read from `input` and write to `output`, which never block.
+/// Instead, a read without data suspends the program until more input arrived.
+#[derive(Default)]
+pub struct AsyncAdapter {
+    input: Rc<RefCell<Pipe>>,
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl AsyncAdapter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn input(&self) -> Box<dyn Read> {
+        Box::new(PipeReader(self.input.clone()))
+    }
+
+    pub fn output(&self) -> Box<dyn Write> {
+        Box::new(PipeWriter(self.output.clone()))
+    }
+
+    /// Runs `program` to completion. Its output is written to `output` whenever it suspends,
+    /// and a program waiting for input suspends until `input` delivered more.
+    /// A program that used up its slice lets other tasks run before it resumes.
+    /// Failing streams stop the program with `IoError`.
+    pub async fn run<R: Resumable>(
+        &self,
+        program: R,
+        mut input: impl AsyncRead + Unpin,
+        mut output: impl AsyncWrite + Unpin,
+    ) -> (R, RuntimeResultCode) {
+        let mut execution = program.start();
+        loop {
+            let flushed = self.flush(&mut output).await;
+            let suspended = match execution {
+                Execution::Finished(program, result) => {
+                    return (
+                        program,
+                        flushed.map_or(RuntimeResultCode::IoError, |_| result),
+                    );
+                }
+                Execution::Suspended(suspended) => suspended,
+            };
+            let ready = match flushed {
+                Ok(_) if suspended.reason() == RuntimeResultCode::InputPending => {
+                    self.receive(&mut input).await
+                }
+                Ok(_) => {
+                    yield_now().await;
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            };
+            if ready.is_err() {
+                return (suspended.into_program(), RuntimeResultCode::IoError);
+            }
+            execution = suspended.resume();
+        }
+    }
+
+    async fn receive(&self, input: &mut (impl AsyncRead + Unpin)) -> io::Result<()> {
+        let mut buffer = [0; 4096];
+        let read = poll_fn(|cx| Pin::new(&mut *input).poll_read(cx, &mut buffer)).await?;
+        let mut pipe = self.input.borrow_mut();
+        if read == 0 {
+            pipe.closed = true;
+        } else {
+            pipe.buffer.extend(&buffer[..read]);
+        }
+        Ok(())
+    }
+
+    async fn flush(&self, output: &mut (impl AsyncWrite + Unpin)) -> io::Result<()> {
+        let pending = std::mem::take(&mut *self.output.borrow_mut());
+        let mut written = 0;
+        while written < pending.len() {
+            let count =
+                poll_fn(|cx| Pin::new(&mut *output).poll_write(cx, &pending[written..])).await?;
+            if count == 0 {
+                return Err(ErrorKind::WriteZero.into());
+            }
+            written += count;
+        }
+        poll_fn(|cx| Pin::new(&mut *output).poll_flush(cx)).await
+    }
+}
+
+/// Returns `Pending` once, so that the executor can run other tasks first.
+fn yield_now() -> impl Future<Output = ()> {
+    let mut yielded = false;
+    poll_fn(move |cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+}
+
+/// Lets a blocking stream stand in for an async one, like stdin and stdout in the CLI.
+pub struct Blocking<T>(pub T);
+
+impl<T: Read + Unpin> AsyncRead for Blocking<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.get_mut().0.read(buf))
+    }
+}
+
+impl<T: Write + Unpin> AsyncWrite for Blocking<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.get_mut().0.write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.get_mut().0.flush())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Runs `future` on the current thread, for hosts without an async runtime.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::interpreter::Interpreter;
+    use crate::execution::suspend::InterpreterRun;
+    use crate::execution::tape::{DenseTape, TapeConfig};
+    use crate::execution::ExecutionLimits;
+    use crate::syntax::parse;
+
+    /// Delivers its bytes one read at a time.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let count = self.0.len().min(buf.len()).min(1);
+            buf[..count].copy_from_slice(&self.0[..count]);
+            self.0 = &self.0[count..];
+            Ok(count)
+        }
+    }
+
+    /// Runs a program that adds four to every byte up to a zero byte, with slices of a few steps.
+    fn run(input: &[u8]) -> (RuntimeResultCode, Vec<u8>) {
+        let nodes = parse(",[>++++[<+>-]<.,]").unwrap();
+        let adapter = AsyncAdapter::new();
+        let limits = ExecutionLimits {
+            slice: Some(3),
+            ..Default::default()
+        };
+        let program = InterpreterRun {
+            nodes: &nodes,
+            interpreter: Interpreter::<DenseTape>::new(
+                adapter.input(),
+                adapter.output(),
+                TapeConfig::new(8),
+                limits,
+            ),
+        };
+        let mut output = Vec::new();
+        let (_, result) =
+            block_on(adapter.run(program, Blocking(Trickle(input)), Blocking(&mut output)));
+        (result, output)
+    }
+
+    #[test]
+    fn runs_suspending_programs() {
+        assert_eq!(run(b"abc\0"), (RuntimeResultCode::Ok, b"efg".to_vec()));
+        // Input that ends early stops the program at the next read.
+        assert_eq!(run(b"ab"), (RuntimeResultCode::IoError, b"ef".to_vec()));
+    }
+}
@@ -1,49 +1,236 @@
-use crate::syntax::Instruction;
-use std::io::{Read, Write};
+use super::{
+    interrupt::Interrupt,
+    tape::{DenseTape, Tape, TapeBounds, TapeConfig},
+    ExecutionLimits, RuntimeResultCode,
+};
+use crate::syntax::{Instruction, Term};
+use std::io::{ErrorKind, Read, Write};
 
-pub struct Interpreter<'a> {
+pub struct Interpreter<'a, T: Tape = DenseTape> {
     input: Box<dyn Read + 'a>,
     output: Box<dyn Write + 'a>,
-    tape: Box<[u8]>,
+    tape: T,
     pointer: usize,
+    bounds: TapeBounds,
+    bidirectional: bool,
+    limits: ExecutionLimits,
+    /// Steps left before execution stops with `OutOfFuel`.
+    fuel: u64,
+    /// Steps left before execution suspends with `Yielded`.
+    slice: u64,
+    /// Where a suspended program continues, as instruction indices from the innermost loop outwards.
+    /// An index past the end of a loop body continues with the check of that loop.
+    resume_path: Vec<usize>,
+    interrupt: Interrupt,
 }
 
-impl<'a> Interpreter<'a> {
-    pub fn new(input: Box<dyn Read + 'a>, output: Box<dyn Write + 'a>, tape_size: usize) -> Self {
+impl<'a, T: Tape> Interpreter<'a, T> {
+    pub fn new(
+        input: Box<dyn Read + 'a>,
+        output: Box<dyn Write + 'a>,
+        tape: TapeConfig,
+        limits: ExecutionLimits,
+    ) -> Self {
         Interpreter {
             input,
             output,
-            tape: vec![0; tape_size].into_boxed_slice(),
-            pointer: 0,
+            tape: T::with_size(tape.size),
+            pointer: tape.start_offset(),
+            bounds: tape.bounds,
+            bidirectional: tape.bidirectional,
+            limits,
+            fuel: limits.fuel(),
+            slice: u64::MAX,
+            resume_path: Vec::new(),
+            interrupt: Interrupt::new(),
         }
     }
 
-    pub fn interpret(&mut self, instructions: &[Instruction]) {
-        for instruction in instructions {
+    /// Runs `instructions`. After a suspension, runs the same instructions again to resume.
+    pub fn interpret(&mut self, instructions: &[Instruction]) -> RuntimeResultCode {
+        self.slice = self.limits.slice.unwrap_or(u64::MAX);
+        self.run(instructions)
+    }
+
+    fn run(&mut self, instructions: &[Instruction]) -> RuntimeResultCode {
+        let start = self.resume_path.pop().unwrap_or(0);
+        for (index, instruction) in instructions.iter().enumerate().skip(start) {
+            // Resuming inside a loop, which was already paid for.
+            let resuming = !self.resume_path.is_empty();
+            if !resuming {
+                if !self.consume_slice() {
+                    self.resume_path.push(index);
+                    return RuntimeResultCode::Yielded;
+                }
+                if !self.consume_fuel() {
+                    return RuntimeResultCode::OutOfFuel;
+                }
+            }
             match instruction {
                 Instruction::Add { value } => {
-                    self.tape[self.pointer] = self.tape[self.pointer].wrapping_add_signed(value.0);
+                    let cell = self.tape.get(self.pointer);
+                    self.tape
+                        .set(self.pointer, cell.wrapping_add_signed(value.0));
                 }
-                Instruction::Move { value } => {
-                    self.pointer = (self.pointer as isize + *value as isize) as usize;
+                Instruction::Move { value } | Instruction::MoveUnchecked { value } => {
+                    let target = self.pointer as isize + *value as isize;
+                    if target >= 0 && (target as usize) < self.tape.size() {
+                        self.pointer = target as usize;
+                    } else if self.bounds == TapeBounds::Wrap {
+                        self.pointer = target.rem_euclid(self.tape.size() as isize) as usize;
+                    } else if target < 0 && self.bidirectional {
+                        let missing = target.unsigned_abs();
+                        let grown = self.tape.grow_front(missing);
+                        self.pointer = grown - missing;
+                    } else {
+                        return RuntimeResultCode::CellOutOfBounds;
+                    }
                 }
                 Instruction::Loop { nodes } => {
-                    while self.tape[self.pointer] != 0 {
-                        self.interpret(nodes);
+                    let mut entered = resuming;
+                    while entered || self.tape.get(self.pointer) != 0 {
+                        entered = false;
+                        let result = self.run(nodes);
+                        if result.is_suspended() {
+                            self.resume_path.push(index);
+                        }
+                        if result != RuntimeResultCode::Ok {
+                            return result;
+                        }
+                        if !self.consume_slice() {
+                            self.resume_path.extend([nodes.len(), index]);
+                            return RuntimeResultCode::Yielded;
+                        }
+                        if !self.consume_fuel() {
+                            return RuntimeResultCode::OutOfFuel;
+                        }
+                        if self.limits.polls_interrupt() {
+                            if let Some(reason) = self.interrupt.get() {
+                                return reason;
+                            }
+                        }
+                    }
+                }
+                Instruction::If { nodes } => {
+                    if resuming || self.tape.get(self.pointer) != 0 {
+                        let result = self.run(nodes);
+                        if result.is_suspended() {
+                            self.resume_path.push(index);
+                        }
+                        if result != RuntimeResultCode::Ok {
+                            return result;
+                        }
+                    }
+                }
+                Instruction::Idiom { idiom, nodes } => {
+                    if !resuming {
+                        let mut output = Vec::new();
+                        if self.tape.apply_idiom(*idiom, self.pointer, &mut output) {
+                            if self.output.write_all(&output).is_err() {
+                                return RuntimeResultCode::IoError;
+                            }
+                            continue;
+                        }
+                    }
+                    let result = self.run(nodes);
+                    if result.is_suspended() {
+                        self.resume_path.push(index);
+                    }
+                    if result != RuntimeResultCode::Ok {
+                        return result;
                     }
                 }
                 Instruction::Write => {
-                    self.output.write_all(&[self.tape[self.pointer]]).unwrap();
+                    if self
+                        .output
+                        .write_all(&[self.tape.get(self.pointer)])
+                        .is_err()
+                    {
+                        return RuntimeResultCode::IoError;
+                    }
+                }
+                Instruction::WriteBytes { bytes } => {
+                    if self.output.write_all(bytes).is_err() {
+                        return RuntimeResultCode::IoError;
+                    }
                 }
                 Instruction::Read => {
                     let mut buffer = [0];
-                    self.input.read_exact(&mut buffer).unwrap();
-                    self.tape[self.pointer] = buffer[0];
+                    match self.input.read_exact(&mut buffer) {
+                        Ok(_) => self.tape.set(self.pointer, buffer[0]),
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                            // The read is repeated on resume, so it does not count yet.
+                            self.fuel += 1;
+                            self.resume_path.push(index);
+                            return RuntimeResultCode::InputPending;
+                        }
+                        Err(_) => return RuntimeResultCode::IoError,
+                    }
                 }
                 Instruction::Set { value } => {
-                    self.tape[self.pointer] = *value;
+                    self.tape.set(self.pointer, *value);
+                }
+                Instruction::SetRange { offset, values } => {
+                    let start = self.pointer.wrapping_add_signed(*offset as isize);
+                    for (index, value) in values.iter().enumerate() {
+                        self.tape.set(start + index, *value);
+                    }
+                }
+                Instruction::AddVector { offset, values } => {
+                    let start = self.pointer.wrapping_add_signed(*offset as isize);
+                    for (index, value) in values.iter().enumerate() {
+                        let cell = self.tape.get(start + index);
+                        self.tape
+                            .set(start + index, cell.wrapping_add_signed(*value));
+                    }
+                }
+                Instruction::MulAdd { offset, terms } => {
+                    let sum = Term::sum(terms, |factor| {
+                        self.tape
+                            .get(self.pointer.wrapping_add_signed(factor as isize))
+                    });
+                    let cell = self.pointer.wrapping_add_signed(*offset as isize);
+                    self.tape.set(cell, self.tape.get(cell).wrapping_add(sum));
                 }
             }
         }
+        RuntimeResultCode::Ok
+    }
+
+    #[inline(always)]
+    fn consume_slice(&mut self) -> bool {
+        if self.slice == 0 {
+            return false;
+        }
+        self.slice -= 1;
+        true
+    }
+
+    #[inline(always)]
+    fn consume_fuel(&mut self) -> bool {
+        if self.fuel == 0 {
+            return false;
+        }
+        self.fuel -= 1;
+        true
+    }
+
+    /// The number of steps executed so far.
+    pub fn steps(&self) -> u64 {
+        self.limits.fuel() - self.fuel
+    }
+
+    /// The cell index of the pointer.
+    pub fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    /// A handle to stop the program from another thread.
+    pub fn interrupt(&self) -> Interrupt {
+        self.interrupt.clone()
+    }
+
+    pub fn tape(&self) -> &T {
+        &self.tape
     }
 }
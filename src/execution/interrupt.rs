@@ -0,0 +1,76 @@
+use super::RuntimeResultCode;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// A flag that stops a running program at its next loop iteration.
+/// Holds the result code the program stops with, or zero while it may keep running.
+#[derive(Clone, Default)]
+pub struct Interrupt(Arc<AtomicU8>);
+
+impl Interrupt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks the program to stop with `reason`.
+    pub fn trigger(&self, reason: RuntimeResultCode) {
+        self.0.store(reason as u8, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<RuntimeResultCode> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            code => RuntimeResultCode::try_from(code).ok(),
+        }
+    }
+
+    /// The address of the flag, polled directly by native code.
+    pub(crate) fn as_ptr(&self) -> *const u8 {
+        self.0.as_ptr()
+    }
+
+    /// Triggers a `Timeout` once `timeout` has passed, unless the returned watchdog was dropped before.
+    pub fn watchdog(&self, timeout: Duration) -> Watchdog {
+        let interrupt = self.clone();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let cancelled = cancelled.clone();
+            thread::spawn(move || {
+                let deadline = Instant::now() + timeout;
+                while !cancelled.load(Ordering::Relaxed) {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        interrupt.trigger(RuntimeResultCode::Timeout);
+                        return;
+                    }
+                    thread::park_timeout(deadline - now);
+                }
+            })
+        };
+        Watchdog {
+            cancelled,
+            thread: Some(thread),
+        }
+    }
+}
+
+pub struct Watchdog {
+    cancelled: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
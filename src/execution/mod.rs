@@ -1,2 +1,431 @@
+pub mod async_io;
 pub mod interpreter;
+pub mod interrupt;
 pub mod native;
+pub mod suspend;
+pub mod tape;
+pub mod tiered;
+pub mod trace;
+pub mod vm;
+
+use std::time::Duration;
+
+/// Limits on how long a program may run.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExecutionLimits {
+    /// Stop with `OutOfFuel` after this many steps.
+    /// The interpreter counts instructions and loop checks, native code counts loop iterations.
+    pub max_steps: Option<u64>,
+    /// Stop with `Timeout` after this much time. Needs a watchdog, see `Interrupt::watchdog`.
+    pub timeout: Option<Duration>,
+    /// Poll the `Interrupt` flag on every loop iteration.
+    pub interruptible: bool,
+    /// Suspend with `Yielded` after this many steps, counted like `max_steps`.
+    /// Every resume grants the same number of steps again.
+    pub slice: Option<u64>,
+}
+
+impl ExecutionLimits {
+    /// Whether loops have to check the `Interrupt` flag.
+    pub fn polls_interrupt(&self) -> bool {
+        self.interruptible || self.timeout.is_some()
+    }
+
+    /// The fuel a run starts with.
+    pub fn fuel(&self) -> u64 {
+        self.max_steps.unwrap_or(u64::MAX)
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeResultCode {
+    Ok = 0,
+    IoError = 1,
+    CellOutOfBounds = 2,
+    OutOfFuel = 3,
+    Timeout = 4,
+    Interrupted = 5,
+    /// A read found no input available yet. The read is repeated on resume.
+    InputPending = 6,
+    /// The program used up the steps of its slice.
+    Yielded = 7,
+}
+
+impl RuntimeResultCode {
+    pub fn is_error(self) -> bool {
+        self != RuntimeResultCode::Ok && !self.is_suspended()
+    }
+
+    /// Whether the program stopped early and can be resumed where it left off.
+    pub fn is_suspended(self) -> bool {
+        matches!(
+            self,
+            RuntimeResultCode::InputPending | RuntimeResultCode::Yielded
+        )
+    }
+}
+
+impl TryFrom<u8> for RuntimeResultCode {
+    type Error = &'static str;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(RuntimeResultCode::Ok),
+            1 => Ok(RuntimeResultCode::IoError),
+            2 => Ok(RuntimeResultCode::CellOutOfBounds),
+            3 => Ok(RuntimeResultCode::OutOfFuel),
+            4 => Ok(RuntimeResultCode::Timeout),
+            5 => Ok(RuntimeResultCode::Interrupted),
+            6 => Ok(RuntimeResultCode::InputPending),
+            7 => Ok(RuntimeResultCode::Yielded),
+            _ => Err("Invalid result code"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::interpreter::Interpreter;
+    use super::interrupt::Interrupt;
+    use super::native::{codegen::CodeGeneration, executor::NativeExecutor, state::State};
+    use super::tape::{StartOffset, Tape, TapeBounds, TapeConfig};
+    use super::vm::{Program, Vm};
+    use super::*;
+    use crate::optimize::bounds::EliminateBoundsChecks;
+    use crate::optimize::peephole::CombineIncrements;
+    use crate::optimize::OptimizationPass;
+    use crate::syntax::{parse, Instruction};
+    use std::cell::{OnceCell, RefCell};
+    use std::io::Write;
+    use std::rc::Rc;
+
+    /// What a run left behind. The pointer is unknown after `CellOutOfBounds`.
+    #[derive(Debug, PartialEq)]
+    pub(super) struct Outcome {
+        pub result: RuntimeResultCode,
+        pub pointer: Option<usize>,
+        pub steps: Option<u64>,
+        pub tape: Vec<u8>,
+        pub output: Vec<u8>,
+    }
+
+    impl Outcome {
+        fn new(
+            result: RuntimeResultCode,
+            pointer: usize,
+            steps: Option<u64>,
+            tape: Vec<u8>,
+            output: Vec<u8>,
+        ) -> Self {
+            let pointer = (result != RuntimeResultCode::CellOutOfBounds).then_some(pointer);
+            Outcome {
+                result,
+                pointer,
+                steps,
+                tape,
+                output,
+            }
+        }
+    }
+
+    pub(super) fn interpret(
+        nodes: &[Instruction],
+        tape: TapeConfig,
+        limits: ExecutionLimits,
+        input: &[u8],
+    ) -> Outcome {
+        let mut output = Vec::new();
+        let mut interpreter: Interpreter =
+            Interpreter::new(Box::new(input), Box::new(&mut output), tape, limits);
+        let result = interpreter.interpret(nodes);
+        let cells = interpreter.tape();
+        let cells = (0..cells.size()).map(|index| cells.get(index)).collect();
+        let (pointer, steps) = (interpreter.pointer(), interpreter.steps());
+        drop(interpreter);
+        Outcome::new(result, pointer, Some(steps), cells, output)
+    }
+
+    pub(super) fn run_vm(
+        nodes: &[Instruction],
+        tape: TapeConfig,
+        limits: ExecutionLimits,
+        input: &[u8],
+    ) -> Outcome {
+        let program = Program::compile(nodes);
+        let mut output = Vec::new();
+        let mut vm: Vm = Vm::new(Box::new(input), Box::new(&mut output), tape, limits);
+        let result = vm.run(&program);
+        let cells = vm.tape();
+        let cells = (0..cells.size()).map(|index| cells.get(index)).collect();
+        let (pointer, steps) = (vm.pointer(), vm.steps());
+        drop(vm);
+        Outcome::new(result, pointer, Some(steps), cells, output)
+    }
+
+    /// Compiles `nodes` with every backend.
+    pub(super) fn compile(
+        nodes: &[Instruction],
+        tape: TapeConfig,
+        limits: ExecutionLimits,
+    ) -> [NativeExecutor; 2] {
+        [
+            CodeGeneration::x86_x64(tape, limits).generate(nodes),
+            CodeGeneration::cranelift(tape, limits).generate(nodes),
+        ]
+    }
+
+    pub(super) fn run_native(
+        executor: &NativeExecutor,
+        tape: TapeConfig,
+        limits: ExecutionLimits,
+        input: &[u8],
+    ) -> Outcome {
+        let mut output = Vec::new();
+        let mut state = State::new(Box::new(input), Box::new(&mut output), tape, limits);
+        let result = executor.run(&mut state);
+        let (pointer, steps, cells) = (state.pointer, state.steps(), state.tape.to_vec());
+        drop(state);
+        Outcome::new(result, pointer, steps, cells, output)
+    }
+
+    /// Runs `source` in every engine, unoptimized and with the passes that depend on the tape,
+    /// and checks that they all end the same. Returns the interpreter's outcome.
+    fn same_in_all_engines(source: &str, tape: TapeConfig) -> Outcome {
+        let limits = ExecutionLimits::default();
+        let nodes = parse(source).unwrap();
+        let combined = CombineIncrements.optimize(nodes.clone());
+        let checked = EliminateBoundsChecks { tape }.optimize(combined.clone());
+        let expected = interpret(&nodes, tape, limits, &[]);
+        for nodes in [nodes, combined, checked] {
+            let mut outcomes = vec![
+                interpret(&nodes, tape, limits, &[]),
+                run_vm(&nodes, tape, limits, &[]),
+            ];
+            for executor in compile(&nodes, tape, limits) {
+                outcomes.push(run_native(&executor, tape, limits, &[]));
+            }
+            for mut outcome in outcomes {
+                // Native code only counts steps with a step limit.
+                outcome.steps = expected.steps;
+                assert_eq!(outcome, expected, "{}", source);
+            }
+        }
+        expected
+    }
+
+    fn wrapping(size: usize) -> TapeConfig {
+        TapeConfig {
+            bounds: TapeBounds::Wrap,
+            ..TapeConfig::new(size)
+        }
+    }
+
+    fn bidirectional(size: usize) -> TapeConfig {
+        TapeConfig {
+            bidirectional: true,
+            ..TapeConfig::new(size)
+        }
+    }
+
+    #[test]
+    fn fuel_runs_out_after_the_same_steps() {
+        let nodes = parse("+[]").unwrap();
+        let tape = TapeConfig::new(1);
+        for max_steps in [0, 1, 2, 1000] {
+            let limits = ExecutionLimits {
+                max_steps: Some(max_steps),
+                ..Default::default()
+            };
+            let mut outcomes = vec![
+                interpret(&nodes, tape, limits, &[]),
+                run_vm(&nodes, tape, limits, &[]),
+            ];
+            for executor in compile(&nodes, tape, limits) {
+                outcomes.push(run_native(&executor, tape, limits, &[]));
+            }
+            for outcome in outcomes {
+                assert_eq!(outcome.result, RuntimeResultCode::OutOfFuel);
+                assert_eq!(outcome.steps, Some(max_steps));
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn guard_faults_name_the_command() {
+        use super::native::memory::page_size;
+        use crate::syntax::source_position;
+
+        let tape = TapeConfig {
+            guard_pages: true,
+            ..TapeConfig::new(page_size())
+        };
+        let limits = ExecutionLimits::default();
+        // The access after a move off the tape faults, or the loop check right after it.
+        let faults = [
+            ("+[>+]", page_size() as isize, (1, 4)),
+            ("++\n  >+<<+", -1, (2, 7)),
+            ("+++[>+++<-]>[<<]", -1, (1, 15)),
+        ];
+        for (source, cell, position) in faults {
+            let executor = CodeGeneration::x86_x64(tape, limits).generate(&parse(source).unwrap());
+            let mut state = State::new(
+                Box::new(std::io::empty()),
+                Box::new(std::io::sink()),
+                tape,
+                limits,
+            );
+            assert_eq!(executor.run(&mut state), RuntimeResultCode::CellOutOfBounds);
+            let fault = state.fault.unwrap();
+            assert_eq!(fault.cell, cell, "{}", source);
+            let instruction = fault.instruction.unwrap();
+            assert_eq!(
+                source_position(source, instruction),
+                Some(position),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn wrap_past_both_ends() {
+        let outcome =
+            same_in_all_engines("<+<<++>>>>>>>>>+++>>>>>>>>>>>>>>>>>>>>++++.", wrapping(8));
+        assert_eq!(outcome.tape, [0, 0, 4, 0, 0, 2, 3, 1]);
+        assert_eq!(outcome.pointer, Some(2));
+        same_in_all_engines(
+            &format!("{}+{}-", "<".repeat(100), ">".repeat(99)),
+            wrapping(7),
+        );
+    }
+
+    #[test]
+    fn wrap_in_loops() {
+        // Walks around the tape until a cell overflows.
+        let outcome = same_in_all_engines("+[>+]", wrapping(8));
+        assert!(outcome.tape.contains(&0));
+        // Moves a value from the first cell to the last and back.
+        let outcome = same_in_all_engines("+++++[-<+>]<[->>+<<]", wrapping(5));
+        assert_eq!(outcome.tape, [0, 5, 0, 0, 0]);
+        same_in_all_engines("++++++++[>+<<<+++>>-]>>[<<+>>>>>-]", wrapping(6));
+    }
+
+    #[test]
+    fn start_in_the_middle() {
+        let middle = |tape: TapeConfig| TapeConfig {
+            start: StartOffset::Middle,
+            ..tape
+        };
+        let outcome = same_in_all_engines("+<<<<<<<<<++>>>>>>>>>>+++", middle(wrapping(16)));
+        assert_eq!(outcome.pointer, Some(9));
+        assert_eq!(outcome.tape[15], 2);
+        let outcome = same_in_all_engines("+<<<<<<<<<++.", middle(TapeConfig::new(16)));
+        assert_eq!(outcome.result, RuntimeResultCode::CellOutOfBounds);
+        let outcome = same_in_all_engines("+<<<<<<<<<++.", middle(bidirectional(16)));
+        assert_eq!(outcome.output, [2]);
+        assert_eq!(outcome.tape[outcome.pointer.unwrap() + 9], 1);
+    }
+
+    #[test]
+    fn grow_left() {
+        let outcome = same_in_all_engines("+<++<<+++.", bidirectional(2));
+        assert_eq!(outcome.output, [3]);
+        // Grows on every iteration.
+        let outcome = same_in_all_engines("++++++++[[-<+>]<-]", bidirectional(1));
+        assert_eq!(outcome.pointer, Some(outcome.tape.len() - 9));
+        // Only the left end grows.
+        let outcome = same_in_all_engines("<+>>>+", bidirectional(2));
+        assert_eq!(outcome.result, RuntimeResultCode::CellOutOfBounds);
+    }
+
+    /// Output that stops the program once a complete line reaches it.
+    struct StopOnLine {
+        written: Rc<RefCell<Vec<u8>>>,
+        interrupt: Rc<OnceCell<Interrupt>>,
+    }
+
+    impl Write for StopOnLine {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.borrow_mut().extend_from_slice(buf);
+            if buf.contains(&b'\n') {
+                self.interrupt
+                    .get()
+                    .unwrap()
+                    .trigger(RuntimeResultCode::Interrupted);
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn lines_are_written_before_a_loop_that_never_ends() {
+        let tape = TapeConfig::new(8);
+        // The step limit only ends the run if the line stayed in the output buffer.
+        let limits = ExecutionLimits {
+            max_steps: Some(10_000_000),
+            interruptible: true,
+            ..Default::default()
+        };
+        let mut bytes = vec![Instruction::WriteBytes {
+            bytes: b"A\n".to_vec(),
+        }];
+        bytes.extend(parse("+[]").unwrap());
+        for nodes in [
+            parse("++++++++[>++++++++<-]>+.>++++++++++.+[]").unwrap(),
+            bytes,
+        ] {
+            for executor in compile(&nodes, tape, limits) {
+                let written = Rc::default();
+                let interrupt = Rc::new(OnceCell::new());
+                let output = StopOnLine {
+                    written: Rc::clone(&written),
+                    interrupt: Rc::clone(&interrupt),
+                };
+                let mut state = State::new(Box::new(&[][..]), Box::new(output), tape, limits);
+                interrupt.set(state.interrupt.clone()).ok().unwrap();
+                let result = executor.run(&mut state);
+                assert_eq!(result, RuntimeResultCode::Interrupted);
+                assert_eq!(*written.borrow(), b"A\n");
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_descriptors_are_written_on_every_output() {
+        let tape = TapeConfig::new(8);
+        let limits = ExecutionLimits {
+            max_steps: Some(10_000_000),
+            interruptible: true,
+            ..Default::default()
+        };
+        // No newline, so only writing on every output stops the loop before the step limit.
+        let nodes = parse("++++++++[>++++++++<-]>+.+[]").unwrap();
+        for executor in compile(&nodes, tape, limits) {
+            let mut fds = [0; 2];
+            assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+            let [read_end, write_end] = fds;
+            let mut state = State::with_fds(read_end, write_end, tape, limits);
+            let interrupt = state.interrupt.clone();
+            let reader = std::thread::spawn(move || {
+                let mut byte = 0u8;
+                let read = unsafe { libc::read(read_end, (&raw mut byte).cast(), 1) };
+                interrupt.trigger(RuntimeResultCode::Interrupted);
+                (read, byte)
+            });
+            let result = executor.run(&mut state);
+            assert_eq!(reader.join().unwrap(), (1, b'A'));
+            assert_eq!(result, RuntimeResultCode::Interrupted);
+            unsafe {
+                libc::close(read_end);
+                libc::close(write_end);
+            }
+        }
+    }
+}
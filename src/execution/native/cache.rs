@@ -0,0 +1,270 @@
+use super::executor::NativeExecutor;
+use crate::execution::{tape::TapeConfig, ExecutionLimits};
+use std::{
+    fs::{self, File},
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+};
+
+const MAGIC: &[u8; 8] = b"BJITCODE";
+
+/// Everything that influences the generated code. Cached code is only reused if all of it matches.
+pub struct CacheKey(String);
+
+impl CacheKey {
+    /// `pipeline` names the optimization passes in the order they run, `backend` the code generator.
+    /// `tape` is the configuration asked for, before `--auto-tape-size` picks a size.
+    pub fn new(
+        source: &str,
+        pipeline: &[&str],
+        backend: &str,
+        tape: TapeConfig,
+        limits: ExecutionLimits,
+    ) -> Self {
+        // The limits themselves live in `State`, the code only depends on which checks it contains.
+        CacheKey(format!(
+            "version={} build={} source={:016x}/{} pipeline={} backend={} tape={:?} interrupt={} fuel={} slice={} cpu={}",
+            env!("CARGO_PKG_VERSION"),
+            build_id(),
+            hash(source),
+            source.len(),
+            pipeline.join(","),
+            backend,
+            tape,
+            limits.polls_interrupt(),
+            limits.max_steps.is_some(),
+            limits.slice.is_some(),
+            cpu_features(),
+        ))
+    }
+}
+
+/// Identifies this build of brainjit, as generated code depends on the layout of `State`.
+fn build_id() -> String {
+    let metadata = std::env::current_exe().and_then(fs::metadata);
+    let modified = metadata
+        .as_ref()
+        .ok()
+        .and_then(|metadata| metadata.modified().ok());
+    match (metadata.as_ref().map(|metadata| metadata.len()), modified) {
+        (Ok(len), Some(modified)) => format!("{}@{:?}", len, modified),
+        _ => "unknown".to_string(),
+    }
+}
+
+fn hash(value: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(target_arch = "x86_64")]
+fn cpu_features() -> String {
+    use std::arch::is_x86_feature_detected;
+    let features = [
+        ("sse4.2", is_x86_feature_detected!("sse4.2")),
+        ("popcnt", is_x86_feature_detected!("popcnt")),
+        ("avx2", is_x86_feature_detected!("avx2")),
+        ("bmi2", is_x86_feature_detected!("bmi2")),
+        ("avx512f", is_x86_feature_detected!("avx512f")),
+    ];
+    let detected: Vec<_> = features
+        .iter()
+        .filter(|(_, detected)| *detected)
+        .map(|(name, _)| *name)
+        .collect();
+    format!("x86_64+{}", detected.join("+"))
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn cpu_features() -> String {
+    std::env::consts::ARCH.to_string()
+}
+
+/// A directory of compiled programs, one file per `CacheKey`.
+pub struct CodeCache {
+    dir: PathBuf,
+}
+
+impl CodeCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, key: &CacheKey) -> PathBuf {
+        self.dir.join(format!("{:016x}.bin", hash(&key.0)))
+    }
+
+    /// Loads the code stored under `key`, with the tape size it was compiled for.
+    /// Missing, damaged or foreign entries are misses.
+    pub fn load(&self, key: &CacheKey) -> Option<(NativeExecutor, usize)> {
+        let mut file = BufReader::new(File::open(self.path(key)).ok()?);
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic).ok()?;
+        let mut len = [0; 8];
+        file.read_exact(&mut len).ok()?;
+        let mut stored_key = vec![0; u64::from_le_bytes(len) as usize];
+        if magic != *MAGIC || stored_key.len() != key.0.len() {
+            return None;
+        }
+        file.read_exact(&mut stored_key).ok()?;
+        if stored_key != key.0.as_bytes() {
+            return None;
+        }
+        let mut tape_size = [0; 8];
+        file.read_exact(&mut tape_size).ok()?;
+        let executor = NativeExecutor::read_from(&mut file).ok()?;
+        Some((executor, u64::from_le_bytes(tape_size) as usize))
+    }
+
+    /// Stores `executor`, compiled for a tape of `tape_size` cells, under `key`. The entry is written to a temporary file first,
+    /// so that concurrent runs never load a partial entry.
+    pub fn store(
+        &self,
+        key: &CacheKey,
+        tape_size: usize,
+        executor: &NativeExecutor,
+    ) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(key);
+        let temp = path.with_extension(format!("{}.tmp", std::process::id()));
+        let mut file = BufWriter::new(File::create(&temp)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&(key.0.len() as u64).to_le_bytes())?;
+        file.write_all(key.0.as_bytes())?;
+        file.write_all(&(tape_size as u64).to_le_bytes())?;
+        executor.write_to(&mut file)?;
+        file.into_inner()?.sync_all()?;
+        fs::rename(temp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        execution::{
+            native::codegen::CodeGeneration,
+            tests::{run_native, Outcome},
+            RuntimeResultCode,
+        },
+        syntax,
+    };
+
+    const PROGRAM: &str = "++++++++[>++++++++<-]>+.+.+.";
+
+    fn compile(tape: TapeConfig, limits: ExecutionLimits) -> NativeExecutor {
+        let nodes = syntax::parse(PROGRAM).unwrap();
+        CodeGeneration::x86_x64(tape, limits).generate(&nodes)
+    }
+
+    fn run(executor: &NativeExecutor, tape: TapeConfig) -> Outcome {
+        run_native(executor, tape, ExecutionLimits::default(), &[])
+    }
+
+    fn key(
+        pipeline: &[&str],
+        backend: &str,
+        tape: TapeConfig,
+        limits: ExecutionLimits,
+    ) -> CacheKey {
+        CacheKey::new(PROGRAM, pipeline, backend, tape, limits)
+    }
+
+    /// A fresh, empty cache directory for one test.
+    fn cache(name: &str) -> CodeCache {
+        let dir =
+            std::env::temp_dir().join(format!("brainjit-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        CodeCache::new(dir)
+    }
+
+    #[test]
+    fn round_trips_code() {
+        let tape = TapeConfig::new(16);
+        let executor = compile(tape, ExecutionLimits::default());
+        let mut bytes = Vec::new();
+        executor.write_to(&mut bytes).unwrap();
+        let read = NativeExecutor::read_from(&mut &bytes[..]).unwrap();
+        assert_eq!(run(&read, tape), run(&executor, tape));
+        assert_eq!(run(&read, tape).result, RuntimeResultCode::Ok);
+        assert_eq!(run(&read, tape).output, b"ABC");
+
+        let cache = cache("round-trip");
+        let key = key(&[], "x86-64", tape, ExecutionLimits::default());
+        cache.store(&key, 16, &executor).unwrap();
+        let (loaded, tape_size) = cache.load(&key).unwrap();
+        assert_eq!(tape_size, 16);
+        assert_eq!(run(&loaded, tape), run(&executor, tape));
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn changed_keys_miss() {
+        let tape = TapeConfig::new(16);
+        let limits = ExecutionLimits::default();
+        let cache = cache("miss");
+        let stored = key(&["CombineIncrements"], "x86-64", tape, limits);
+        cache.store(&stored, 16, &compile(tape, limits)).unwrap();
+        assert!(cache.load(&stored).is_some());
+
+        let changed = [
+            key(&[], "x86-64", tape, limits),
+            key(
+                &["CombineIncrements", "CoalesceStores"],
+                "x86-64",
+                tape,
+                limits,
+            ),
+            key(&["CombineIncrements"], "cranelift", tape, limits),
+            key(
+                &["CombineIncrements"],
+                "x86-64",
+                TapeConfig::new(32),
+                limits,
+            ),
+            key(
+                &["CombineIncrements"],
+                "x86-64",
+                TapeConfig {
+                    bidirectional: true,
+                    ..tape
+                },
+                limits,
+            ),
+            key(
+                &["CombineIncrements"],
+                "x86-64",
+                tape,
+                ExecutionLimits {
+                    max_steps: Some(100),
+                    ..limits
+                },
+            ),
+            key(
+                &["CombineIncrements"],
+                "x86-64",
+                tape,
+                ExecutionLimits {
+                    interruptible: true,
+                    ..limits
+                },
+            ),
+            key(
+                &["CombineIncrements"],
+                "x86-64",
+                tape,
+                ExecutionLimits {
+                    slice: Some(1000),
+                    ..limits
+                },
+            ),
+            CacheKey::new("+", &["CombineIncrements"], "x86-64", tape, limits),
+        ];
+        for key in &changed {
+            assert!(cache.load(key).is_none(), "{}", key.0);
+        }
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
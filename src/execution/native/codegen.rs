@@ -1,71 +1,122 @@
-use super::{executor::NativeExecutor, x86_64::X86_64CodeGen};
-use crate::syntax::Instruction;
-use dynasmrt::{Assembler, AssemblyOffset};
+use super::{cranelift::CraneliftCodeGen, executor::NativeExecutor, x86_64::X86_64CodeGen};
+use crate::{
+    execution::{tape::TapeConfig, ExecutionLimits},
+    syntax::{Idiom, Instruction, Term},
+};
 
 pub struct CodeGeneration<B>
 where
     B: NativeCodeGenBackend,
 {
     codegen: B,
-    ops: dynasmrt::Assembler<B::Relocation>,
+    ops: B::Ops,
 }
 
 impl CodeGeneration<X86_64CodeGen> {
-    pub fn x86_x64() -> CodeGeneration<X86_64CodeGen> {
+    pub fn x86_x64(tape: TapeConfig, limits: ExecutionLimits) -> CodeGeneration<X86_64CodeGen> {
         CodeGeneration {
-            codegen: X86_64CodeGen,
+            codegen: X86_64CodeGen::new(tape, limits),
             ops: dynasmrt::x64::Assembler::new().unwrap(),
         }
     }
 }
 
+impl CodeGeneration<CraneliftCodeGen> {
+    /// Cranelift does not support guard pages, so `tape.guard_pages` must be false.
+    pub fn cranelift(
+        tape: TapeConfig,
+        limits: ExecutionLimits,
+    ) -> CodeGeneration<CraneliftCodeGen> {
+        let codegen = CraneliftCodeGen::new(tape, limits);
+        CodeGeneration {
+            ops: codegen.new_ops(),
+            codegen,
+        }
+    }
+}
+
 impl<B> CodeGeneration<B>
 where
     B: NativeCodeGenBackend,
 {
     pub fn generate(mut self, instrs: &[Instruction]) -> NativeExecutor {
-        let code_start = self.codegen.generate_prolouge(&mut self.ops);
+        self.codegen.generate_prolouge(&mut self.ops);
 
         for instr in instrs {
             self.codegen.generate_instruction(&mut self.ops, instr);
         }
 
         self.codegen.generate_epilouge(&mut self.ops);
-
-        match self.ops.finalize() {
-            Ok(code) => NativeExecutor::new(code, code_start),
-            Err(_) => panic!("Failed to finalize code"),
-        }
+        self.codegen.finalize(self.ops)
     }
 }
 
 pub trait NativeCodeGenBackend {
-    type Relocation: dynasmrt::relocations::Relocation;
+    /// Collects the generated code, e.g. an assembler.
+    type Ops;
+
+    fn generate_prolouge(&self, ops: &mut Self::Ops);
 
-    fn generate_prolouge(&self, ops: &mut Assembler<Self::Relocation>) -> AssemblyOffset;
+    /// Generates all exit points of the program. Every exit but the one for
+    /// `CellOutOfBounds` stores the pointer in `State::pointer`.
+    fn generate_epilouge(&self, ops: &mut Self::Ops);
 
-    fn generate_epilouge(&self, ops: &mut Assembler<Self::Relocation>);
+    /// Turns the generated code into an executor.
+    fn finalize(&self, ops: Self::Ops) -> NativeExecutor;
 
-    fn generate_instruction(&self, ops: &mut Assembler<Self::Relocation>, instr: &Instruction) {
+    /// Called before the code of every instruction, loop bodies right after their loop.
+    /// Backends that support guard pages record where it starts, to find the instruction that faulted.
+    fn start_instruction(&self, _ops: &mut Self::Ops) {}
+
+    fn generate_instruction(&self, ops: &mut Self::Ops, instr: &Instruction) {
+        self.start_instruction(ops);
         match instr {
             Instruction::Add { value } => self.generate_increment(ops, value.0),
             Instruction::Move { value } => self.generate_cell_increment(ops, *value),
             Instruction::Loop { nodes } => self.generate_loop(ops, nodes),
+            Instruction::If { nodes } => self.generate_if(ops, nodes),
+            Instruction::MulAdd { offset, terms } => self.generate_mul_add(ops, *offset, terms),
+            Instruction::Idiom { idiom, nodes } => self.generate_idiom(ops, *idiom, nodes),
             Instruction::Write => self.generate_write(ops),
+            Instruction::WriteBytes { bytes } => self.generate_write_bytes(ops, bytes),
             Instruction::Read => self.generate_read(ops),
             Instruction::Set { value } => self.generate_set(ops, *value),
+            Instruction::MoveUnchecked { value } => {
+                self.generate_unchecked_cell_increment(ops, *value)
+            }
+            Instruction::SetRange { offset, values } => {
+                self.generate_set_range(ops, *offset, values)
+            }
+            Instruction::AddVector { offset, values } => {
+                self.generate_add_vector(ops, *offset, values)
+            }
         }
     }
 
-    fn generate_increment(&self, ops: &mut Assembler<Self::Relocation>, value: i8);
+    fn generate_increment(&self, ops: &mut Self::Ops, value: i8);
+
+    fn generate_cell_increment(&self, ops: &mut Self::Ops, value: i32);
+
+    fn generate_unchecked_cell_increment(&self, ops: &mut Self::Ops, value: i32);
+
+    fn generate_loop(&self, ops: &mut Self::Ops, nodes: &[Instruction]);
+
+    fn generate_if(&self, ops: &mut Self::Ops, nodes: &[Instruction]);
+
+    /// Calls `State::idiom_fn` and runs `nodes` if the idiom does not apply.
+    fn generate_idiom(&self, ops: &mut Self::Ops, idiom: Idiom, nodes: &[Instruction]);
+
+    fn generate_write(&self, ops: &mut Self::Ops);
+
+    fn generate_write_bytes(&self, ops: &mut Self::Ops, bytes: &[u8]);
 
-    fn generate_cell_increment(&self, ops: &mut Assembler<Self::Relocation>, value: i32);
+    fn generate_read(&self, ops: &mut Self::Ops);
 
-    fn generate_loop(&self, ops: &mut Assembler<Self::Relocation>, nodes: &[Instruction]);
+    fn generate_set(&self, ops: &mut Self::Ops, value: u8);
 
-    fn generate_write(&self, ops: &mut Assembler<Self::Relocation>);
+    fn generate_set_range(&self, ops: &mut Self::Ops, offset: i32, values: &[u8]);
 
-    fn generate_read(&self, ops: &mut Assembler<Self::Relocation>);
+    fn generate_add_vector(&self, ops: &mut Self::Ops, offset: i32, values: &[i8]);
 
-    fn generate_set(&self, ops: &mut Assembler<Self::Relocation>, value: u8);
+    fn generate_mul_add(&self, ops: &mut Self::Ops, offset: i32, terms: &[Term]);
 }
@@ -0,0 +1,760 @@
+use super::{codegen::NativeCodeGenBackend, executor::NativeExecutor, state::State};
+use crate::{
+    execution::{
+        tape::{TapeBounds, TapeConfig},
+        ExecutionLimits,
+    },
+    syntax::{Idiom, Instruction, Term},
+};
+use cranelift_codegen::{
+    control::ControlPlane,
+    cursor::{Cursor, FuncCursor},
+    ir::{
+        condcodes::IntCC, types, AbiParam, Block, Function, InstBuilder, JumpTableData, MemFlags,
+        SigRef, Signature, StackSlotData, StackSlotKind, TrapCode, Type, UserFuncName, Value,
+    },
+    isa::OwnedTargetIsa,
+    settings::{self, Configurable},
+    Context,
+};
+use dynasmrt::{mmap::MutableBuffer, AssemblyOffset};
+use std::mem::offset_of;
+
+/// The Cranelift code generation backend. Lowers the IR to Cranelift IR and lets Cranelift
+/// allocate registers, so it runs on every host Cranelift supports.
+/// Only for 64 bit hosts. Relies on bounds checks only, guard pages are not supported.
+pub struct CraneliftCodeGen {
+    tape: TapeConfig,
+    limits: ExecutionLimits,
+    isa: OwnedTargetIsa,
+}
+
+/// The values that live in registers in the x64 backend. Every block but the special exits
+/// receives them as parameters, so that moves and tape growth can change them on any path.
+#[derive(Clone, Copy)]
+struct Regs {
+    cell_ptr: Value,
+    tape_start: Value,
+    tape_end: Value,
+}
+
+impl Regs {
+    fn values(&self) -> [Value; 3] {
+        [self.cell_ptr, self.tape_start, self.tape_end]
+    }
+}
+
+/// The function under construction.
+pub struct CraneliftOps {
+    func: Function,
+    /// The block instructions are appended to.
+    block: Block,
+    regs: Regs,
+    state: Value,
+    /// Signature of `getchar` and `putchar`.
+    io_sig: SigRef,
+    write_bytes_sig: SigRef,
+    idiom_sig: SigRef,
+    grow_tape_sig: SigRef,
+    /// Takes the result code and the registers, stores the pointer and returns.
+    exit: Block,
+    /// Returns `CellOutOfBounds` without storing the pointer.
+    error_bounds: Block,
+    /// Continues a suspended program at `resume_points[State::resume_at - 1]`.
+    dispatch: Block,
+    resume_points: Vec<Block>,
+}
+
+impl CraneliftOps {
+    fn cursor(&mut self) -> FuncCursor<'_> {
+        FuncCursor::new(&mut self.func).at_bottom(self.block)
+    }
+
+    /// Creates a block that takes the registers as parameters.
+    fn new_block(&mut self) -> Block {
+        let block = self.func.dfg.make_block();
+        for _ in 0..3 {
+            self.func.dfg.append_block_param(block, types::I64);
+        }
+        block
+    }
+
+    /// Continues generating code in `block`, which must have been created by `new_block`.
+    fn switch_to(&mut self, block: Block) {
+        self.func.layout.append_block(block);
+        let params = self.func.dfg.block_params(block);
+        self.regs = Regs {
+            cell_ptr: params[0],
+            tape_start: params[1],
+            tape_end: params[2],
+        };
+        self.block = block;
+    }
+
+    fn jump(&mut self, block: Block) {
+        let args = self.regs.values();
+        self.cursor().ins().jump(block, &args);
+    }
+
+    /// Jumps to `then_block` if `condition` is not zero, to `else_block` otherwise.
+    fn branch(&mut self, condition: Value, then_block: Block, else_block: Block) {
+        let args = self.regs.values();
+        self.cursor()
+            .ins()
+            .brif(condition, then_block, &args, else_block, &args);
+    }
+
+    /// Exits with `code` if `condition` is not zero and continues in a new block otherwise.
+    fn exit_if(&mut self, condition: Value, code: Value) {
+        let exit = self.func.dfg.make_block();
+        self.func.layout.append_block(exit);
+        self.func.layout.set_cold(exit);
+        let next = self.new_block();
+        let args = self.regs.values();
+        self.cursor().ins().brif(condition, exit, &[], next, &args);
+
+        self.block = exit;
+        self.exit_with(code);
+        self.switch_to(next);
+    }
+
+    /// Leaves with `code` from the current block.
+    fn exit_with(&mut self, code: Value) {
+        let exit = self.exit;
+        let [cell_ptr, tape_start, tape_end] = self.regs.values();
+        self.cursor()
+            .ins()
+            .jump(exit, &[code, cell_ptr, tape_start, tape_end]);
+    }
+
+    fn code(&mut self, code: u8) -> Value {
+        self.cursor().ins().iconst(types::I8, code as i64)
+    }
+
+    fn load_state(&mut self, ty: Type, offset: usize) -> Value {
+        let state = self.state;
+        self.cursor()
+            .ins()
+            .load(ty, MemFlags::trusted(), state, offset as i32)
+    }
+
+    fn store_state(&mut self, value: Value, offset: usize) {
+        let state = self.state;
+        self.cursor()
+            .ins()
+            .store(MemFlags::trusted(), value, state, offset as i32);
+    }
+
+    fn load_cell(&mut self) -> Value {
+        let cell_ptr = self.regs.cell_ptr;
+        self.cursor()
+            .ins()
+            .load(types::I8, MemFlags::trusted(), cell_ptr, 0)
+    }
+
+    fn store_cell(&mut self, value: Value) {
+        let cell_ptr = self.regs.cell_ptr;
+        self.cursor()
+            .ins()
+            .store(MemFlags::trusted(), value, cell_ptr, 0);
+    }
+
+    /// Registers `block` as a point to resume at and stores it in `State::resume_at`.
+    fn suspend_at(&mut self, block: Block) {
+        self.resume_points.push(block);
+        let index = self.resume_points.len() as i64;
+        let index = self.cursor().ins().iconst(types::I64, index);
+        self.store_state(index, offset_of!(State, resume_at));
+    }
+
+    /// Calls into the runtime with the current cell. `function` is the offset of the function address in `State`.
+    fn call_runtime(&mut self, sig: SigRef, function: usize) -> Value {
+        let callee = self.load_state(types::I64, function);
+        let args = [self.state, self.regs.cell_ptr];
+        let call = self.cursor().ins().call_indirect(sig, callee, &args);
+        self.func.dfg.inst_results(call)[0]
+    }
+}
+
+impl CraneliftCodeGen {
+    pub fn new(tape: TapeConfig, limits: ExecutionLimits) -> Self {
+        assert!(
+            !tape.guard_pages,
+            "The Cranelift backend does not support guard pages"
+        );
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").unwrap();
+        let isa = cranelift_native::builder()
+            .expect("Cranelift does not support this host")
+            .finish(settings::Flags::new(flags))
+            .expect("Failed to create the Cranelift target");
+        Self { tape, limits, isa }
+    }
+
+    /// Creates an empty function with the signature of generated code.
+    pub fn new_ops(&self) -> CraneliftOps {
+        let call_conv = self.isa.default_call_conv();
+        let pointer = AbiParam::new(types::I64);
+
+        // fn(State* state, u8* tape_start, u8* tape_end, u8* cell_ptr) -> u8
+        let mut signature = Signature::new(call_conv);
+        signature.params = vec![pointer; 4];
+        signature.returns = vec![AbiParam::new(types::I8)];
+        let mut func = Function::with_name_signature(UserFuncName::default(), signature);
+
+        let mut io_sig = Signature::new(call_conv);
+        io_sig.params = vec![pointer; 2];
+        io_sig.returns = vec![AbiParam::new(types::I8)];
+        let io_sig = func.import_signature(io_sig);
+        let mut write_bytes_sig = Signature::new(call_conv);
+        write_bytes_sig.params = vec![pointer; 3];
+        write_bytes_sig.returns = vec![AbiParam::new(types::I8)];
+        let write_bytes_sig = func.import_signature(write_bytes_sig);
+        let mut idiom_sig = Signature::new(call_conv);
+        idiom_sig.params = vec![pointer, pointer, AbiParam::new(types::I8)];
+        idiom_sig.returns = vec![AbiParam::new(types::I8)];
+        let idiom_sig = func.import_signature(idiom_sig);
+        let mut grow_tape_sig = Signature::new(call_conv);
+        grow_tape_sig.params = vec![pointer; 2];
+        grow_tape_sig.returns = vec![pointer];
+        let grow_tape_sig = func.import_signature(grow_tape_sig);
+
+        let entry = func.dfg.make_block();
+        let state = func.dfg.append_block_param(entry, types::I64);
+        let tape_start = func.dfg.append_block_param(entry, types::I64);
+        let tape_end = func.dfg.append_block_param(entry, types::I64);
+        let cell_ptr = func.dfg.append_block_param(entry, types::I64);
+        func.layout.append_block(entry);
+
+        let exit = func.dfg.make_block();
+        func.dfg.append_block_param(exit, types::I8);
+        let error_bounds = func.dfg.make_block();
+
+        let mut ops = CraneliftOps {
+            func,
+            block: entry,
+            regs: Regs {
+                cell_ptr,
+                tape_start,
+                tape_end,
+            },
+            state,
+            io_sig,
+            write_bytes_sig,
+            idiom_sig,
+            grow_tape_sig,
+            exit,
+            error_bounds,
+            dispatch: entry,
+            resume_points: Vec::new(),
+        };
+        for _ in 0..3 {
+            ops.func.dfg.append_block_param(exit, types::I64);
+        }
+        ops.dispatch = ops.new_block();
+        ops
+    }
+
+    /// Checks the bounds after a move and wraps the pointer or grows the tape if needed.
+    fn generate_bounds_check(&self, ops: &mut CraneliftOps, value: i32) {
+        let Regs {
+            cell_ptr,
+            tape_start,
+            tape_end,
+        } = ops.regs;
+        let in_bounds = ops.new_block();
+        match (self.tape.bounds, value > 0) {
+            // Moves can span the tape several times, so keep wrapping until the pointer is back in range.
+            (TapeBounds::Wrap, forward) => {
+                let wrap = ops.new_block();
+                let out_of_bounds = if forward {
+                    ops.cursor()
+                        .ins()
+                        .icmp(IntCC::UnsignedGreaterThanOrEqual, cell_ptr, tape_end)
+                } else {
+                    ops.cursor()
+                        .ins()
+                        .icmp(IntCC::UnsignedLessThan, cell_ptr, tape_start)
+                };
+                ops.branch(out_of_bounds, wrap, in_bounds);
+
+                ops.switch_to(wrap);
+                let Regs {
+                    cell_ptr,
+                    tape_start,
+                    tape_end,
+                } = ops.regs;
+                let mut cursor = ops.cursor();
+                let tape_size = cursor.ins().isub(tape_end, tape_start);
+                let (cell_ptr, out_of_bounds) = if forward {
+                    let cell_ptr = cursor.ins().isub(cell_ptr, tape_size);
+                    let out_of_bounds =
+                        cursor
+                            .ins()
+                            .icmp(IntCC::UnsignedGreaterThanOrEqual, cell_ptr, tape_end);
+                    (cell_ptr, out_of_bounds)
+                } else {
+                    let cell_ptr = cursor.ins().iadd(cell_ptr, tape_size);
+                    let out_of_bounds =
+                        cursor
+                            .ins()
+                            .icmp(IntCC::UnsignedLessThan, cell_ptr, tape_start);
+                    (cell_ptr, out_of_bounds)
+                };
+                ops.regs.cell_ptr = cell_ptr;
+                ops.branch(out_of_bounds, wrap, in_bounds);
+            }
+            (TapeBounds::Error, true) => {
+                let out_of_bounds =
+                    ops.cursor()
+                        .ins()
+                        .icmp(IntCC::UnsignedGreaterThanOrEqual, cell_ptr, tape_end);
+                let args = ops.regs.values();
+                let error_bounds = ops.error_bounds;
+                ops.cursor()
+                    .ins()
+                    .brif(out_of_bounds, error_bounds, &[], in_bounds, &args);
+            }
+            (TapeBounds::Error, false) if self.tape.bidirectional => {
+                let grow = ops.new_block();
+                let out_of_bounds =
+                    ops.cursor()
+                        .ins()
+                        .icmp(IntCC::UnsignedLessThan, cell_ptr, tape_start);
+                ops.branch(out_of_bounds, grow, in_bounds);
+
+                // Grow the tape to the left and reload its bounds.
+                ops.switch_to(grow);
+                ops.func.layout.set_cold(grow);
+                let grow_tape_sig = ops.grow_tape_sig;
+                let cell_ptr = ops.call_runtime(grow_tape_sig, offset_of!(State, grow_tape_fn));
+                ops.regs = Regs {
+                    cell_ptr,
+                    tape_start: ops.load_state(types::I64, offset_of!(State, tape_start)),
+                    tape_end: ops.load_state(types::I64, offset_of!(State, tape_end)),
+                };
+                ops.jump(in_bounds);
+            }
+            (TapeBounds::Error, false) => {
+                let out_of_bounds =
+                    ops.cursor()
+                        .ins()
+                        .icmp(IntCC::UnsignedLessThan, cell_ptr, tape_start);
+                let args = ops.regs.values();
+                let error_bounds = ops.error_bounds;
+                ops.cursor()
+                    .ins()
+                    .brif(out_of_bounds, error_bounds, &[], in_bounds, &args);
+            }
+        }
+        ops.switch_to(in_bounds);
+    }
+
+    /// Checks the limits after an iteration of a loop, then continues in `body`.
+    fn generate_loop_limits(&self, ops: &mut CraneliftOps, body: Block) {
+        if self.limits.polls_interrupt() {
+            // The flag holds the result code to stop with.
+            let flag = ops.load_state(types::I64, offset_of!(State, interrupt_flag));
+            let reason = ops
+                .cursor()
+                .ins()
+                .load(types::I8, MemFlags::trusted(), flag, 0);
+            ops.exit_if(reason, reason);
+        }
+        // Every iteration costs one step of fuel and of the slice.
+        if self.limits.max_steps.is_some() {
+            let fuel = ops.load_state(types::I64, offset_of!(State, fuel));
+            let out_of_fuel = ops.cursor().ins().icmp_imm(IntCC::Equal, fuel, 0);
+            let code = ops.code(3);
+            ops.exit_if(out_of_fuel, code);
+            let fuel = ops.cursor().ins().iadd_imm(fuel, -1);
+            ops.store_state(fuel, offset_of!(State, fuel));
+        }
+        if self.limits.slice.is_some() {
+            let slice = ops.load_state(types::I64, offset_of!(State, slice));
+            let yielded = ops.func.dfg.make_block();
+            let next = ops.new_block();
+            let args = ops.regs.values();
+            ops.cursor().ins().brif(slice, next, &args, yielded, &[]);
+
+            // The iteration was already paid for, so resuming starts right in the loop body.
+            ops.func.layout.append_block(yielded);
+            ops.func.layout.set_cold(yielded);
+            ops.block = yielded;
+            ops.suspend_at(body);
+            let code = ops.code(7);
+            ops.exit_with(code);
+
+            ops.switch_to(next);
+            let slice = ops.cursor().ins().iadd_imm(slice, -1);
+            ops.store_state(slice, offset_of!(State, slice));
+        }
+        ops.jump(body);
+    }
+}
+
+impl NativeCodeGenBackend for CraneliftCodeGen {
+    type Ops = CraneliftOps;
+
+    fn generate_prolouge(&self, ops: &mut Self::Ops) {
+        // Continue a suspended program at its resume point
+        let resume_at = ops.load_state(types::I64, offset_of!(State, resume_at));
+        let start = ops.new_block();
+        let dispatch = ops.dispatch;
+        ops.branch(resume_at, dispatch, start);
+        ops.switch_to(start);
+    }
+
+    fn generate_epilouge(&self, ops: &mut Self::Ops) {
+        let code = ops.code(0);
+        ops.exit_with(code);
+
+        let exit = ops.exit;
+        ops.func.layout.append_block(exit);
+        ops.block = exit;
+        let [code, cell_ptr, tape_start, _] = *ops.func.dfg.block_params(exit) else {
+            unreachable!()
+        };
+        let pointer = ops.cursor().ins().isub(cell_ptr, tape_start);
+        ops.store_state(pointer, offset_of!(State, pointer));
+        ops.cursor().ins().return_(&[code]);
+
+        let error_bounds = ops.error_bounds;
+        ops.func.layout.append_block(error_bounds);
+        ops.func.layout.set_cold(error_bounds);
+        ops.block = error_bounds;
+        let code = ops.code(2);
+        ops.cursor().ins().return_(&[code]);
+
+        let dispatch = ops.dispatch;
+        ops.switch_to(dispatch);
+        let resume_at = ops.load_state(types::I64, offset_of!(State, resume_at));
+        let zero = ops.cursor().ins().iconst(types::I64, 0);
+        ops.store_state(zero, offset_of!(State, resume_at));
+        let mut cursor = ops.cursor();
+        let index = cursor.ins().iadd_imm(resume_at, -1);
+        let index = cursor.ins().ireduce(types::I32, index);
+
+        let invalid = ops.func.dfg.make_block();
+        let args = ops.regs.values();
+        let dfg = &mut ops.func.dfg;
+        let targets: Vec<_> = ops
+            .resume_points
+            .iter()
+            .map(|block| dfg.block_call(*block, &args))
+            .collect();
+        let default = dfg.block_call(invalid, &[]);
+        let table = ops
+            .func
+            .create_jump_table(JumpTableData::new(default, &targets));
+        ops.cursor().ins().br_table(index, table);
+
+        ops.func.layout.append_block(invalid);
+        ops.func.layout.set_cold(invalid);
+        ops.block = invalid;
+        ops.cursor().ins().trap(TrapCode::unwrap_user(1));
+    }
+
+    fn finalize(&self, ops: Self::Ops) -> NativeExecutor {
+        let mut context = Context::for_function(ops.func);
+        let compiled = match context.compile(&*self.isa, &mut ControlPlane::default()) {
+            Ok(compiled) => compiled,
+            Err(e) => panic!("Failed to compile with Cranelift: {:?}", e.inner),
+        };
+        // Generated code only calls the runtime through `State`, so it does not need relocations.
+        assert!(compiled.buffer.relocs().is_empty());
+        let bytes = compiled.code_buffer();
+
+        let mut code = MutableBuffer::new(bytes.len()).expect("Failed to allocate code");
+        code.set_len(bytes.len());
+        code.copy_from_slice(bytes);
+        let code = code.make_exec().expect("Failed to make code executable");
+        NativeExecutor::new(
+            code,
+            AssemblyOffset(0),
+            AssemblyOffset(0),
+            false,
+            Vec::new(),
+        )
+    }
+
+    /// Handles overflows and underflows by wrapping around the value.
+    fn generate_increment(&self, ops: &mut Self::Ops, value: i8) {
+        let cell = ops.load_cell();
+        let cell = ops.cursor().ins().iadd_imm(cell, value as i64);
+        ops.store_cell(cell);
+    }
+
+    fn generate_set(&self, ops: &mut Self::Ops, value: u8) {
+        let value = ops.code(value);
+        ops.store_cell(value);
+    }
+
+    /// Stores 8 bytes at a time, the rest one by one.
+    fn generate_set_range(&self, ops: &mut Self::Ops, offset: i32, values: &[u8]) {
+        let cell_ptr = ops.regs.cell_ptr;
+        let mut cell = offset;
+        let mut chunks = values.chunks_exact(8);
+        for chunk in &mut chunks {
+            let value = i64::from_le_bytes(chunk.try_into().unwrap());
+            let mut cursor = ops.cursor();
+            let value = cursor.ins().iconst(types::I64, value);
+            cursor
+                .ins()
+                .store(MemFlags::trusted(), value, cell_ptr, cell);
+            cell += 8;
+        }
+        for value in chunks.remainder() {
+            let value = ops.code(*value);
+            ops.cursor()
+                .ins()
+                .store(MemFlags::trusted(), value, cell_ptr, cell);
+            cell += 1;
+        }
+    }
+
+    fn generate_add_vector(&self, ops: &mut Self::Ops, offset: i32, values: &[i8]) {
+        let cell_ptr = ops.regs.cell_ptr;
+        for (index, value) in values.iter().enumerate() {
+            if *value == 0 {
+                continue;
+            }
+            let cell = offset + index as i32;
+            let mut cursor = ops.cursor();
+            let old = cursor
+                .ins()
+                .load(types::I8, MemFlags::trusted(), cell_ptr, cell);
+            let new = cursor.ins().iadd_imm(old, *value as i64);
+            cursor.ins().store(MemFlags::trusted(), new, cell_ptr, cell);
+        }
+    }
+
+    fn generate_mul_add(&self, ops: &mut Self::Ops, offset: i32, terms: &[Term]) {
+        let cell_ptr = ops.regs.cell_ptr;
+        let mut cursor = ops.cursor();
+        let mut sum = cursor
+            .ins()
+            .load(types::I8, MemFlags::trusted(), cell_ptr, offset);
+        for term in terms {
+            let mut product = cursor.ins().iconst(types::I8, term.coefficient as i64);
+            for factor in &term.factors {
+                let cell = cursor
+                    .ins()
+                    .load(types::I8, MemFlags::trusted(), cell_ptr, *factor);
+                product = cursor.ins().imul(product, cell);
+            }
+            sum = cursor.ins().iadd(sum, product);
+        }
+        cursor
+            .ins()
+            .store(MemFlags::trusted(), sum, cell_ptr, offset);
+    }
+
+    fn generate_cell_increment(&self, ops: &mut Self::Ops, value: i32) {
+        self.generate_unchecked_cell_increment(ops, value);
+        self.generate_bounds_check(ops, value);
+    }
+
+    fn generate_unchecked_cell_increment(&self, ops: &mut Self::Ops, value: i32) {
+        let cell_ptr = ops.regs.cell_ptr;
+        ops.regs.cell_ptr = ops.cursor().ins().iadd_imm(cell_ptr, value as i64);
+    }
+
+    fn generate_loop(&self, ops: &mut Self::Ops, nodes: &[Instruction]) {
+        let body = ops.new_block();
+        let after = ops.new_block();
+
+        let cell = ops.load_cell();
+        ops.branch(cell, body, after);
+        ops.switch_to(body);
+
+        for node in nodes {
+            self.generate_instruction(ops, node);
+        }
+
+        let cell = ops.load_cell();
+        let limits = &self.limits;
+        if limits.polls_interrupt() || limits.max_steps.is_some() || limits.slice.is_some() {
+            let next = ops.new_block();
+            ops.branch(cell, next, after);
+            ops.switch_to(next);
+            self.generate_loop_limits(ops, body);
+        } else {
+            ops.branch(cell, body, after);
+        }
+        ops.switch_to(after);
+    }
+
+    fn generate_if(&self, ops: &mut Self::Ops, nodes: &[Instruction]) {
+        let body = ops.new_block();
+        let after = ops.new_block();
+
+        let cell = ops.load_cell();
+        ops.branch(cell, body, after);
+        ops.switch_to(body);
+
+        for node in nodes {
+            self.generate_instruction(ops, node);
+        }
+
+        ops.jump(after);
+        ops.switch_to(after);
+    }
+
+    fn generate_idiom(&self, ops: &mut Self::Ops, idiom: Idiom, nodes: &[Instruction]) {
+        let snippet = ops.new_block();
+        let after = ops.new_block();
+
+        let callee = ops.load_state(types::I64, offset_of!(State, idiom_fn));
+        let state = ops.state;
+        let cell_ptr = ops.regs.cell_ptr;
+        let idiom_sig = ops.idiom_sig;
+        let idiom = ops.code(idiom as u8);
+        let call = ops
+            .cursor()
+            .ins()
+            .call_indirect(idiom_sig, callee, &[state, cell_ptr, idiom]);
+        let result = ops.func.dfg.inst_results(call)[0];
+        let failed = ops.cursor().ins().icmp_imm(IntCC::Equal, result, 1);
+        ops.exit_if(failed, result);
+        // The idiom does not apply, so run its snippet.
+        ops.branch(result, snippet, after);
+        ops.switch_to(snippet);
+
+        for node in nodes {
+            self.generate_instruction(ops, node);
+        }
+
+        ops.jump(after);
+        ops.switch_to(after);
+    }
+
+    fn generate_write(&self, ops: &mut Self::Ops) {
+        // Append to the output buffer, the runtime only has to flush it once it reached
+        // `output_limit` or a line is complete.
+        let append = ops.new_block();
+        let flush = ops.new_block();
+        let done = ops.new_block();
+        let len = ops.load_state(types::I64, offset_of!(State, output_len));
+        let limit = ops.load_state(types::I64, offset_of!(State, output_limit));
+        let cell = ops.load_cell();
+        let mut cursor = ops.cursor();
+        let full = cursor
+            .ins()
+            .icmp(IntCC::UnsignedGreaterThanOrEqual, len, limit);
+        let newline = cursor.ins().icmp_imm(IntCC::Equal, cell, b'\n' as i64);
+        let flush_now = cursor.ins().bor(full, newline);
+        ops.branch(flush_now, flush, append);
+
+        ops.switch_to(append);
+        let state = ops.state;
+        let mut cursor = ops.cursor();
+        let address = cursor.ins().iadd(state, len);
+        cursor.ins().store(
+            MemFlags::trusted(),
+            cell,
+            address,
+            offset_of!(State, output_buffer) as i32,
+        );
+        let len = cursor.ins().iadd_imm(len, 1);
+        ops.store_state(len, offset_of!(State, output_len));
+        ops.jump(done);
+
+        ops.switch_to(flush);
+        ops.func.layout.set_cold(flush);
+        let io_sig = ops.io_sig;
+        let result = ops.call_runtime(io_sig, offset_of!(State, putchar_fn));
+        ops.exit_if(result, result);
+        ops.jump(done);
+
+        ops.switch_to(done);
+    }
+
+    /// The bytes are copied to the stack and written by the runtime in a single call.
+    fn generate_write_bytes(&self, ops: &mut Self::Ops, bytes: &[u8]) {
+        let slot = ops.func.create_sized_stack_slot(StackSlotData::new(
+            StackSlotKind::ExplicitSlot,
+            bytes.len() as u32,
+            0,
+        ));
+        let mut offset = 0;
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            let value = i64::from_le_bytes(chunk.try_into().unwrap());
+            let mut cursor = ops.cursor();
+            let value = cursor.ins().iconst(types::I64, value);
+            cursor.ins().stack_store(value, slot, offset);
+            offset += 8;
+        }
+        for byte in chunks.remainder() {
+            let value = ops.code(*byte);
+            ops.cursor().ins().stack_store(value, slot, offset);
+            offset += 1;
+        }
+
+        let callee = ops.load_state(types::I64, offset_of!(State, write_bytes_fn));
+        let state = ops.state;
+        let write_bytes_sig = ops.write_bytes_sig;
+        let mut cursor = ops.cursor();
+        let address = cursor.ins().stack_addr(types::I64, slot, 0);
+        let len = cursor.ins().iconst(types::I64, bytes.len() as i64);
+        let call = cursor
+            .ins()
+            .call_indirect(write_bytes_sig, callee, &[state, address, len]);
+        let result = ops.func.dfg.inst_results(call)[0];
+        ops.exit_if(result, result);
+    }
+
+    fn generate_read(&self, ops: &mut Self::Ops) {
+        // Take the next byte from the input buffer, the runtime only has to refill it once it is empty.
+        // Without input the refill suspends, and resuming repeats the read.
+        let resume = ops.new_block();
+        let take = ops.new_block();
+        let refill = ops.new_block();
+        let done = ops.new_block();
+        ops.jump(resume);
+
+        ops.switch_to(resume);
+        let pos = ops.load_state(types::I64, offset_of!(State, input_pos));
+        let len = ops.load_state(types::I64, offset_of!(State, input_len));
+        let empty = ops
+            .cursor()
+            .ins()
+            .icmp(IntCC::UnsignedGreaterThanOrEqual, pos, len);
+        ops.branch(empty, refill, take);
+
+        ops.switch_to(take);
+        let state = ops.state;
+        let mut cursor = ops.cursor();
+        let address = cursor.ins().iadd(state, pos);
+        let value = cursor.ins().load(
+            types::I8,
+            MemFlags::trusted(),
+            address,
+            offset_of!(State, input_buffer) as i32,
+        );
+        let pos = cursor.ins().iadd_imm(pos, 1);
+        ops.store_cell(value);
+        ops.store_state(pos, offset_of!(State, input_pos));
+        ops.jump(done);
+
+        ops.switch_to(refill);
+        ops.func.layout.set_cold(refill);
+        let io_sig = ops.io_sig;
+        let result = ops.call_runtime(io_sig, offset_of!(State, getchar_fn));
+        let failed = ops.cursor().ins().icmp_imm(IntCC::NotEqual, result, 0);
+        let pending = ops.new_block();
+        ops.branch(failed, pending, done);
+
+        ops.switch_to(pending);
+        ops.func.layout.set_cold(pending);
+        let is_error = ops.cursor().ins().icmp_imm(IntCC::NotEqual, result, 2);
+        let code = ops.code(1);
+        ops.exit_if(is_error, code);
+        ops.suspend_at(resume);
+        let code = ops.code(6);
+        ops.exit_with(code);
+
+        ops.switch_to(done);
+    }
+}
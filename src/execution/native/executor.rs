@@ -1,24 +1,45 @@
-use super::{state::State, RuntimeResultCode};
+use super::state::{GuardFault, State};
+use crate::execution::RuntimeResultCode;
+use dynasmrt::{mmap::MutableBuffer, AssemblyOffset, ExecutableBuffer};
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, Read, Write};
 use std::mem;
 
 pub struct NativeExecutor {
     code: dynasmrt::ExecutableBuffer,
     code_start: dynasmrt::AssemblyOffset,
+    error_bounds: dynasmrt::AssemblyOffset,
+    guard_pages: bool,
+    /// Offsets in `code` where the code of each instruction starts, in the order the instructions
+    /// were generated. Empty if the backend does not record them.
+    instruction_starts: Vec<usize>,
 }
 
 impl NativeExecutor {
-    pub fn new(code: dynasmrt::ExecutableBuffer, code_start: dynasmrt::AssemblyOffset) -> Self {
-        Self { code, code_start }
+    pub fn new(
+        code: dynasmrt::ExecutableBuffer,
+        code_start: dynasmrt::AssemblyOffset,
+        error_bounds: dynasmrt::AssemblyOffset,
+        guard_pages: bool,
+        instruction_starts: Vec<usize>,
+    ) -> Self {
+        Self {
+            code,
+            code_start,
+            error_bounds,
+            guard_pages,
+            instruction_starts,
+        }
     }
 
-    pub fn run(self, state: &mut State) -> RuntimeResultCode {
+    /// Runs the program on `state`. If it suspended on `state` before, it continues where it left off.
+    pub fn run(&self, state: &mut State) -> RuntimeResultCode {
         #[cfg(target_os = "windows")]
         let native_code: extern "win64" fn(
             state: *mut State,
             tape_start: *mut u8,
             tape_end: *mut u8,
+            cell_ptr: *mut u8,
         ) -> u8 = unsafe { mem::transmute(self.code.ptr(self.code_start)) };
 
         #[cfg(any(target_os = "linux", target_os = "macos"))]
@@ -26,12 +47,120 @@ impl NativeExecutor {
             state: *mut State,
             tape_start: *mut u8,
             tape_end: *mut u8,
+            cell_ptr: *mut u8,
         ) -> u8 = unsafe { mem::transmute(self.code.ptr(self.code_start)) };
 
-        let tape_start = state.tape.as_mut_ptr();
-        let tape_end = unsafe { tape_start.add(state.tape.len()) };
-        let result = native_code(state, tape_start, tape_end);
-        RuntimeResultCode::try_from(result).unwrap()
+        if state.pointer >= state.tape.len() {
+            return RuntimeResultCode::CellOutOfBounds;
+        }
+
+        state.update_bounds();
+        state.start_slice();
+        let tape_start = state.tape_start;
+        let tape_end = state.tape_end;
+        let cell_ptr = unsafe { tape_start.add(state.pointer) };
+
+        let result = if self.guard_pages {
+            assert!(
+                state.tape.has_guard_pages(),
+                "Code compiled for guard pages needs a tape with guard pages"
+            );
+            let (result, fault) = self.run_guarded(tape_start, || {
+                native_code(state, tape_start, tape_end, cell_ptr)
+            });
+            state.fault = fault;
+            result
+        } else {
+            native_code(state, tape_start, tape_end, cell_ptr)
+        };
+        let result = RuntimeResultCode::try_from(result).unwrap();
+        match state.flush_output() {
+            Err(_) if !result.is_error() => RuntimeResultCode::IoError,
+            _ => result,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn run_guarded(
+        &self,
+        tape_start: *mut u8,
+        run: impl FnOnce() -> u8,
+    ) -> (u8, Option<GuardFault>) {
+        let (result, fault) =
+            super::guard::run_guarded(&self.code, self.code.ptr(self.error_bounds), run);
+        let fault = fault.map(|fault| GuardFault {
+            cell: fault.address.wrapping_sub(tape_start as usize) as isize,
+            instruction: self.instruction_at(fault.pc),
+        });
+        (result, fault)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn run_guarded(
+        &self,
+        _tape_start: *mut u8,
+        _run: impl FnOnce() -> u8,
+    ) -> (u8, Option<GuardFault>) {
+        panic!("Guard pages are only supported on Linux");
+    }
+
+    /// The index of the instruction whose code contains the address `pc`.
+    fn instruction_at(&self, pc: usize) -> Option<usize> {
+        let offset = pc.checked_sub(self.code.ptr(AssemblyOffset(0)) as usize)?;
+        self.instruction_starts
+            .partition_point(|&start| start <= offset)
+            .checked_sub(1)
+    }
+
+    /// Serializes the executor. Generated code only calls the runtime through `State`,
+    /// so it can be loaded again at any address.
+    pub fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(&(self.code_start.0 as u64).to_le_bytes())?;
+        out.write_all(&(self.error_bounds.0 as u64).to_le_bytes())?;
+        out.write_all(&[self.guard_pages as u8])?;
+        out.write_all(&(self.instruction_starts.len() as u64).to_le_bytes())?;
+        for start in &self.instruction_starts {
+            out.write_all(&(*start as u64).to_le_bytes())?;
+        }
+        out.write_all(&(self.code.len() as u64).to_le_bytes())?;
+        out.write_all(&self.code)
+    }
+
+    /// Loads an executor written by `write_to` into fresh executable memory.
+    pub fn read_from(input: &mut dyn Read) -> io::Result<Self> {
+        fn read_u64(input: &mut dyn Read) -> io::Result<usize> {
+            let mut bytes = [0; 8];
+            input.read_exact(&mut bytes)?;
+            Ok(u64::from_le_bytes(bytes) as usize)
+        }
+
+        let code_start = AssemblyOffset(read_u64(input)?);
+        let error_bounds = AssemblyOffset(read_u64(input)?);
+        let mut guard_pages = [0];
+        input.read_exact(&mut guard_pages)?;
+        let count = read_u64(input)?;
+        let instruction_starts = (0..count)
+            .map(|_| read_u64(input))
+            .collect::<io::Result<Vec<_>>>()?;
+        let len = read_u64(input)?;
+        if code_start.0 >= len
+            || error_bounds.0 >= len
+            || instruction_starts.iter().any(|start| *start > len)
+        {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+
+        let mut code = MutableBuffer::new(len)?;
+        code.set_len(len);
+        input.read_exact(&mut code)?;
+        let code: ExecutableBuffer = code.make_exec()?;
+        Ok(Self::new(
+            code,
+            code_start,
+            error_bounds,
+            guard_pages[0] != 0,
+            instruction_starts,
+        ))
     }
 
     pub fn dump_binary(&self, path: &str) {
@@ -39,3 +168,9 @@ impl NativeExecutor {
         file.write_all(&self.code).unwrap();
     }
 }
+
+// Generated code only reads its own buffer, so one executor can run on many threads at once.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<NativeExecutor>();
+};
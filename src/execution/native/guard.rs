@@ -0,0 +1,123 @@
+use std::{cell::Cell, mem, ptr, sync::Once};
+
+#[derive(Clone, Copy)]
+struct ActiveCode {
+    start: usize,
+    end: usize,
+    recovery: usize,
+}
+
+/// A fault inside generated code.
+#[derive(Clone, Copy)]
+pub struct Fault {
+    /// The address whose access faulted.
+    pub address: usize,
+    /// The address of the faulting instruction.
+    pub pc: usize,
+}
+
+thread_local! {
+    static ACTIVE: Cell<Option<ActiveCode>> = const { Cell::new(None) };
+    static FAULT: Cell<Option<Fault>> = const { Cell::new(None) };
+}
+
+static INSTALL: Once = Once::new();
+static mut PREVIOUS_SEGV: mem::MaybeUninit<libc::sigaction> = mem::MaybeUninit::uninit();
+static mut PREVIOUS_BUS: mem::MaybeUninit<libc::sigaction> = mem::MaybeUninit::uninit();
+
+/// Runs `f` while faults inside `code` are redirected to `recovery`.
+/// Code compiled for guard pages does not check moves against the tape bounds,
+/// so the first access past either end of the tape faults instead.
+/// Returns the result of `f` and the fault, if any.
+pub fn run_guarded<R>(
+    code: &[u8],
+    recovery: *const u8,
+    f: impl FnOnce() -> R,
+) -> (R, Option<Fault>) {
+    INSTALL.call_once(install_handlers);
+
+    let start = code.as_ptr() as usize;
+    ACTIVE.with(|active| {
+        active.set(Some(ActiveCode {
+            start,
+            end: start + code.len(),
+            recovery: recovery as usize,
+        }))
+    });
+    FAULT.with(|fault| fault.set(None));
+
+    let result = f();
+
+    ACTIVE.with(|active| active.set(None));
+    (result, FAULT.with(|fault| fault.take()))
+}
+
+fn install_handlers() {
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handle_fault as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(
+            libc::SIGSEGV,
+            &action,
+            ptr::addr_of_mut!(PREVIOUS_SEGV).cast(),
+        );
+        libc::sigaction(
+            libc::SIGBUS,
+            &action,
+            ptr::addr_of_mut!(PREVIOUS_BUS).cast(),
+        );
+    }
+}
+
+extern "C" fn handle_fault(
+    signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    context: *mut libc::c_void,
+) {
+    let context = context as *mut libc::ucontext_t;
+    let rip = unsafe { &mut (*context).uc_mcontext.gregs[libc::REG_RIP as usize] };
+    let pc = *rip as usize;
+
+    if let Some(active) = ACTIVE.with(|active| active.get()) {
+        if (active.start..active.end).contains(&pc) {
+            let address = unsafe { (*info).si_addr() } as usize;
+            FAULT.with(|fault| fault.set(Some(Fault { address, pc })));
+            *rip = active.recovery as i64;
+            return;
+        }
+    }
+
+    unsafe { forward_fault(signal, info, context.cast()) }
+}
+
+/// Hands a fault that did not come from generated code to the previously installed handler.
+unsafe fn forward_fault(
+    signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    context: *mut libc::c_void,
+) {
+    let previous = if signal == libc::SIGSEGV {
+        ptr::addr_of!(PREVIOUS_SEGV)
+    } else {
+        ptr::addr_of!(PREVIOUS_BUS)
+    };
+    let previous = &*(*previous).as_ptr();
+
+    match previous.sa_sigaction {
+        // Restore the default action and return, so the faulting instruction faults again.
+        libc::SIG_DFL | libc::SIG_IGN => {
+            libc::sigaction(signal, previous, ptr::null_mut());
+        }
+        handler if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+            let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                mem::transmute(handler);
+            handler(signal, info, context);
+        }
+        handler => {
+            let handler: extern "C" fn(libc::c_int) = mem::transmute(handler);
+            handler(signal);
+        }
+    }
+}
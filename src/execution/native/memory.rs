@@ -0,0 +1,189 @@
+use crate::execution::tape::dump_cells;
+use std::{
+    io::{self, Write},
+    ops::{Deref, DerefMut},
+    ptr, slice,
+};
+
+/// Size of the inaccessible region on either side of a guarded tape.
+/// Larger than any single `Move`, so a move out of bounds always lands in a guard region.
+pub const GUARD_SIZE: usize = 1 << 32;
+
+/// Tape memory for native code.
+/// On unix the tape is an anonymous mapping without swap reservation,
+/// so pages are only committed once the generated code touches them.
+pub struct TapeMemory {
+    ptr: *mut u8,
+    len: usize,
+    guard: usize,
+}
+
+impl TapeMemory {
+    pub fn new(len: usize) -> Self {
+        Self::with_guard(len, 0)
+    }
+
+    /// Places the tape between two `GUARD_SIZE` regions that fault on access.
+    /// `len` must be a multiple of the page size so that both ends touch a guard region.
+    pub fn with_guard_pages(len: usize) -> Self {
+        assert!(
+            len.is_multiple_of(page_size()),
+            "guarded tape is not made of whole pages"
+        );
+        Self::with_guard(len, GUARD_SIZE)
+    }
+
+    fn with_guard(len: usize, guard: usize) -> Self {
+        TapeMemory {
+            ptr: allocate(len, guard),
+            len,
+            guard,
+        }
+    }
+
+    pub fn has_guard_pages(&self) -> bool {
+        self.guard != 0
+    }
+
+    /// Grows the tape at the front so that a pointer `missing` cells left of the first cell becomes valid.
+    /// The tape at least doubles in size to keep repeated growth cheap.
+    /// Returns the number of cells that were inserted in front of the old first cell.
+    pub fn grow_front(&mut self, missing: usize) -> usize {
+        let mut grown = missing.max(self.len).max(1);
+        if self.has_guard_pages() {
+            grown = grown.next_multiple_of(page_size());
+        }
+        let mut new_tape = TapeMemory::with_guard(grown + self.len, self.guard);
+        new_tape[grown..].copy_from_slice(self);
+        *self = new_tape;
+        grown
+    }
+
+    /// Writes all rows of the tape that contain a non-zero cell.
+    /// Pages that were never committed are skipped without touching them.
+    pub fn dump(&self, out: &mut dyn Write) -> io::Result<()> {
+        let page_size = page_size();
+        let batch_size = page_size * 4096;
+        for (batch, cells) in self.chunks(batch_size).enumerate() {
+            let resident = resident_pages(cells, page_size);
+            for (page, cells) in cells.chunks(page_size).enumerate() {
+                if resident[page] {
+                    dump_cells(cells, batch * batch_size + page * page_size, out)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Deref for TapeMemory {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for TapeMemory {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for TapeMemory {
+    fn drop(&mut self) {
+        deallocate(self.ptr, self.len, self.guard);
+    }
+}
+
+/// Maps `len` bytes of tape with `guard` inaccessible bytes on either side.
+/// Returns the address of the first cell.
+#[cfg(unix)]
+fn allocate(len: usize, guard: usize) -> *mut u8 {
+    if len == 0 && guard == 0 {
+        return ptr::NonNull::dangling().as_ptr();
+    }
+    let protection = if guard == 0 {
+        libc::PROT_READ | libc::PROT_WRITE
+    } else {
+        libc::PROT_NONE
+    };
+    let base = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len + 2 * guard,
+            protection,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+            -1,
+            0,
+        )
+    };
+    if base == libc::MAP_FAILED {
+        panic!("Failed to reserve {} bytes of tape", len);
+    }
+    let ptr = unsafe { (base as *mut u8).add(guard) };
+    if guard != 0
+        && len != 0
+        && unsafe {
+            libc::mprotect(
+                ptr as *mut libc::c_void,
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+            )
+        } != 0
+    {
+        panic!("Failed to unprotect {} bytes of tape", len);
+    }
+    ptr
+}
+
+#[cfg(unix)]
+fn deallocate(ptr: *mut u8, len: usize, guard: usize) {
+    if len != 0 || guard != 0 {
+        unsafe { libc::munmap(ptr.sub(guard) as *mut libc::c_void, len + 2 * guard) };
+    }
+}
+
+#[cfg(unix)]
+pub fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Checks which pages of `cells` have been committed.
+#[cfg(unix)]
+fn resident_pages(cells: &[u8], page_size: usize) -> Vec<bool> {
+    let pages = cells.len().div_ceil(page_size);
+    let mut resident = vec![0u8; pages];
+    let result = unsafe {
+        libc::mincore(
+            cells.as_ptr() as *mut libc::c_void,
+            cells.len(),
+            resident.as_mut_ptr() as *mut _,
+        )
+    };
+    if result != 0 {
+        return vec![true; pages];
+    }
+    resident.into_iter().map(|page| page & 1 != 0).collect()
+}
+
+#[cfg(not(unix))]
+pub fn page_size() -> usize {
+    4096
+}
+
+#[cfg(not(unix))]
+fn resident_pages(cells: &[u8], page_size: usize) -> Vec<bool> {
+    vec![true; cells.len().div_ceil(page_size)]
+}
+
+#[cfg(not(unix))]
+fn allocate(len: usize, guard: usize) -> *mut u8 {
+    assert_eq!(guard, 0, "Guard pages are only supported on unix");
+    Box::into_raw(vec![0u8; len].into_boxed_slice()) as *mut u8
+}
+
+#[cfg(not(unix))]
+fn deallocate(ptr: *mut u8, len: usize, _guard: usize) {
+    drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(ptr, len)) });
+}
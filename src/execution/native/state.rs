@@ -1,60 +1,423 @@
-use std::{
-    io::{Read, Write},
-    slice,
+use super::memory::TapeMemory;
+use crate::execution::{
+    interrupt::Interrupt, tape::TapeConfig, ExecutionLimits, RuntimeResultCode,
 };
+use crate::syntax::Idiom;
+use std::io::{self, ErrorKind, Read, Write};
+
+/// Size of the input and output buffers in `State`.
+pub const IO_BUFFER_SIZE: usize = 4096;
+
+/// Where the program reads its input from and writes its output to.
+pub enum Io<'a> {
+    Streams {
+        input: Box<dyn Read + 'a>,
+        output: Box<dyn Write + 'a>,
+    },
+    /// File descriptors, read and written with plain system calls.
+    /// They must stay open while the state is in use, and are not closed by it.
+    #[cfg(unix)]
+    Fds {
+        input: std::os::fd::RawFd,
+        output: std::os::fd::RawFd,
+    },
+}
+
+/// An access to a guard page, see `TapeConfig::guard_pages`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuardFault {
+    /// The cell index that was accessed. Negative left of the tape.
+    pub cell: isize,
+    /// The index of the instruction whose code accessed the cell, counting all instructions
+    /// of the compiled program in order with loop bodies right after their loop.
+    /// Unknown if the backend did not record where the code of each instruction starts.
+    pub instruction: Option<usize>,
+}
 
 pub struct State<'a> {
-    pub input: Box<dyn Read + 'a>,
-    pub output: Box<dyn Write + 'a>,
-    pub tape: Box<[u8]>,
+    pub io: Io<'a>,
+    pub tape: TapeMemory,
+    /// The cell index of the pointer. Native code starts here and stores the pointer on exit.
+    pub pointer: usize,
+    /// The access that hit a guard page, if the last run stopped on one.
+    pub fault: Option<GuardFault>,
+    /// Loop iterations left before execution stops with `OutOfFuel`.
+    /// Only consumed by code compiled with a step limit.
+    pub fuel: u64,
+    /// Loop iterations left before execution suspends with `Yielded`.
+    pub(crate) slice: u64,
+    /// The bytecode index a trace left at, see `execution::trace`.
+    pub(crate) trace_exit: usize,
+    /// Address in the generated code to continue at after a suspension, null to start from the beginning.
+    pub(crate) resume_at: *const u8,
+    limits: ExecutionLimits,
+    pub interrupt: Interrupt,
+    /// Address of the `interrupt` flag, polled by native code.
+    pub(crate) interrupt_flag: *const u8,
+    /// Addresses of the runtime functions. Generated code calls through these,
+    /// so that it does not depend on where this binary was loaded.
+    pub(crate) getchar_fn: *const (),
+    pub(crate) putchar_fn: *const (),
+    pub(crate) write_bytes_fn: *const (),
+    pub(crate) idiom_fn: *const (),
+    pub(crate) grow_tape_fn: *const (),
+    /// Raw bounds of `tape`. Generated code reloads these after the tape was grown.
+    pub(crate) tape_start: *mut u8,
+    pub(crate) tape_end: *mut u8,
+    /// Output that generated code appended but was not written to `output` yet.
+    /// Flushed when it holds `output_limit` bytes, after a newline, before a read and when native code exits.
+    pub(crate) output_buffer: [u8; IO_BUFFER_SIZE],
+    pub(crate) output_len: usize,
+    /// `IO_BUFFER_SIZE` for streams. Zero for file descriptors, which are written on every output
+    /// instead, as nothing else buffers what goes to them.
+    pub(crate) output_limit: usize,
+    /// Input read ahead from `input`. Generated code consumes `input_buffer[input_pos..input_len]`
+    /// and only calls into the runtime once it is empty. Input read ahead is lost when the state is dropped.
+    pub(crate) input_buffer: [u8; IO_BUFFER_SIZE],
+    pub(crate) input_pos: usize,
+    pub(crate) input_len: usize,
 }
 
 impl<'a> State<'a> {
-    pub fn new(input: Box<dyn Read + 'a>, output: Box<dyn Write + 'a>, tape_size: usize) -> Self {
-        State {
-            input,
-            output,
-            tape: vec![0; tape_size].into_boxed_slice(),
+    pub fn new(
+        input: Box<dyn Read + 'a>,
+        output: Box<dyn Write + 'a>,
+        tape: TapeConfig,
+        limits: ExecutionLimits,
+    ) -> Self {
+        State::with_io(Io::Streams { input, output }, tape, limits)
+    }
+
+    /// Binds the state to raw file descriptors instead of Rust streams.
+    #[cfg(unix)]
+    pub fn with_fds(
+        input: std::os::fd::RawFd,
+        output: std::os::fd::RawFd,
+        tape: TapeConfig,
+        limits: ExecutionLimits,
+    ) -> Self {
+        State::with_io(Io::Fds { input, output }, tape, limits)
+    }
+
+    /// Creates a state that reads and writes through `io`.
+    pub fn with_io(io: Io<'a>, tape: TapeConfig, limits: ExecutionLimits) -> Self {
+        let output_limit = match io {
+            Io::Streams { .. } => IO_BUFFER_SIZE,
+            #[cfg(unix)]
+            Io::Fds { .. } => 0,
+        };
+        let mut state = State {
+            io,
+            tape: if tape.guard_pages {
+                TapeMemory::with_guard_pages(tape.size)
+            } else {
+                TapeMemory::new(tape.size)
+            },
+            pointer: tape.start_offset(),
+            fault: None,
+            fuel: limits.fuel(),
+            slice: 0,
+            trace_exit: 0,
+            resume_at: std::ptr::null(),
+            limits,
+            interrupt: Interrupt::new(),
+            interrupt_flag: std::ptr::null(),
+            getchar_fn: Self::getchar as *const (),
+            putchar_fn: Self::putchar as *const (),
+            write_bytes_fn: Self::write_bytes as *const (),
+            idiom_fn: Self::idiom as *const (),
+            grow_tape_fn: Self::grow_tape as *const (),
+            tape_start: std::ptr::null_mut(),
+            tape_end: std::ptr::null_mut(),
+            output_buffer: [0; IO_BUFFER_SIZE],
+            output_len: 0,
+            output_limit,
+            input_buffer: [0; IO_BUFFER_SIZE],
+            input_pos: 0,
+            input_len: 0,
+        };
+        state.update_bounds();
+        state.interrupt_flag = state.interrupt.as_ptr();
+        state
+    }
+
+    /// The number of loop iterations executed so far. Only counted with a step limit.
+    pub fn steps(&self) -> Option<u64> {
+        self.limits.max_steps.map(|max_steps| max_steps - self.fuel)
+    }
+
+    /// Whether the program suspended and the next run continues where it left off.
+    pub fn is_suspended(&self) -> bool {
+        !self.resume_at.is_null()
+    }
+
+    /// Writes the buffered output to `output` and flushes it. The buffer is emptied even if that fails.
+    pub fn flush_output(&mut self) -> io::Result<()> {
+        let len = std::mem::take(&mut self.output_len);
+        match &mut self.io {
+            Io::Streams { output, .. } => output
+                .write_all(&self.output_buffer[..len])
+                .and_then(|()| output.flush()),
+            #[cfg(unix)]
+            Io::Fds { output, .. } => sys::write_all(*output, &self.output_buffer[..len]),
+        }
+    }
+
+    /// Reads the next chunk of input into the input buffer.
+    fn refill_input(&mut self) -> io::Result<usize> {
+        loop {
+            let result = match &mut self.io {
+                Io::Streams { input, .. } => input.read(&mut self.input_buffer),
+                #[cfg(unix)]
+                Io::Fds { input, .. } => sys::read(*input, &mut self.input_buffer),
+            };
+            match result {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                result => return result,
+            }
         }
     }
 
+    /// Reads a byte into `cell` through the input buffer, like generated code does.
+    pub(crate) fn read_cell(&mut self, cell: usize) -> RuntimeResultCode {
+        let cell: *mut u8 = &mut self.tape[cell];
+        match unsafe { Self::getchar_inner(self, cell) } {
+            0 => RuntimeResultCode::Ok,
+            2 => RuntimeResultCode::InputPending,
+            _ => RuntimeResultCode::IoError,
+        }
+    }
+
+    /// Writes `cell` through the output buffer, like generated code does.
+    pub(crate) fn write_cell(&mut self, cell: usize) -> RuntimeResultCode {
+        let cell: *mut u8 = &mut self.tape[cell];
+        match unsafe { Self::putchar_inner(self, cell) } {
+            0 => RuntimeResultCode::Ok,
+            _ => RuntimeResultCode::IoError,
+        }
+    }
+
+    /// Writes `bytes` through the output buffer, like generated code does.
+    pub(crate) fn write_slice(&mut self, bytes: &[u8]) -> RuntimeResultCode {
+        match unsafe { Self::write_bytes_inner(self, bytes.as_ptr(), bytes.len()) } {
+            0 => RuntimeResultCode::Ok,
+            _ => RuntimeResultCode::IoError,
+        }
+    }
+
+    /// Applies `idiom` at `cell` and writes its output through the output buffer, like generated code does.
+    /// Returns `None` if the idiom does not apply, so that its snippet has to run instead.
+    pub(crate) fn apply_idiom(&mut self, idiom: Idiom, cell: usize) -> Option<RuntimeResultCode> {
+        let cell: *mut u8 = &mut self.tape[cell];
+        match unsafe { Self::idiom_inner(self, cell, idiom) } {
+            0 => Some(RuntimeResultCode::Ok),
+            1 => Some(RuntimeResultCode::IoError),
+            _ => None,
+        }
+    }
+
+    /// Grants the steps of a new slice.
+    pub(crate) fn start_slice(&mut self) {
+        self.slice = self.limits.slice.unwrap_or(u64::MAX);
+    }
+
+    pub(crate) fn update_bounds(&mut self) {
+        self.tape_start = self.tape.as_mut_ptr();
+        self.tape_end = unsafe { self.tape_start.add(self.tape.len()) };
+    }
+
+    /// # Safety
+    /// `cell` must point into the tape of `state`.
     #[cfg(target_os = "windows")]
     pub unsafe extern "win64" fn getchar(state: &mut State, cell: *mut u8) -> u8 {
         Self::getchar_inner(state, cell)
     }
 
+    /// # Safety
+    /// `cell` must point into the tape of `state`.
     #[cfg(target_os = "windows")]
     pub unsafe extern "win64" fn putchar(state: &mut State, cell: *mut u8) -> u8 {
         Self::putchar_inner(state, cell)
     }
 
+    /// # Safety
+    /// `bytes` must point to `len` readable bytes.
+    #[cfg(target_os = "windows")]
+    pub unsafe extern "win64" fn write_bytes(
+        state: &mut State,
+        bytes: *const u8,
+        len: usize,
+    ) -> u8 {
+        Self::write_bytes_inner(state, bytes, len)
+    }
+
+    /// # Safety
+    /// `cell` must point to the cell the pointer is at, which may be outside of the tape.
+    #[cfg(target_os = "windows")]
+    pub unsafe extern "win64" fn idiom(state: &mut State, cell: *mut u8, idiom: Idiom) -> u8 {
+        Self::idiom_inner(state, cell, idiom)
+    }
+
+    /// # Safety
+    /// `cell` must point left of the tape of `state`.
+    #[cfg(target_os = "windows")]
+    pub unsafe extern "win64" fn grow_tape(state: &mut State, cell: *mut u8) -> *mut u8 {
+        Self::grow_tape_inner(state, cell)
+    }
+
+    /// # Safety
+    /// `cell` must point into the tape of `state`.
     #[cfg(any(target_os = "linux", target_os = "macos"))]
     pub unsafe extern "sysv64" fn getchar(state: &mut State, cell: *mut u8) -> u8 {
         Self::getchar_inner(state, cell)
     }
 
+    /// # Safety
+    /// `cell` must point into the tape of `state`.
     #[cfg(any(target_os = "linux", target_os = "macos"))]
     pub unsafe extern "sysv64" fn putchar(state: &mut State, cell: *mut u8) -> u8 {
         Self::putchar_inner(state, cell)
     }
 
-    /// Reads a single byte from the input.
+    /// # Safety
+    /// `bytes` must point to `len` readable bytes.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub unsafe extern "sysv64" fn write_bytes(
+        state: &mut State,
+        bytes: *const u8,
+        len: usize,
+    ) -> u8 {
+        Self::write_bytes_inner(state, bytes, len)
+    }
+
+    /// # Safety
+    /// `cell` must point to the cell the pointer is at, which may be outside of the tape.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub unsafe extern "sysv64" fn idiom(state: &mut State, cell: *mut u8, idiom: Idiom) -> u8 {
+        Self::idiom_inner(state, cell, idiom)
+    }
+
+    /// # Safety
+    /// `cell` must point left of the tape of `state`.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub unsafe extern "sysv64" fn grow_tape(state: &mut State, cell: *mut u8) -> *mut u8 {
+        Self::grow_tape_inner(state, cell)
+    }
+
+    /// Refills the input buffer and reads a single byte from it. Flushes the output first,
+    /// so that interactive programs show their prompt before waiting for input.
+    /// Returns 2 if no input is available yet, so that the program can suspend.
     unsafe fn getchar_inner(state: &mut State, cell: *mut u8) -> u8 {
-        let mut buffer = [0];
-        match state.input.read_exact(&mut buffer) {
-            Ok(_) => {
-                *cell = buffer[0];
-                0
+        if state.flush_output().is_err() {
+            return 1;
+        }
+        if state.input_pos == state.input_len {
+            match state.refill_input() {
+                Ok(0) => return 1,
+                Ok(len) => {
+                    state.input_pos = 0;
+                    state.input_len = len;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return 2,
+                Err(_) => return 1,
             }
-            Err(_) => 1,
         }
+        *cell = state.input_buffer[state.input_pos];
+        state.input_pos += 1;
+        0
     }
 
-    /// Writes a single byte to the output.
+    /// Appends a single byte to the output buffer, flushing it first if it is full
+    /// and afterwards if the byte ends a line or the buffer reached `output_limit`.
     unsafe fn putchar_inner(state: &mut State, cell: *mut u8) -> u8 {
-        match state.output.write_all(slice::from_raw_parts(cell, 1)) {
-            Ok(_) => 0,
-            Err(_) => 1,
+        if state.output_len == IO_BUFFER_SIZE && state.flush_output().is_err() {
+            return 1;
+        }
+        state.output_buffer[state.output_len] = *cell;
+        state.output_len += 1;
+        if (*cell == b'\n' || state.output_len >= state.output_limit)
+            && state.flush_output().is_err()
+        {
+            return 1;
+        }
+        0
+    }
+
+    /// Appends `len` bytes to the output buffer, flushing it whenever it is full
+    /// and at the end if the bytes contain a newline or the buffer reached `output_limit`.
+    unsafe fn write_bytes_inner(state: &mut State, bytes: *const u8, len: usize) -> u8 {
+        let mut bytes = std::slice::from_raw_parts(bytes, len);
+        let newline = bytes.contains(&b'\n');
+        while !bytes.is_empty() {
+            if state.output_len == IO_BUFFER_SIZE && state.flush_output().is_err() {
+                return 1;
+            }
+            let count = bytes.len().min(IO_BUFFER_SIZE - state.output_len);
+            state.output_buffer[state.output_len..state.output_len + count]
+                .copy_from_slice(&bytes[..count]);
+            state.output_len += count;
+            bytes = &bytes[count..];
+        }
+        if (newline || state.output_len >= state.output_limit) && state.flush_output().is_err() {
+            return 1;
+        }
+        0
+    }
+
+    /// Applies `idiom` to the cells from `cell` on and appends its output to the output buffer.
+    /// Returns 2 if the idiom does not apply or its cells are not all in the tape.
+    unsafe fn idiom_inner(state: &mut State, cell: *mut u8, idiom: Idiom) -> u8 {
+        let width = idiom.width();
+        let (start, end) = (state.tape_start as usize, state.tape_end as usize);
+        if (cell as usize) < start || end.saturating_sub(cell as usize) < width {
+            return 2;
+        }
+        let mut output = Vec::new();
+        if !idiom.apply(std::slice::from_raw_parts_mut(cell, width), &mut output) {
+            return 2;
+        }
+        Self::write_bytes_inner(state, output.as_ptr(), output.len())
+    }
+
+    /// Grows the tape to the left until `cell` is a valid cell.
+    /// Returns the new address of `cell`.
+    unsafe fn grow_tape_inner(state: &mut State, cell: *mut u8) -> *mut u8 {
+        // `cell` is outside the tape allocation, so `offset_from` would be undefined behavior.
+        let missing = state.tape_start as usize - cell as usize;
+        let grown = state.tape.grow_front(missing);
+        state.update_bounds();
+        state.tape_start.add(grown - missing)
+    }
+}
+
+/// System calls for states bound to file descriptors.
+#[cfg(unix)]
+mod sys {
+    use std::io::{self, ErrorKind};
+
+    pub fn read(fd: i32, buffer: &mut [u8]) -> io::Result<usize> {
+        let read = unsafe { libc::read(fd, buffer.as_mut_ptr().cast(), buffer.len()) };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(read as usize)
+    }
+
+    pub fn write_all(fd: i32, mut buffer: &[u8]) -> io::Result<()> {
+        while !buffer.is_empty() {
+            let written = unsafe { libc::write(fd, buffer.as_ptr().cast(), buffer.len()) };
+            if written < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error);
+            }
+            if written == 0 {
+                return Err(ErrorKind::WriteZero.into());
+            }
+            buffer = &buffer[written as usize..];
         }
+        Ok(())
     }
 }
@@ -0,0 +1,181 @@
+use super::{
+    codegen::NativeCodeGenBackend,
+    executor::NativeExecutor,
+    state::State,
+    x86_64::{alias_asm, X86_64CodeGen},
+};
+use crate::execution::{
+    tape::TapeConfig,
+    trace::{Trace, TraceEnd, TraceOp, TraceTree},
+    ExecutionLimits,
+};
+use dynasmrt::{dynasm, x64::X64Relocation, Assembler, DynamicLabel, DynasmApi, DynasmLabelApi};
+use std::{collections::HashMap, mem::offset_of};
+
+/// Compiles the root trace of a loop together with its branches. Guards that fail go to the branch
+/// recorded from their exit if there is one. All other exits store the bytecode index to continue at
+/// in `State::trace_exit` and return `Ok`, errors return as usual.
+/// `trees` are the trees that `TraceOp::Loop` refers to, their code is inlined.
+pub fn generate(
+    root: &Trace,
+    branches: &[Trace],
+    trees: &[TraceTree],
+    tape: TapeConfig,
+) -> NativeExecutor {
+    // Moves are checked by the trace itself, so the code never relies on guard pages.
+    let codegen = X86_64CodeGen::new(
+        TapeConfig {
+            guard_pages: false,
+            ..tape
+        },
+        ExecutionLimits::default(),
+    );
+    let mut compiler = TreeCompiler {
+        codegen,
+        ops: dynasmrt::x64::Assembler::new().unwrap(),
+        trees,
+        branch_labels: Vec::new(),
+        exits: Vec::new(),
+    };
+    compiler.codegen.generate_prolouge(&mut compiler.ops);
+    compiler.generate_tree(root, branches, None);
+
+    let TreeCompiler {
+        codegen,
+        mut ops,
+        exits,
+        ..
+    } = compiler;
+    let trace_exit_offset = offset_of!(State, trace_exit) as i32;
+    for (label, exit) in exits {
+        alias_asm!(ops,
+            ;=>label
+            ; mov QWORD [state + trace_exit_offset], exit as i32
+            ; mov retval, 0
+            ; jmp ->store_pointer
+        );
+    }
+    codegen.generate_epilouge(&mut ops);
+    codegen.finalize(ops)
+}
+
+struct TreeCompiler<'t> {
+    codegen: X86_64CodeGen,
+    ops: Assembler<X64Relocation>,
+    trees: &'t [TraceTree],
+    /// The labels of the branches of every tree being generated, innermost last.
+    branch_labels: Vec<HashMap<usize, DynamicLabel>>,
+    /// The label and bytecode index of every exit back to the VM
+    exits: Vec<(DynamicLabel, usize)>,
+}
+
+impl TreeCompiler<'_> {
+    fn exit_label(&mut self, exit: usize) -> DynamicLabel {
+        let label = self.ops.new_dynamic_label();
+        self.exits.push((label, exit));
+        label
+    }
+
+    /// The label to continue at `exit`: a branch starting there, or an exit to the VM.
+    /// Branches of enclosing trees are valid, too, as traces do not assume anything about the tape.
+    fn branch_or_exit(&mut self, exit: usize) -> DynamicLabel {
+        let branch = self
+            .branch_labels
+            .iter()
+            .rev()
+            .find_map(|labels| labels.get(&exit));
+        match branch {
+            Some(label) => *label,
+            None => self.exit_label(exit),
+        }
+    }
+
+    /// Generates a loop and its branches. When the loop ends, continues at `next`, or leaves if there is none.
+    fn generate_tree(&mut self, root: &Trace, branches: &[Trace], next: Option<DynamicLabel>) {
+        let loop_start = self.ops.new_dynamic_label();
+        let labels: HashMap<_, _> = branches
+            .iter()
+            .map(|branch| (branch.start, self.ops.new_dynamic_label()))
+            .collect();
+        self.branch_labels.push(labels.clone());
+
+        for (trace, label) in std::iter::once((root, loop_start)).chain(
+            branches
+                .iter()
+                .map(|branch| (branch, labels[&branch.start])),
+        ) {
+            alias_asm!(self.ops,
+                ;=>label
+            );
+            for op in &trace.ops {
+                self.generate_op(*op);
+            }
+
+            self.codegen.forget_cell(&mut self.ops);
+            let end = match trace.end {
+                TraceEnd::Loop { exit } => {
+                    alias_asm!(self.ops,
+                        ; cmp BYTE [cell_ptr], 0
+                        ; jnz =>loop_start
+                    );
+                    match next {
+                        Some(next) => next,
+                        None => self.exit_label(exit),
+                    }
+                }
+                TraceEnd::Exit(exit) => self.branch_or_exit(exit),
+            };
+            alias_asm!(self.ops,
+                ; jmp =>end
+            );
+        }
+        self.branch_labels.pop();
+    }
+
+    fn generate_op(&mut self, op: TraceOp) {
+        match op {
+            TraceOp::Add(value) => self.codegen.generate_increment(&mut self.ops, value),
+            TraceOp::Set(value) => self.codegen.generate_set(&mut self.ops, value),
+            // A move can not go to a branch, which might start with the very same move.
+            TraceOp::Move { value, exit } => {
+                self.codegen.forget_cell(&mut self.ops);
+                let exit = self.exit_label(exit);
+                alias_asm!(self.ops,
+                    ; lea rax, [cell_ptr + value]
+                    ; cmp rax, tape_start
+                    ; jb =>exit
+                    ; cmp rax, tape_end
+                    ; jae =>exit
+                    ; mov cell_ptr, rax
+                );
+            }
+            TraceOp::Write => self.codegen.generate_write(&mut self.ops),
+            TraceOp::Read => self.codegen.generate_read(&mut self.ops),
+            TraceOp::Guard { zero, exit } => {
+                self.codegen.forget_cell(&mut self.ops);
+                let exit = self.branch_or_exit(exit);
+                alias_asm!(self.ops,
+                    ; cmp BYTE [cell_ptr], 0
+                );
+                if zero {
+                    alias_asm!(self.ops,
+                        ; jnz =>exit
+                    );
+                } else {
+                    alias_asm!(self.ops,
+                        ; jz =>exit
+                    );
+                }
+            }
+            TraceOp::Loop { tree } => {
+                let tree = &self.trees[tree];
+                let next = self.ops.new_dynamic_label();
+                self.codegen.forget_cell(&mut self.ops);
+                self.generate_tree(&tree.root, &tree.branches, Some(next));
+                alias_asm!(self.ops,
+                    ;=>next
+                );
+            }
+        }
+    }
+}
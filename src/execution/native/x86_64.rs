@@ -1,9 +1,20 @@
-use super::codegen::NativeCodeGenBackend;
-use crate::{execution::native::state::State, syntax::Instruction};
+use super::{codegen::NativeCodeGenBackend, executor::NativeExecutor};
+use crate::{
+    execution::{
+        native::{memory::GUARD_SIZE, state::State},
+        tape::{TapeBounds, TapeConfig},
+        ExecutionLimits,
+    },
+    syntax::{Idiom, Instruction, Term},
+};
 use dynasmrt::dynasm;
 use dynasmrt::DynasmApi;
 use dynasmrt::DynasmLabelApi;
-use dynasmrt::{x64::X64Relocation, Assembler, AssemblyOffset};
+use dynasmrt::{components::StaticLabel, x64::X64Relocation, Assembler};
+use std::{
+    cell::{Cell, RefCell},
+    mem::offset_of,
+};
 
 /// Alias registers for easier access in the generated code.
 /// Store all our relevant data in registers.
@@ -20,12 +31,11 @@ macro_rules! alias_asm {
             ; .alias tape_end, r13
             ; .alias tape_size, r14
             ; .alias cell_ptr, r15
+            ; .alias cell_value, r10b
 
             ; .alias retval, rax
             ; .alias retval_lower_8, al
 
-            ; .alias fn_call_reg, rax
-
             ; .alias first_arg, rcx
             ; .alias second_arg, rdx
             ; .alias third_arg, r8
@@ -45,12 +55,11 @@ macro_rules! alias_asm {
             ; .alias tape_end, r14
             ; .alias cell_ptr, r15
             ; .alias tape_size, r11
+            ; .alias cell_value, r10b
 
             ; .alias retval, rax
             ; .alias retval_lower_8, al
 
-            ; .alias fn_call_reg, rax
-
             ; .alias first_arg, rdi
             ; .alias second_arg, rsi
             ; .alias third_arg, rdx
@@ -60,6 +69,8 @@ macro_rules! alias_asm {
     }
 }
 
+pub(crate) use alias_asm;
+
 macro_rules! x64_save_registers {
     ($ops:expr) => {
         alias_asm!($ops,
@@ -86,94 +97,362 @@ macro_rules! x64_restore_registers {
     };
 }
 
+/// Where the current cell lives while straight-line code works on it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CachedCell {
+    /// Only in memory.
+    None,
+    /// `cell_value` holds the same value as memory.
+    Clean,
+    /// `cell_value` holds a value that was not written back yet.
+    Dirty,
+}
+
 /// The x64 code generation backend. Only for windows x64.
-pub struct X86_64CodeGen;
+pub struct X86_64CodeGen {
+    tape: TapeConfig,
+    limits: ExecutionLimits,
+    /// Distance the pointer moved since the tape was last accessed without a bounds check.
+    unchecked_move: Cell<i64>,
+    /// The current cell is kept in `cell_value` until the next move, call or jump target.
+    cached_cell: Cell<CachedCell>,
+    /// Where the code of each instruction starts, see `NativeExecutor::instruction_at`.
+    instruction_starts: RefCell<Vec<usize>>,
+}
+
+impl X86_64CodeGen {
+    pub fn new(tape: TapeConfig, limits: ExecutionLimits) -> Self {
+        Self {
+            tape,
+            limits,
+            unchecked_move: Cell::new(0),
+            cached_cell: Cell::new(CachedCell::None),
+            instruction_starts: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Loads the current cell into `cell_value` unless it is there already.
+    fn load_cell(&self, ops: &mut Assembler<X64Relocation>) {
+        if self.cached_cell.get() == CachedCell::None {
+            alias_asm!(ops,
+                ; mov cell_value, BYTE [cell_ptr]
+            );
+            self.touch_cell();
+            self.cached_cell.set(CachedCell::Clean);
+        }
+    }
+
+    /// Writes `cell_value` back to the current cell if it changed.
+    fn store_cell(&self, ops: &mut Assembler<X64Relocation>) {
+        if self.cached_cell.get() == CachedCell::Dirty {
+            alias_asm!(ops,
+                ; mov BYTE [cell_ptr], cell_value
+            );
+            self.touch_cell();
+            self.cached_cell.set(CachedCell::Clean);
+        }
+    }
+
+    /// Writes the cached cell back and forgets it. Needed before the pointer moves,
+    /// before calls that clobber `cell_value` and before code other paths can jump to.
+    pub(crate) fn forget_cell(&self, ops: &mut Assembler<X64Relocation>) {
+        self.store_cell(ops);
+        self.cached_cell.set(CachedCell::None);
+    }
+
+    /// Loads 16 bytes into `xmm0`.
+    fn load_vector(&self, ops: &mut Assembler<X64Relocation>, bytes: &[u8]) {
+        if bytes.iter().all(|byte| *byte == 0) {
+            alias_asm!(ops,
+                ; pxor xmm0, xmm0
+            );
+            return;
+        }
+        let low = i64::from_le_bytes(bytes[..8].try_into().unwrap());
+        let high = i64::from_le_bytes(bytes[8..].try_into().unwrap());
+        alias_asm!(ops,
+            ; mov rax, QWORD low
+            ; movq xmm0, rax
+            ; mov rax, QWORD high
+            ; movq xmm1, rax
+            ; punpcklqdq xmm0, xmm1
+        );
+    }
+
+    /// Compares the current cell with zero, in `cell_value` if it is cached.
+    fn test_cell(&self, ops: &mut Assembler<X64Relocation>) {
+        self.store_cell(ops);
+        if self.cached_cell.get() == CachedCell::Clean {
+            alias_asm!(ops,
+                ; test cell_value, cell_value
+            );
+        } else {
+            alias_asm!(ops,
+                ; cmp BYTE [cell_ptr], 0
+            );
+            self.touch_cell();
+        }
+    }
+
+    /// Records that the generated code accessed the current cell.
+    /// With guard pages an out of bounds pointer has faulted by now, so it is known to be in bounds.
+    fn touch_cell(&self) {
+        self.unchecked_move.set(0);
+    }
+
+    /// With guard pages, a move needs no bounds check as long as the pointer
+    /// can not skip over a guard region before the next access.
+    fn is_guarded_move(&self, value: i32) -> bool {
+        if !self.tape.guard_pages || (value < 0 && self.tape.bidirectional) {
+            return false;
+        }
+        let unchecked = self.unchecked_move.get() + value as i64;
+        if unchecked.unsigned_abs() >= GUARD_SIZE as u64 {
+            return false;
+        }
+        self.unchecked_move.set(unchecked);
+        true
+    }
+
+    /// Calls into the runtime with the current cell, leaving its result in `retval`. With guard pages
+    /// the cell is accessed first, so a fault on a guard page happens in generated code rather than in the runtime.
+    /// `function` is the offset of the function address in `State`.
+    fn generate_runtime_call(&self, ops: &mut Assembler<X64Relocation>, function: usize) {
+        if self.tape.guard_pages {
+            alias_asm!(ops,
+                ; cmp BYTE [cell_ptr], 0
+            );
+        }
+        self.touch_cell();
+        alias_asm!(ops,
+            ;; x64_save_registers!(ops)
+
+            ; mov first_arg, state
+            ; mov second_arg, cell_ptr
+            ; call QWORD [state + function as i32]
+
+            ;; x64_restore_registers!(ops)
+        );
+    }
+
+    /// Calls into the runtime to grow the tape to the left and reloads the tape bounds afterwards.
+    fn generate_grow_tape(&self, ops: &mut Assembler<X64Relocation>) {
+        let tape_start_offset = offset_of!(State, tape_start) as i32;
+        let tape_end_offset = offset_of!(State, tape_end) as i32;
+        let grow_tape_fn_offset = offset_of!(State, grow_tape_fn) as i32;
+        alias_asm!(ops,
+            ;; x64_save_registers!(ops)
+
+            ; mov first_arg, state
+            ; mov second_arg, cell_ptr
+            ; call QWORD [state + grow_tape_fn_offset]
+
+            ;; x64_restore_registers!(ops)
+
+            ; mov cell_ptr, retval
+            ; mov tape_start, QWORD [state + tape_start_offset]
+            ; mov tape_end, QWORD [state + tape_end_offset]
+            ; mov tape_size, tape_end
+            ; sub tape_size, tape_start
+        );
+    }
+}
 
 impl NativeCodeGenBackend for X86_64CodeGen {
-    type Relocation = X64Relocation;
+    type Ops = Assembler<X64Relocation>;
 
-    fn generate_prolouge(&self, ops: &mut Assembler<Self::Relocation>) -> AssemblyOffset {
-        let start = ops.offset();
+    fn generate_prolouge(&self, ops: &mut Self::Ops) {
+        let resume_at_offset = offset_of!(State, resume_at) as i32;
         alias_asm!(ops,
+            ;->code_start:
             // Allocate shadow space for win64 calling convention
             ; sub rsp, 40
 
             ;; x64_save_registers!(ops)
 
             // Save the passed arguments to their registers
-            // Signature: fn(State* state, u8* tape_start, u8* tape_end) -> u8
+            // Signature: fn(State* state, u8* tape_start, u8* tape_end, u8* cell_ptr) -> u8
             ; mov state, first_arg
             ; mov tape_start, second_arg
             ; mov tape_end, third_arg
-
-            ; mov cell_ptr, tape_start
+            ; mov cell_ptr, fourth_arg
 
             // Calculate the tape size
             ; mov tape_size, tape_end
             ; sub tape_size, tape_start
+
+            // Continue a suspended program at its resume point
+            ; mov rax, QWORD [state + resume_at_offset]
+            ; test rax, rax
+            ; jz >start
+            ; mov QWORD [state + resume_at_offset], 0
+            ; jmp rax
+            ; start:
         );
-        start
     }
 
-    fn generate_epilouge(&self, ops: &mut Assembler<Self::Relocation>) {
+    fn generate_epilouge(&self, ops: &mut Self::Ops) {
         macro_rules! epilogue {
-            ($ops:expr, $e:expr) => {
+            ($ops:expr) => {
                 alias_asm!($ops,
                     ;; x64_restore_registers!($ops)
-                    ; mov retval, $e
                     ; add rsp, 40
                     ; ret
                 );
             };
         }
 
+        self.forget_cell(ops);
+
+        // Moves past the end of the program are not followed by an access that could fault.
+        if self.tape.guard_pages {
+            alias_asm!(ops,
+                ; cmp BYTE [cell_ptr], 0
+            );
+        }
+
+        let fuel_offset = offset_of!(State, fuel) as i32;
+        let pointer_offset = offset_of!(State, pointer) as i32;
+        let interrupt_flag_offset = offset_of!(State, interrupt_flag) as i32;
+        let slice_offset = offset_of!(State, slice) as i32;
+        let resume_at_offset = offset_of!(State, resume_at) as i32;
+
         // All possible exit points from the program can be jumped to by their respective label.
+        // Every exit but `error_bounds` leaves the pointer in bounds and stores it in the state.
         alias_asm!(ops,
-            ;; epilogue!(ops, 0)
+            ; mov retval, 0
+            ; jmp ->store_pointer
             ;->error_io:
-            ;; epilogue!(ops, 1)
+            ; mov retval, 1
+            ; jmp ->store_pointer
+            ;->error_fuel:
+            // Running out of fuel borrowed one unit, so reset the fuel to zero.
+            ; mov QWORD [state + fuel_offset], 0
+            ; mov retval, 3
+            ; jmp ->store_pointer
+            // Suspensions jump here with the address to resume at in `rax`.
+            ;->input_pending:
+            ; mov QWORD [state + resume_at_offset], rax
+            ; mov retval, 6
+            ; jmp ->store_pointer
+            ;->yielded:
+            ; mov QWORD [state + resume_at_offset], rax
+            // Ending the slice borrowed one step, so reset it to zero.
+            ; mov QWORD [state + slice_offset], 0
+            ; mov retval, 7
+            ; jmp ->store_pointer
+            ;->interrupted:
+            // The flag holds the result code to stop with.
+            ; mov retval, QWORD [state + interrupt_flag_offset]
+            ; movzx eax, BYTE [retval]
+            ;->store_pointer:
+            ; mov fourth_arg, cell_ptr
+            ; sub fourth_arg, tape_start
+            ; mov QWORD [state + pointer_offset], fourth_arg
+            ;; epilogue!(ops)
             ;->error_bounds:
-            ;; epilogue!(ops, 2)
+            ; mov retval, 2
+            ;; epilogue!(ops)
         );
     }
 
+    /// A cached cell is written back by the code of a later instruction,
+    /// so a fault there is reported for the instruction that touched the cell last.
+    fn start_instruction(&self, ops: &mut Self::Ops) {
+        self.instruction_starts.borrow_mut().push(ops.offset().0);
+    }
+
     /// Handles overflows and underflows by wrapping around the value.
-    fn generate_increment(&self, ops: &mut Assembler<Self::Relocation>, value: i8) {
+    fn generate_increment(&self, ops: &mut Self::Ops, value: i8) {
+        self.load_cell(ops);
         alias_asm!(ops,
-            ; add BYTE [cell_ptr], value
+            ; add cell_value, value
         );
+        self.cached_cell.set(CachedCell::Dirty);
     }
 
-    fn generate_set(&self, ops: &mut Assembler<Self::Relocation>, value: u8) {
+    fn generate_set(&self, ops: &mut Self::Ops, value: u8) {
         alias_asm!(ops,
-            ; mov BYTE [cell_ptr], value as i8
+            ; mov cell_value, value as i8
         );
+        self.cached_cell.set(CachedCell::Dirty);
     }
 
-    fn generate_cell_increment(&self, ops: &mut Assembler<Self::Relocation>, value: i32) {
+    fn generate_cell_increment(&self, ops: &mut Self::Ops, value: i32) {
+        self.forget_cell(ops);
         alias_asm!(ops,
             ; add cell_ptr, value
         );
-        if value > 0 {
-            alias_asm!(ops,
-                ; cmp cell_ptr, tape_end
-                ; jae ->error_bounds
-            );
-        } else {
-            alias_asm!(ops,
-                ; cmp cell_ptr, tape_start
-                ; jb ->error_bounds
-            );
+        if self.tape.bounds == TapeBounds::Error && self.is_guarded_move(value) {
+            return;
+        }
+        self.touch_cell();
+        match (self.tape.bounds, value > 0) {
+            // Moves can span the tape several times, so keep wrapping until the pointer is back in range.
+            (TapeBounds::Wrap, true) => {
+                alias_asm!(ops,
+                    ; cmp cell_ptr, tape_end
+                    ; jb >in_bounds
+                    ; wrap:
+                    ; sub cell_ptr, tape_size
+                    ; cmp cell_ptr, tape_end
+                    ; jae <wrap
+                    ; in_bounds:
+                );
+            }
+            (TapeBounds::Wrap, false) => {
+                alias_asm!(ops,
+                    ; cmp cell_ptr, tape_start
+                    ; jae >in_bounds
+                    ; wrap:
+                    ; add cell_ptr, tape_size
+                    ; cmp cell_ptr, tape_start
+                    ; jb <wrap
+                    ; in_bounds:
+                );
+            }
+            (TapeBounds::Error, true) => {
+                alias_asm!(ops,
+                    ; cmp cell_ptr, tape_end
+                    ; jae ->error_bounds
+                );
+            }
+            (TapeBounds::Error, false) if self.tape.bidirectional => {
+                alias_asm!(ops,
+                    ; cmp cell_ptr, tape_start
+                    ; jae >in_bounds
+                );
+                self.generate_grow_tape(ops);
+                alias_asm!(ops,
+                    ; in_bounds:
+                );
+            }
+            (TapeBounds::Error, false) => {
+                alias_asm!(ops,
+                    ; cmp cell_ptr, tape_start
+                    ; jb ->error_bounds
+                );
+            }
         }
     }
 
-    fn generate_loop(&self, ops: &mut Assembler<Self::Relocation>, nodes: &[Instruction]) {
+    fn generate_unchecked_cell_increment(&self, ops: &mut Self::Ops, value: i32) {
+        self.forget_cell(ops);
+        // The pointer is known to be in bounds, just like after a checked access.
+        self.touch_cell();
+        alias_asm!(ops,
+            ; add cell_ptr, value
+        );
+    }
+
+    fn generate_loop(&self, ops: &mut Self::Ops, nodes: &[Instruction]) {
         let backward_label = ops.new_dynamic_label();
         let forward_label = ops.new_dynamic_label();
 
         // Start of the loop: Check if the current cell is 0, jump to the forward label (end of loop) if true.
+        self.test_cell(ops);
+        self.forget_cell(ops);
         alias_asm!(ops,
-            ; cmp BYTE [cell_ptr], 0
             ; jz =>forward_label
             ;=>backward_label
         );
@@ -184,37 +463,170 @@ impl NativeCodeGenBackend for X86_64CodeGen {
         }
 
         // End of the loop: Jump back to the start of the loop if the condition is still true.
+        let polls_interrupt = self.limits.polls_interrupt();
+        let counts_fuel = self.limits.max_steps.is_some();
+        let yields = self.limits.slice.is_some();
+        self.test_cell(ops);
+        if polls_interrupt || counts_fuel || yields {
+            alias_asm!(ops,
+                ; jz =>forward_label
+            );
+            if polls_interrupt {
+                let interrupt_flag_offset = offset_of!(State, interrupt_flag) as i32;
+                alias_asm!(ops,
+                    ; mov rax, QWORD [state + interrupt_flag_offset]
+                    ; cmp BYTE [rax], 0
+                    ; jnz ->interrupted
+                );
+            }
+            // Every iteration costs one step of fuel and of the slice. Subtracting from zero borrows.
+            if counts_fuel {
+                let fuel_offset = offset_of!(State, fuel) as i32;
+                alias_asm!(ops,
+                    ; sub QWORD [state + fuel_offset], 1
+                );
+                if yields {
+                    alias_asm!(ops,
+                        ; jc ->error_fuel
+                    );
+                } else {
+                    alias_asm!(ops,
+                        ; jnc =>backward_label
+                        ; jmp ->error_fuel
+                    );
+                }
+            }
+            if yields {
+                // The iteration was already paid for, so resuming starts right in the loop body.
+                let slice_offset = offset_of!(State, slice) as i32;
+                alias_asm!(ops,
+                    ; sub QWORD [state + slice_offset], 1
+                    ; jnc =>backward_label
+                    ; lea rax, [=>backward_label]
+                    ; jmp ->yielded
+                );
+            } else if !counts_fuel {
+                alias_asm!(ops,
+                    ; jmp =>backward_label
+                );
+            }
+            alias_asm!(ops,
+                ;=>forward_label
+            );
+        } else {
+            alias_asm!(ops,
+                ; jnz =>backward_label
+                ;=>forward_label
+            );
+        }
+        // The loop only ends once the cell is zero.
+        alias_asm!(ops,
+            ; mov cell_value, 0
+        );
+        self.touch_cell();
+        self.cached_cell.set(CachedCell::Clean);
+    }
+
+    fn generate_if(&self, ops: &mut Self::Ops, nodes: &[Instruction]) {
+        let forward_label = ops.new_dynamic_label();
+
+        self.test_cell(ops);
+        self.forget_cell(ops);
+        alias_asm!(ops,
+            ; jz =>forward_label
+        );
+
+        for node in nodes {
+            self.generate_instruction(ops, node);
+        }
+
+        // The body ends on the cell it started at, which it left zero.
+        self.forget_cell(ops);
         alias_asm!(ops,
-            ; cmp BYTE [cell_ptr], 0
-            ; jnz =>backward_label
             ;=>forward_label
+            ; mov cell_value, 0
         );
+        self.touch_cell();
+        self.cached_cell.set(CachedCell::Clean);
     }
 
-    fn generate_write(&self, ops: &mut Assembler<Self::Relocation>) {
+    /// The runtime checks that the cells of the idiom are in the tape, so the call needs no bounds check.
+    fn generate_idiom(&self, ops: &mut Self::Ops, idiom: Idiom, nodes: &[Instruction]) {
+        let idiom_fn_offset = offset_of!(State, idiom_fn) as i32;
+        let done_label = ops.new_dynamic_label();
+
+        self.forget_cell(ops);
         alias_asm!(ops,
             ;; x64_save_registers!(ops)
 
             ; mov first_arg, state
             ; mov second_arg, cell_ptr
-            ; mov fn_call_reg, QWORD State::putchar as *const () as i64
-            ; call fn_call_reg
+            ; mov third_arg, idiom as i32
+            ; call QWORD [state + idiom_fn_offset]
 
             ;; x64_restore_registers!(ops)
 
+            ; cmp retval_lower_8, 1
+            ; jb =>done_label
+            ; je ->error_io
+        );
+
+        // The idiom does not apply, so run its snippet.
+        for node in nodes {
+            self.generate_instruction(ops, node);
+        }
+
+        self.forget_cell(ops);
+        alias_asm!(ops,
+            ;=>done_label
+        );
+    }
+
+    fn generate_write(&self, ops: &mut Self::Ops) {
+        let output_buffer_offset = offset_of!(State, output_buffer) as i32;
+        let output_len_offset = offset_of!(State, output_len) as i32;
+        let output_limit_offset = offset_of!(State, output_limit) as i32;
+        // Append to the output buffer, the runtime only has to flush it once it reached
+        // `output_limit` or a line is complete.
+        self.load_cell(ops);
+        self.store_cell(ops);
+        alias_asm!(ops,
+            ; mov rax, QWORD [state + output_len_offset]
+            ; cmp rax, QWORD [state + output_limit_offset]
+            ; jae >flush
+            ; cmp cell_value, b'\n' as i8
+            ; je >flush
+            ; mov BYTE [state + rax + output_buffer_offset], cell_value
+            ; add rax, 1
+            ; mov QWORD [state + output_len_offset], rax
+            ; jmp >done
+            ; flush:
+        );
+        self.generate_runtime_call(ops, offset_of!(State, putchar_fn));
+        // The call clobbered `cell_value`.
+        alias_asm!(ops,
+            ; mov cell_value, BYTE [cell_ptr]
             ; cmp retval_lower_8, 0
             ; jnz ->error_io
+            ; done:
         );
     }
 
-    fn generate_read(&self, ops: &mut Assembler<Self::Relocation>) {
+    /// The bytes are embedded in the code and written by the runtime in a single call.
+    fn generate_write_bytes(&self, ops: &mut Self::Ops, bytes: &[u8]) {
+        let write_bytes_fn_offset = offset_of!(State, write_bytes_fn) as i32;
+        self.forget_cell(ops);
         alias_asm!(ops,
+            ; jmp >code
+            ; data:
+            ; .bytes bytes.iter()
+            ; code:
             ;; x64_save_registers!(ops)
 
             ; mov first_arg, state
-            ; mov second_arg, cell_ptr
-            ; mov fn_call_reg, QWORD State::getchar as *const () as i64
-            ; call fn_call_reg
+            ; lea second_arg, [<data]
+            ; mov third_arg, bytes.len() as i32
+            ; call QWORD [state + write_bytes_fn_offset]
 
             ;; x64_restore_registers!(ops)
 
@@ -222,4 +634,174 @@ impl NativeCodeGenBackend for X86_64CodeGen {
             ; jnz ->error_io
         );
     }
+
+    fn generate_read(&self, ops: &mut Self::Ops) {
+        let input_buffer_offset = offset_of!(State, input_buffer) as i32;
+        let input_pos_offset = offset_of!(State, input_pos) as i32;
+        let input_len_offset = offset_of!(State, input_len) as i32;
+        // Take the next byte from the input buffer, the runtime only has to refill it once it is empty.
+        // Without input the refill suspends, and resuming repeats the read.
+        self.forget_cell(ops);
+        alias_asm!(ops,
+            ; resume:
+            ; mov rax, QWORD [state + input_pos_offset]
+            ; cmp rax, QWORD [state + input_len_offset]
+            ; jae >refill
+            ; movzx ecx, BYTE [state + rax + input_buffer_offset]
+            ; mov BYTE [cell_ptr], cl
+            ; add rax, 1
+            ; mov QWORD [state + input_pos_offset], rax
+            ; jmp >done
+            ; refill:
+        );
+        self.generate_runtime_call(ops, offset_of!(State, getchar_fn));
+        alias_asm!(ops,
+            ; cmp retval_lower_8, 0
+            ; jz >done
+            ; cmp retval_lower_8, 2
+            ; jne ->error_io
+            ; lea rax, [<resume]
+            ; jmp ->input_pending
+            ; done:
+        );
+    }
+
+    /// Multiplies in `ecx` and adds every term to the cell on its own, the low byte is all that matters.
+    fn generate_mul_add(&self, ops: &mut Self::Ops, offset: i32, terms: &[Term]) {
+        self.forget_cell(ops);
+        for term in terms {
+            alias_asm!(ops,
+                ; mov ecx, term.coefficient as i32
+            );
+            for factor in &term.factors {
+                alias_asm!(ops,
+                    ; movzx eax, BYTE [cell_ptr + *factor]
+                    ; imul ecx, eax
+                );
+            }
+            alias_asm!(ops,
+                ; add BYTE [cell_ptr + offset], cl
+            );
+        }
+    }
+
+    /// Stores 16 bytes at a time with SSE, the rest with the widest integer stores that fit.
+    fn generate_set_range(&self, ops: &mut Self::Ops, offset: i32, values: &[u8]) {
+        self.forget_cell(ops);
+        let mut cell = offset;
+        let mut loaded = None;
+        let mut chunks = values.chunks_exact(16);
+        for chunk in &mut chunks {
+            if loaded != Some(chunk) {
+                self.load_vector(ops, chunk);
+                loaded = Some(chunk);
+            }
+            alias_asm!(ops,
+                ; movdqu [cell_ptr + cell], xmm0
+            );
+            cell += 16;
+        }
+
+        let mut rest = chunks.remainder();
+        while !rest.is_empty() {
+            let width = match rest.len() {
+                8.. => {
+                    let value = i64::from_le_bytes(rest[..8].try_into().unwrap());
+                    alias_asm!(ops,
+                        ; mov rax, QWORD value
+                        ; mov QWORD [cell_ptr + cell], rax
+                    );
+                    8
+                }
+                4.. => {
+                    let value = i32::from_le_bytes(rest[..4].try_into().unwrap());
+                    alias_asm!(ops,
+                        ; mov DWORD [cell_ptr + cell], value
+                    );
+                    4
+                }
+                2.. => {
+                    let value = i16::from_le_bytes(rest[..2].try_into().unwrap());
+                    alias_asm!(ops,
+                        ; mov WORD [cell_ptr + cell], value
+                    );
+                    2
+                }
+                _ => {
+                    alias_asm!(ops,
+                        ; mov BYTE [cell_ptr + cell], rest[0] as i8
+                    );
+                    1
+                }
+            };
+            rest = &rest[width..];
+            cell += width as i32;
+        }
+    }
+
+    /// Adds 16 bytes at a time with SSE, then 8, the rest one by one.
+    fn generate_add_vector(&self, ops: &mut Self::Ops, offset: i32, values: &[i8]) {
+        self.forget_cell(ops);
+        let bytes: Vec<u8> = values.iter().map(|value| *value as u8).collect();
+        let mut cell = offset;
+        let mut loaded = None;
+        let mut chunks = bytes.chunks_exact(16);
+        for chunk in &mut chunks {
+            if chunk.iter().any(|byte| *byte != 0) {
+                if loaded != Some(chunk) {
+                    self.load_vector(ops, chunk);
+                    loaded = Some(chunk);
+                }
+                alias_asm!(ops,
+                    ; movdqu xmm1, [cell_ptr + cell]
+                    ; paddb xmm1, xmm0
+                    ; movdqu [cell_ptr + cell], xmm1
+                );
+            }
+            cell += 16;
+        }
+
+        let mut rest = chunks.remainder();
+        if rest.len() >= 8 && rest[..8].iter().filter(|byte| **byte != 0).count() > 1 {
+            let value = i64::from_le_bytes(rest[..8].try_into().unwrap());
+            alias_asm!(ops,
+                ; mov rax, QWORD value
+                ; movq xmm0, rax
+                ; movq xmm1, QWORD [cell_ptr + cell]
+                ; paddb xmm1, xmm0
+                ; movq QWORD [cell_ptr + cell], xmm1
+            );
+            rest = &rest[8..];
+            cell += 8;
+        }
+        for value in rest {
+            if *value != 0 {
+                alias_asm!(ops,
+                    ; add BYTE [cell_ptr + cell], *value as i8
+                );
+            }
+            cell += 1;
+        }
+    }
+
+    /// `->error_bounds` doubles as the handler for faults on guard pages.
+    fn finalize(&self, ops: Self::Ops) -> NativeExecutor {
+        let labels = ops.labels();
+        let code_start = labels
+            .resolve_static(&StaticLabel::global("code_start"))
+            .expect("The prologue must define ->code_start");
+        let error_bounds = labels
+            .resolve_static(&StaticLabel::global("error_bounds"))
+            .expect("The epilogue must define ->error_bounds");
+        match ops.finalize() {
+            Ok(code) => NativeExecutor::new(
+                code,
+                code_start,
+                error_bounds,
+                self.tape.guard_pages,
+                self.instruction_starts.take(),
+            ),
+            Err(_) => panic!("Failed to finalize code"),
+        }
+    }
 }
@@ -0,0 +1,262 @@
+use super::{
+    interpreter::Interpreter,
+    native::{executor::NativeExecutor, state::State},
+    tape::Tape,
+    vm::{Program, Vm},
+    RuntimeResultCode,
+};
+use crate::syntax::Instruction;
+
+/// A program that can stop before it finishes and continue later with the same tape and pointer.
+pub trait Resumable: Sized {
+    /// Runs until the program finishes or suspends. After a suspension, continues where it left off.
+    fn run_slice(&mut self) -> RuntimeResultCode;
+
+    /// Runs the program until it finishes or suspends.
+    fn start(mut self) -> Execution<Self> {
+        let result = self.run_slice();
+        if result.is_suspended() {
+            Execution::Suspended(Suspended {
+                program: self,
+                reason: result,
+            })
+        } else {
+            Execution::Finished(self, result)
+        }
+    }
+}
+
+pub enum Execution<R> {
+    Finished(R, RuntimeResultCode),
+    Suspended(Suspended<R>),
+}
+
+/// A handle to a suspended program.
+pub struct Suspended<R> {
+    program: R,
+    reason: RuntimeResultCode,
+}
+
+impl<R: Resumable> Suspended<R> {
+    /// Either `InputPending` or `Yielded`.
+    pub fn reason(&self) -> RuntimeResultCode {
+        self.reason
+    }
+
+    pub fn program(&mut self) -> &mut R {
+        &mut self.program
+    }
+
+    /// Gives up on the program, e.g. to inspect its tape.
+    pub fn into_program(self) -> R {
+        self.program
+    }
+
+    /// Continues the program until it finishes or suspends again.
+    pub fn resume(self) -> Execution<R> {
+        self.program.start()
+    }
+}
+
+/// A compiled program together with the state it runs on.
+pub struct NativeRun<'e, 'a> {
+    pub executor: &'e NativeExecutor,
+    pub state: State<'a>,
+}
+
+impl Resumable for NativeRun<'_, '_> {
+    fn run_slice(&mut self) -> RuntimeResultCode {
+        self.executor.run(&mut self.state)
+    }
+}
+
+/// An interpreted program together with the interpreter running it.
+pub struct InterpreterRun<'p, 'a, T: Tape> {
+    pub nodes: &'p [Instruction],
+    pub interpreter: Interpreter<'a, T>,
+}
+
+impl<T: Tape> Resumable for InterpreterRun<'_, '_, T> {
+    fn run_slice(&mut self) -> RuntimeResultCode {
+        self.interpreter.interpret(self.nodes)
+    }
+}
+
+/// A bytecode program together with the VM running it.
+pub struct VmRun<'p, 'a, T: Tape> {
+    pub program: &'p Program,
+    pub vm: Vm<'a, T>,
+}
+
+impl<T: Tape> Resumable for VmRun<'_, '_, T> {
+    fn run_slice(&mut self) -> RuntimeResultCode {
+        self.vm.run(self.program)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::native::codegen::CodeGeneration;
+    use crate::execution::tape::{DenseTape, TapeConfig};
+    use crate::execution::ExecutionLimits;
+    use crate::syntax::parse;
+    use std::{
+        cell::RefCell,
+        collections::VecDeque,
+        io::{self, ErrorKind, Read, Write},
+        rc::Rc,
+    };
+
+    /// Adds four to every byte up to a zero byte, in a loop so that slices end inside of it.
+    const PROGRAM: &str = ",[>++++[<+>-]<.,]";
+    const INPUT: &[u8] = b"abc\0";
+    /// Steps per slice of the suspended run.
+    const SLICE: u64 = 3;
+
+    /// Input that only has the bytes delivered so far. Reading more would block.
+    #[derive(Clone, Default)]
+    struct Feed(Rc<RefCell<VecDeque<u8>>>);
+
+    impl Read for Feed {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut bytes = self.0.borrow_mut();
+            if bytes.is_empty() {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            bytes.read(buf)
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Sink(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Runs a program built by `make` once with all input at hand, and once with slices of a few steps
+    /// and input that arrives a byte at a time whenever the program waits for it.
+    /// Both runs must end with the same result, pointer, tape and output, which `inspect` reads.
+    fn resumes_like_a_single_run<R: Resumable>(
+        make: impl Fn(Feed, Sink, ExecutionLimits) -> R,
+        inspect: impl Fn(&R) -> (usize, Vec<u8>),
+    ) {
+        let (feed, sink) = (Feed::default(), Sink::default());
+        feed.0.borrow_mut().extend(INPUT);
+        let Execution::Finished(program, result) =
+            make(feed, sink.clone(), ExecutionLimits::default()).start()
+        else {
+            panic!("A run with all input at hand suspended");
+        };
+        assert_eq!(result, RuntimeResultCode::Ok);
+        let expected = (result, inspect(&program), sink.0.take());
+        assert_eq!(expected.2, b"efg");
+
+        let (feed, sink) = (Feed::default(), Sink::default());
+        let limits = ExecutionLimits {
+            slice: Some(SLICE),
+            ..Default::default()
+        };
+        let mut input = INPUT.iter();
+        let (mut pending, mut yielded) = (0, 0);
+        let mut execution = make(feed.clone(), sink.clone(), limits).start();
+        let (program, result) = loop {
+            match execution {
+                Execution::Finished(program, result) => break (program, result),
+                Execution::Suspended(suspended) => {
+                    if suspended.reason() == RuntimeResultCode::InputPending {
+                        pending += 1;
+                        feed.0.borrow_mut().push_back(*input.next().unwrap());
+                    } else {
+                        yielded += 1;
+                    }
+                    execution = suspended.resume();
+                }
+            }
+        };
+        assert_eq!(pending, INPUT.len());
+        assert!(yielded > 0);
+        assert_eq!((result, inspect(&program), sink.0.take()), expected);
+    }
+
+    fn cells(tape: &impl Tape) -> Vec<u8> {
+        (0..tape.size()).map(|index| tape.get(index)).collect()
+    }
+
+    #[test]
+    fn interpreter() {
+        let nodes = parse(PROGRAM).unwrap();
+        resumes_like_a_single_run(
+            |input, output, limits| InterpreterRun {
+                nodes: &nodes,
+                interpreter: Interpreter::<DenseTape>::new(
+                    Box::new(input),
+                    Box::new(output),
+                    TapeConfig::new(8),
+                    limits,
+                ),
+            },
+            |run| (run.interpreter.pointer(), cells(run.interpreter.tape())),
+        );
+    }
+
+    #[test]
+    fn vm() {
+        let program = Program::compile(&parse(PROGRAM).unwrap());
+        resumes_like_a_single_run(
+            |input, output, limits| VmRun {
+                program: &program,
+                vm: Vm::<DenseTape>::new(
+                    Box::new(input),
+                    Box::new(output),
+                    TapeConfig::new(8),
+                    limits,
+                ),
+            },
+            |run| (run.vm.pointer(), cells(run.vm.tape())),
+        );
+    }
+
+    #[test]
+    fn native() {
+        let nodes = parse(PROGRAM).unwrap();
+        let sliced = ExecutionLimits {
+            slice: Some(SLICE),
+            ..Default::default()
+        };
+        // Code for slices counts steps, so it differs from code without them.
+        let executors = [
+            [
+                CodeGeneration::x86_x64(TapeConfig::new(8), ExecutionLimits::default())
+                    .generate(&nodes),
+                CodeGeneration::x86_x64(TapeConfig::new(8), sliced).generate(&nodes),
+            ],
+            [
+                CodeGeneration::cranelift(TapeConfig::new(8), ExecutionLimits::default())
+                    .generate(&nodes),
+                CodeGeneration::cranelift(TapeConfig::new(8), sliced).generate(&nodes),
+            ],
+        ];
+        for executors in &executors {
+            resumes_like_a_single_run(
+                |input, output, limits| NativeRun {
+                    executor: &executors[limits.slice.is_some() as usize],
+                    state: State::new(
+                        Box::new(input),
+                        Box::new(output),
+                        TapeConfig::new(8),
+                        limits,
+                    ),
+                },
+                |run| (run.state.pointer, run.state.tape.to_vec()),
+            );
+        }
+    }
+}
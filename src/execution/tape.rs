@@ -0,0 +1,287 @@
+use crate::syntax::Idiom;
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    str::FromStr,
+};
+
+/// Where the cell pointer is placed on the tape before execution starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartOffset {
+    Cell(usize),
+    Middle,
+}
+
+impl FromStr for StartOffset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "middle" => Ok(StartOffset::Middle),
+            _ => s
+                .parse()
+                .map(StartOffset::Cell)
+                .map_err(|_| format!("expected a cell index or `middle`, got `{}`", s)),
+        }
+    }
+}
+
+/// What happens when the pointer moves past either end of the tape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TapeBounds {
+    /// Stop execution with `CellOutOfBounds`.
+    Error,
+    /// Treat the tape as circular and wrap the pointer modulo the tape size.
+    Wrap,
+}
+
+/// Layout of the tape shared by all execution engines.
+#[derive(Debug, Clone, Copy)]
+pub struct TapeConfig {
+    /// The number of cells allocated up front.
+    pub size: usize,
+    pub start: StartOffset,
+    pub bounds: TapeBounds,
+    /// Extend the tape when the pointer moves left of the first cell instead of failing.
+    pub bidirectional: bool,
+    /// Surround the native tape with guard pages instead of checking every move.
+    /// Only affects native code on Linux.
+    pub guard_pages: bool,
+}
+
+impl TapeConfig {
+    pub fn new(size: usize) -> Self {
+        TapeConfig {
+            size,
+            start: StartOffset::Cell(0),
+            bounds: TapeBounds::Error,
+            bidirectional: false,
+            guard_pages: false,
+        }
+    }
+
+    /// The index of the cell the pointer starts at.
+    pub fn start_offset(&self) -> usize {
+        match self.start {
+            StartOffset::Cell(cell) => cell,
+            StartOffset::Middle => self.size / 2,
+        }
+    }
+}
+
+/// Storage for the cells of the interpreter.
+pub trait Tape {
+    fn with_size(size: usize) -> Self
+    where
+        Self: Sized;
+
+    /// The number of addressable cells.
+    fn size(&self) -> usize;
+
+    fn get(&self, index: usize) -> u8;
+
+    fn set(&mut self, index: usize, value: u8);
+
+    /// Grows the tape at the front so that a pointer `missing` cells left of the first cell becomes valid.
+    /// Returns the number of cells that were inserted in front of the old first cell.
+    fn grow_front(&mut self, missing: usize) -> usize;
+
+    /// Applies `idiom` to the cells from `index` on and appends its output, see `Idiom::apply`.
+    /// Returns false if the idiom does not apply or its cells are not all in the tape.
+    fn apply_idiom(&mut self, idiom: Idiom, index: usize, output: &mut Vec<u8>) -> bool {
+        let width = idiom.width();
+        if index + width > self.size() {
+            return false;
+        }
+        let mut cells = [0; Idiom::MAX_WIDTH];
+        let cells = &mut cells[..width];
+        for (offset, cell) in cells.iter_mut().enumerate() {
+            *cell = self.get(index + offset);
+        }
+        if !idiom.apply(cells, output) {
+            return false;
+        }
+        for (offset, cell) in cells.iter().enumerate() {
+            self.set(index + offset, *cell);
+        }
+        true
+    }
+
+    /// Writes all rows of the tape that contain a non-zero cell.
+    fn dump(&self, out: &mut dyn Write) -> io::Result<()>;
+}
+
+/// A tape backed by a single contiguous allocation.
+pub struct DenseTape(Box<[u8]>);
+
+impl Tape for DenseTape {
+    fn with_size(size: usize) -> Self {
+        DenseTape(vec![0; size].into_boxed_slice())
+    }
+
+    fn size(&self) -> usize {
+        self.0.len()
+    }
+
+    #[inline(always)]
+    fn get(&self, index: usize) -> u8 {
+        self.0[index]
+    }
+
+    #[inline(always)]
+    fn set(&mut self, index: usize, value: u8) {
+        self.0[index] = value;
+    }
+
+    /// The tape at least doubles in size to keep repeated growth cheap.
+    fn grow_front(&mut self, missing: usize) -> usize {
+        let grown = missing.max(self.0.len()).max(1);
+        let mut new_tape = vec![0; grown + self.0.len()];
+        new_tape[grown..].copy_from_slice(&self.0);
+        self.0 = new_tape.into_boxed_slice();
+        grown
+    }
+
+    fn dump(&self, out: &mut dyn Write) -> io::Result<()> {
+        dump_cells(&self.0, 0, out)
+    }
+}
+
+const PAGE_SIZE: usize = 4096;
+
+/// A tape that only allocates pages once a cell in them is written.
+/// Untouched cells read as zero, so huge tapes with scattered accesses stay cheap.
+pub struct SparseTape {
+    size: usize,
+    pages: HashMap<usize, Box<[u8; PAGE_SIZE]>>,
+}
+
+impl Tape for SparseTape {
+    fn with_size(size: usize) -> Self {
+        SparseTape {
+            size,
+            pages: HashMap::new(),
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn get(&self, index: usize) -> u8 {
+        debug_assert!(index < self.size);
+        self.pages
+            .get(&(index / PAGE_SIZE))
+            .map_or(0, |page| page[index % PAGE_SIZE])
+    }
+
+    fn set(&mut self, index: usize, value: u8) {
+        debug_assert!(index < self.size);
+        let page = self
+            .pages
+            .entry(index / PAGE_SIZE)
+            .or_insert_with(|| Box::new([0; PAGE_SIZE]));
+        page[index % PAGE_SIZE] = value;
+    }
+
+    /// Grows by whole pages so that existing pages only need to be renumbered.
+    fn grow_front(&mut self, missing: usize) -> usize {
+        let grown = missing.max(self.size).max(1).next_multiple_of(PAGE_SIZE);
+        let shift = grown / PAGE_SIZE;
+        self.pages = self
+            .pages
+            .drain()
+            .map(|(page, cells)| (page + shift, cells))
+            .collect();
+        self.size += grown;
+        grown
+    }
+
+    fn dump(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut pages = self.pages.iter().collect::<Vec<_>>();
+        pages.sort_unstable_by_key(|(page, _)| **page);
+        for (page, cells) in pages {
+            dump_cells(&cells[..], page * PAGE_SIZE, out)?;
+        }
+        Ok(())
+    }
+}
+
+const DUMP_ROW: usize = 16;
+
+/// Writes `cells` as hex rows of 16 cells, prefixed by the index of their first cell.
+/// Rows that only contain zeros are skipped.
+pub fn dump_cells(cells: &[u8], base: usize, out: &mut dyn Write) -> io::Result<()> {
+    for (row, chunk) in cells.chunks(DUMP_ROW).enumerate() {
+        if chunk.iter().all(|&cell| cell == 0) {
+            continue;
+        }
+        write!(out, "{:012x}:", base + row * DUMP_ROW)?;
+        for cell in chunk {
+            write!(out, " {:02x}", cell)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparse_pages_are_allocated_on_first_write() {
+        let mut tape = SparseTape::with_size(1 << 40);
+        assert_eq!(tape.get(0), 0);
+        assert_eq!(tape.get((1 << 40) - 1), 0);
+        assert!(tape.pages.is_empty());
+
+        tape.set(5 * PAGE_SIZE + 3, 7);
+        tape.set(5 * PAGE_SIZE + 4, 8);
+        assert_eq!(tape.pages.len(), 1);
+        assert_eq!(tape.get(5 * PAGE_SIZE + 3), 7);
+        assert_eq!(tape.get(5 * PAGE_SIZE + 2), 0);
+        assert_eq!(tape.get(6 * PAGE_SIZE + 3), 0);
+        assert_eq!(tape.pages.len(), 1);
+    }
+
+    #[test]
+    fn sparse_growth_keeps_cells() {
+        let mut tape = SparseTape::with_size(10);
+        tape.set(9, 1);
+        let grown = tape.grow_front(3);
+        assert_eq!(grown, PAGE_SIZE);
+        assert_eq!(tape.size(), PAGE_SIZE + 10);
+        assert_eq!(tape.get(grown + 9), 1);
+        assert_eq!(tape.get(9), 0);
+    }
+
+    #[test]
+    fn dump_skips_zero_rows() {
+        let mut cells = [0u8; 48];
+        cells[1] = 0xab;
+        cells[47] = 1;
+        let mut out = Vec::new();
+        dump_cells(&cells, 0x100, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "000000000100: 00 ab 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n\
+             000000000120: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 01\n"
+        );
+    }
+
+    #[test]
+    fn sparse_dump_matches_dense() {
+        let mut sparse = SparseTape::with_size(4 * PAGE_SIZE);
+        let mut dense = DenseTape::with_size(4 * PAGE_SIZE);
+        for (index, value) in [(3 * PAGE_SIZE + 17, 2), (5, 9), (PAGE_SIZE - 1, 255)] {
+            sparse.set(index, value);
+            dense.set(index, value);
+        }
+        let (mut sparse_out, mut dense_out) = (Vec::new(), Vec::new());
+        sparse.dump(&mut sparse_out).unwrap();
+        dense.dump(&mut dense_out).unwrap();
+        assert_eq!(sparse_out.iter().filter(|&&byte| byte == b'\n').count(), 3);
+        assert_eq!(sparse_out, dense_out);
+    }
+}
@@ -0,0 +1,192 @@
+use super::{
+    native::{codegen::CodeGeneration, executor::NativeExecutor, state::State},
+    tape::{TapeBounds, TapeConfig},
+    ExecutionLimits, RuntimeResultCode,
+};
+use crate::syntax::{Instruction, Term};
+use std::collections::HashMap;
+
+/// Starts every program in an interpreter and moves hot loops to native code.
+/// Both work on the same `State`, so the tape, the pointer and the I/O buffers carry over.
+/// Like native code, loop iterations count as steps.
+pub struct TieredExecutor<'a> {
+    pub state: State<'a>,
+    tape: TapeConfig,
+    limits: ExecutionLimits,
+    /// Entries plus iterations after which a loop is compiled.
+    threshold: u64,
+    /// Entries plus iterations of every loop so far, keyed by the address of its node.
+    heat: HashMap<*const Instruction, u64>,
+    compiled: HashMap<*const Instruction, NativeExecutor>,
+}
+
+impl<'a> TieredExecutor<'a> {
+    /// `tape` and `limits` must be the ones `state` was created with.
+    /// Suspending is not supported, so `limits.slice` is ignored.
+    pub fn new(
+        state: State<'a>,
+        tape: TapeConfig,
+        limits: ExecutionLimits,
+        threshold: u64,
+    ) -> Self {
+        Self {
+            state,
+            tape,
+            limits: ExecutionLimits {
+                slice: None,
+                ..limits
+            },
+            threshold,
+            heat: HashMap::new(),
+            compiled: HashMap::new(),
+        }
+    }
+
+    /// The number of loops that were compiled to native code.
+    pub fn compiled_loops(&self) -> usize {
+        self.compiled.len()
+    }
+
+    pub fn run(&mut self, nodes: &[Instruction]) -> RuntimeResultCode {
+        for node in nodes {
+            let pointer = self.state.pointer;
+            match node {
+                Instruction::Add { value } => {
+                    let cell = &mut self.state.tape[pointer];
+                    *cell = cell.wrapping_add_signed(value.0);
+                }
+                Instruction::Set { value } => self.state.tape[pointer] = *value,
+                Instruction::SetRange { offset, values } => {
+                    let start = pointer.wrapping_add_signed(*offset as isize);
+                    self.state.tape[start..start + values.len()].copy_from_slice(values);
+                }
+                Instruction::AddVector { offset, values } => {
+                    let start = pointer.wrapping_add_signed(*offset as isize);
+                    let cells = &mut self.state.tape[start..start + values.len()];
+                    for (cell, value) in cells.iter_mut().zip(values) {
+                        *cell = cell.wrapping_add_signed(*value);
+                    }
+                }
+                Instruction::MulAdd { offset, terms } => {
+                    let tape = &mut self.state.tape;
+                    let sum = Term::sum(terms, |factor| {
+                        tape[pointer.wrapping_add_signed(factor as isize)]
+                    });
+                    let cell = &mut tape[pointer.wrapping_add_signed(*offset as isize)];
+                    *cell = cell.wrapping_add(sum);
+                }
+                Instruction::Move { value } | Instruction::MoveUnchecked { value } => {
+                    if !self.move_pointer(*value) {
+                        return RuntimeResultCode::CellOutOfBounds;
+                    }
+                }
+                Instruction::Write => {
+                    let result = self.state.write_cell(pointer);
+                    if result != RuntimeResultCode::Ok {
+                        return result;
+                    }
+                }
+                Instruction::WriteBytes { bytes } => {
+                    let result = self.state.write_slice(bytes);
+                    if result != RuntimeResultCode::Ok {
+                        return result;
+                    }
+                }
+                Instruction::Read => {
+                    let result = self.state.read_cell(pointer);
+                    if result != RuntimeResultCode::Ok {
+                        return result;
+                    }
+                }
+                Instruction::Loop { nodes: body } => {
+                    let result = self.run_loop(node, body);
+                    if result != RuntimeResultCode::Ok {
+                        return result;
+                    }
+                }
+                Instruction::Idiom { idiom, nodes: body } => {
+                    let result = match self.state.apply_idiom(*idiom, pointer) {
+                        Some(result) => result,
+                        None => self.run(body),
+                    };
+                    if result != RuntimeResultCode::Ok {
+                        return result;
+                    }
+                }
+                Instruction::If { nodes: body } => {
+                    if self.state.tape[pointer] != 0 {
+                        let result = self.run(body);
+                        if result != RuntimeResultCode::Ok {
+                            return result;
+                        }
+                    }
+                }
+            }
+        }
+        RuntimeResultCode::Ok
+    }
+
+    fn run_loop(&mut self, node: &Instruction, body: &[Instruction]) -> RuntimeResultCode {
+        let key = node as *const Instruction;
+        let mut entered = false;
+        while self.state.tape[self.state.pointer] != 0 {
+            // Every iteration after the first costs a step, just like a back-edge in native code.
+            if entered {
+                if self.state.fuel == 0 {
+                    return RuntimeResultCode::OutOfFuel;
+                }
+                self.state.fuel -= 1;
+                if self.limits.polls_interrupt() {
+                    if let Some(reason) = self.state.interrupt.get() {
+                        return reason;
+                    }
+                }
+            }
+            entered = true;
+
+            // Native code can take over at the start of any iteration, as it checks the cell first.
+            if self.compile_if_hot(key, node) {
+                return self.compiled[&key].run(&mut self.state);
+            }
+            let result = self.run(body);
+            if result != RuntimeResultCode::Ok {
+                return result;
+            }
+        }
+        RuntimeResultCode::Ok
+    }
+
+    /// Counts an entry or iteration of the loop `node`. Returns whether it is compiled.
+    fn compile_if_hot(&mut self, key: *const Instruction, node: &Instruction) -> bool {
+        if self.compiled.contains_key(&key) {
+            return true;
+        }
+        let heat = self.heat.entry(key).or_insert(0);
+        *heat += 1;
+        if *heat < self.threshold {
+            return false;
+        }
+        let codegen = CodeGeneration::x86_x64(self.tape, self.limits);
+        let executor = codegen.generate(std::slice::from_ref(node));
+        self.compiled.insert(key, executor);
+        true
+    }
+
+    /// Moves the pointer. Returns false if it left the tape.
+    fn move_pointer(&mut self, value: i32) -> bool {
+        let size = self.state.tape.len();
+        let target = self.state.pointer as isize + value as isize;
+        if target >= 0 && (target as usize) < size {
+            self.state.pointer = target as usize;
+        } else if self.tape.bounds == TapeBounds::Wrap {
+            self.state.pointer = target.rem_euclid(size as isize) as usize;
+        } else if target < 0 && self.tape.bidirectional {
+            let missing = target.unsigned_abs();
+            let grown = self.state.tape.grow_front(missing);
+            self.state.pointer = grown - missing;
+        } else {
+            return false;
+        }
+        true
+    }
+}
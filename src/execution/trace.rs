@@ -0,0 +1,464 @@
+use super::{
+    native::{executor::NativeExecutor, state::State, trace},
+    tape::{TapeBounds, TapeConfig},
+    vm::{Op, Program},
+    RuntimeResultCode,
+};
+use crate::syntax::Term;
+use std::{collections::HashMap, fmt};
+
+/// Recordings longer than this are given up on.
+const MAX_TRACE_LENGTH: usize = 4096;
+/// Side exits of a loop that are recorded at most, as every branch recompiles the loop.
+const MAX_BRANCHES: usize = 16;
+
+/// An instruction of a trace. Exits are the bytecode indices the `TracingVm` continues at
+/// when execution leaves the recorded path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceOp {
+    Add(i8),
+    Set(u8),
+    /// Leaves at `exit` instead if the pointer would leave the tape, so that the VM handles the move.
+    Move {
+        value: i32,
+        exit: usize,
+    },
+    Write,
+    Read,
+    /// Leaves at `exit` unless the current cell is zero, or not zero if `zero` is false.
+    Guard {
+        zero: bool,
+        exit: usize,
+    },
+    /// Runs an inner loop through its tree, the index into `TracingVm::trees`.
+    Loop {
+        tree: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEnd {
+    /// The trace reached the end of the loop body. The loop repeats while the current cell is not zero,
+    /// then leaves at `exit`.
+    Loop { exit: usize },
+    /// The trace ran into a branch of its tree and continues there.
+    Exit(usize),
+}
+
+/// A path through the body of a loop, recorded while the `TracingVm` ran it.
+/// Traces make no assumptions about the tape, so they can run whenever the VM is at `start`.
+pub struct Trace {
+    /// The bytecode index of the first instruction.
+    pub start: usize,
+    pub ops: Vec<TraceOp>,
+    pub end: TraceEnd,
+}
+
+impl Trace {
+    /// Specializes the trace for the cell values it sets itself. Adds to known cells become sets,
+    /// and sets and guards that can not change anything are dropped.
+    fn specialize(&mut self) {
+        // Known cell values by their distance from the pointer at the start of the trace
+        let mut known = HashMap::new();
+        let mut offset = 0i64;
+        self.ops.retain_mut(|op| {
+            match *op {
+                TraceOp::Add(value) => {
+                    if let Some(cell) = known.get_mut(&offset) {
+                        *cell = u8::wrapping_add_signed(*cell, value);
+                        *op = TraceOp::Set(*cell);
+                    }
+                }
+                TraceOp::Set(value) => return known.insert(offset, value) != Some(value),
+                // A move that leaves the tape also leaves the trace, so offsets never wrap.
+                TraceOp::Move { value, .. } => offset += value as i64,
+                TraceOp::Write => {}
+                TraceOp::Read => {
+                    known.remove(&offset);
+                }
+                // The recording passed every guard, so a guard on a known cell always passes.
+                TraceOp::Guard { zero, .. } => {
+                    if known.contains_key(&offset) {
+                        return false;
+                    }
+                    if zero {
+                        known.insert(offset, 0);
+                    }
+                }
+                // The inner loop may change any cell and move anywhere, but it ends on a zero.
+                TraceOp::Loop { .. } => {
+                    known.clear();
+                    offset = 0;
+                    known.insert(offset, 0);
+                }
+            }
+            true
+        });
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Trace at {}:", self.start)?;
+        for op in &self.ops {
+            writeln!(f, "    {:?}", op)?;
+        }
+        writeln!(f, "    {:?}", self.end)
+    }
+}
+
+/// The traces of a loop: the body as recorded first, and branches recorded from its hot side exits.
+/// They are compiled together, so that guards jump straight to the branches.
+pub struct TraceTree {
+    pub root: Trace,
+    pub branches: Vec<Trace>,
+    executor: NativeExecutor,
+    /// Side exits taken so far, by the bytecode index they left at.
+    /// Counting stops at the threshold, so every side exit is recorded at most once.
+    side_exits: HashMap<usize, u64>,
+}
+
+impl TraceTree {
+    /// Where the loop ends, which is the only exit that is not a side exit.
+    fn end(&self) -> Option<usize> {
+        match self.root.end {
+            TraceEnd::Loop { exit } => Some(exit),
+            TraceEnd::Exit(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for TraceTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.root)?;
+        for branch in &self.branches {
+            write!(f, "{}", branch)?;
+        }
+        Ok(())
+    }
+}
+
+struct Recording {
+    trace: Trace,
+    /// The first instruction of the loop body.
+    header: usize,
+    /// The index of the tree the trace is a branch of, if it is one.
+    tree: Option<usize>,
+}
+
+/// Runs unfused bytecode on a `State` and compiles the paths hot loops take to native code.
+/// A loop is recorded from its back-edge once it is hot, and its trace leaves back to the VM
+/// wherever execution takes another path than the recorded one. Hot side exits are recorded
+/// as well and become branches of the loop's `TraceTree`.
+/// Experimental: limits are ignored and the program can not be suspended.
+pub struct TracingVm<'a> {
+    pub state: State<'a>,
+    tape: TapeConfig,
+    /// Taken back-edges or side exits after which they are recorded.
+    threshold: u64,
+    /// Taken back-edges into every loop body so far, by the bytecode index of the body.
+    /// Counting stops at the threshold, so every loop is recorded at most once.
+    heat: Vec<u64>,
+    recording: Option<Recording>,
+    trees: Vec<TraceTree>,
+    /// The index into `trees` of the tree of every loop body, by the bytecode index of the body.
+    entries: Vec<Option<usize>>,
+    side_exits: u64,
+}
+
+impl<'a> TracingVm<'a> {
+    /// `tape` must be the one `state` was created with.
+    pub fn new(state: State<'a>, tape: TapeConfig, threshold: u64) -> Self {
+        Self {
+            state,
+            tape,
+            threshold,
+            heat: Vec::new(),
+            recording: None,
+            trees: Vec::new(),
+            entries: Vec::new(),
+            side_exits: 0,
+        }
+    }
+
+    pub fn trees(&self) -> &[TraceTree] {
+        &self.trees
+    }
+
+    /// The number of times a tree left before the end of its loop.
+    pub fn side_exits(&self) -> u64 {
+        self.side_exits
+    }
+
+    /// Runs `program`, which must be compiled with `Program::unfused`.
+    pub fn run(&mut self, program: &Program) -> RuntimeResultCode {
+        // Trees can also leave at the end of the program.
+        self.heat.resize(program.ops().len() + 1, 0);
+        self.entries.resize(program.ops().len() + 1, None);
+        let mut pc = 0;
+        while let Some(&op) = program.ops().get(pc) {
+            if self.recording.is_some() {
+                self.record(op, pc);
+            }
+            pc += 1;
+            let pointer = self.state.pointer;
+            match op {
+                Op::Add(value) => {
+                    let cell = &mut self.state.tape[pointer];
+                    *cell = cell.wrapping_add_signed(value);
+                    continue;
+                }
+                Op::Set(value) => {
+                    self.state.tape[pointer] = value;
+                    continue;
+                }
+                Op::Move(value) => {
+                    if !self.move_pointer(value) {
+                        return RuntimeResultCode::CellOutOfBounds;
+                    }
+                    continue;
+                }
+                Op::Write => {
+                    let result = self.state.write_cell(pointer);
+                    if result != RuntimeResultCode::Ok {
+                        return result;
+                    }
+                    continue;
+                }
+                Op::Read => {
+                    let result = self.state.read_cell(pointer);
+                    if result != RuntimeResultCode::Ok {
+                        return result;
+                    }
+                    continue;
+                }
+                Op::WriteBytes(bytes) => {
+                    let result = self.state.write_slice(program.bytes(bytes));
+                    if result != RuntimeResultCode::Ok {
+                        return result;
+                    }
+                    continue;
+                }
+                Op::MulAdd(mul_add) => {
+                    let (offset, terms) = program.mul_add(mul_add);
+                    let tape = &mut self.state.tape;
+                    let sum = Term::sum(terms, |factor| {
+                        tape[pointer.wrapping_add_signed(factor as isize)]
+                    });
+                    let cell = &mut tape[pointer.wrapping_add_signed(offset as isize)];
+                    *cell = cell.wrapping_add(sum);
+                    continue;
+                }
+                Op::Idiom(idiom, target) => {
+                    if let Some(result) = self.state.apply_idiom(idiom, pointer) {
+                        if result != RuntimeResultCode::Ok {
+                            return result;
+                        }
+                        pc = target as usize;
+                    }
+                    continue;
+                }
+                Op::JumpIfZero(target) => {
+                    if self.state.tape[pointer] == 0 {
+                        pc = target as usize;
+                        continue;
+                    }
+                }
+                Op::JumpIfNotZero(target) => {
+                    if self.state.tape[pointer] == 0 {
+                        continue;
+                    }
+                    pc = target as usize;
+                    self.count_iteration(pc);
+                }
+                Op::AddMove(..) | Op::MoveAdd(..) => {
+                    unreachable!("Traces are recorded from unfused bytecode")
+                }
+            }
+
+            // Execution just entered the body of a loop at `pc`.
+            let Some(mut index) = self.entries[pc] else {
+                continue;
+            };
+            loop {
+                if let Some(recording) = &mut self.recording {
+                    recording.trace.ops.push(TraceOp::Loop { tree: index });
+                }
+                let tree = &self.trees[index];
+                let result = tree.executor.run(&mut self.state);
+                if result != RuntimeResultCode::Ok {
+                    return result;
+                }
+                pc = self.state.trace_exit;
+                if Some(pc) != tree.end() {
+                    // The recording can only continue after the inner loop ended.
+                    self.abort_recording();
+                    if self.entries[pc].is_none() {
+                        self.side_exits += 1;
+                        self.count_side_exit(index, pc);
+                    }
+                }
+                // The tree left right at the body of another loop, so continue there without the VM.
+                match self.entries[pc] {
+                    Some(next) if next != index => index = next,
+                    _ => break,
+                }
+            }
+        }
+        RuntimeResultCode::Ok
+    }
+
+    /// Counts a taken back-edge into the loop body at `header`, and starts recording it once it is hot.
+    fn count_iteration(&mut self, header: usize) {
+        let heat = &mut self.heat[header];
+        if *heat >= self.threshold || self.recording.is_some() {
+            return;
+        }
+        *heat += 1;
+        if *heat == self.threshold && self.entries[header].is_none() {
+            self.recording = Some(Recording {
+                trace: Trace {
+                    start: header,
+                    ops: Vec::new(),
+                    end: TraceEnd::Exit(header),
+                },
+                header,
+                tree: None,
+            });
+        }
+    }
+
+    /// Counts a side exit of the tree at `index` to `pc`, and starts recording a branch there once it is hot.
+    fn count_side_exit(&mut self, index: usize, pc: usize) {
+        let tree = &mut self.trees[index];
+        let heat = tree.side_exits.entry(pc).or_insert(0);
+        if *heat >= self.threshold || self.recording.is_some() {
+            return;
+        }
+        *heat += 1;
+        if *heat == self.threshold && tree.branches.len() < MAX_BRANCHES {
+            self.recording = Some(Recording {
+                trace: Trace {
+                    start: pc,
+                    ops: Vec::new(),
+                    end: TraceEnd::Exit(pc),
+                },
+                header: tree.root.start,
+                tree: Some(index),
+            });
+        }
+    }
+
+    /// Appends `op` at `pc` to the recording, before it runs. Jumps become guards for the way they are about to go.
+    fn record(&mut self, op: Op, pc: usize) {
+        let Some(recording) = &mut self.recording else {
+            return;
+        };
+        // A branch that runs into another branch of its tree continues there.
+        if let Some(tree) = recording.tree {
+            let joins = self.trees[tree]
+                .branches
+                .iter()
+                .any(|branch| branch.start == pc);
+            if joins && !recording.trace.ops.is_empty() {
+                let mut recording = self.recording.take().unwrap();
+                recording.trace.end = TraceEnd::Exit(pc);
+                self.compile(recording);
+                return;
+            }
+        }
+        let is_zero = self.state.tape[self.state.pointer] == 0;
+        let op = match op {
+            Op::Add(value) => TraceOp::Add(value),
+            Op::Set(value) => TraceOp::Set(value),
+            Op::Move(value) => TraceOp::Move { value, exit: pc },
+            Op::Write => TraceOp::Write,
+            Op::Read => TraceOp::Read,
+            // Constant output is only generated outside of loops, so traces hardly ever contain it.
+            // Traces can not express closed form loops or idioms, so loops containing them stay in the VM.
+            Op::WriteBytes(_) | Op::MulAdd(_) | Op::Idiom(..) => {
+                self.abort_recording();
+                return;
+            }
+            // The back-edge of the recorded loop closes the trace.
+            Op::JumpIfNotZero(target) if target as usize == recording.header => {
+                let mut recording = self.recording.take().unwrap();
+                recording.trace.end = TraceEnd::Loop { exit: pc + 1 };
+                self.compile(recording);
+                return;
+            }
+            Op::JumpIfZero(target) | Op::JumpIfNotZero(target) => {
+                let (taken, not_taken) = (target as usize, pc + 1);
+                let jumps = matches!(op, Op::JumpIfZero(_)) == is_zero;
+                TraceOp::Guard {
+                    zero: is_zero,
+                    exit: if jumps { not_taken } else { taken },
+                }
+            }
+            Op::AddMove(..) | Op::MoveAdd(..) => {
+                unreachable!("Traces are recorded from unfused bytecode")
+            }
+        };
+        recording.trace.ops.push(op);
+        if recording.trace.ops.len() > MAX_TRACE_LENGTH {
+            self.recording = None;
+        }
+    }
+
+    /// Gives up on the recording for now. It starts over once its loop or side exit is hot again.
+    fn abort_recording(&mut self) {
+        match self.recording.take() {
+            Some(Recording {
+                trace,
+                tree: Some(index),
+                ..
+            }) => {
+                self.trees[index].side_exits.insert(trace.start, 0);
+            }
+            Some(Recording { trace, .. }) => self.heat[trace.start] = 0,
+            None => {}
+        }
+    }
+
+    /// Compiles a recording, either as a new tree or by recompiling the tree it is a branch of.
+    fn compile(&mut self, recording: Recording) {
+        let mut trace = recording.trace;
+        trace.specialize();
+        match recording.tree {
+            Some(index) => {
+                self.trees[index].branches.push(trace);
+                let tree = &self.trees[index];
+                let executor = trace::generate(&tree.root, &tree.branches, &self.trees, self.tape);
+                self.trees[index].executor = executor;
+            }
+            None => {
+                let executor = trace::generate(&trace, &[], &self.trees, self.tape);
+                self.entries[trace.start] = Some(self.trees.len());
+                self.trees.push(TraceTree {
+                    root: trace,
+                    branches: Vec::new(),
+                    executor,
+                    side_exits: HashMap::new(),
+                });
+            }
+        }
+    }
+
+    /// Moves the pointer. Returns false if it left the tape.
+    fn move_pointer(&mut self, value: i32) -> bool {
+        let size = self.state.tape.len();
+        let target = self.state.pointer as isize + value as isize;
+        if target >= 0 && (target as usize) < size {
+            self.state.pointer = target as usize;
+        } else if self.tape.bounds == TapeBounds::Wrap {
+            self.state.pointer = target.rem_euclid(size as isize) as usize;
+        } else if target < 0 && self.tape.bidirectional {
+            let missing = target.unsigned_abs();
+            let grown = self.state.tape.grow_front(missing);
+            self.state.pointer = grown - missing;
+        } else {
+            return false;
+        }
+        true
+    }
+}
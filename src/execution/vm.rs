@@ -0,0 +1,402 @@
+use super::{
+    interrupt::Interrupt,
+    tape::{DenseTape, Tape, TapeBounds, TapeConfig},
+    ExecutionLimits, RuntimeResultCode,
+};
+use crate::syntax::{Idiom, Instruction, Term};
+use std::{
+    fmt,
+    io::{ErrorKind, Read, Write},
+};
+
+/// A single bytecode instruction. Jump targets are indices into the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add(i8),
+    Move(i32),
+    Set(u8),
+    Write,
+    Read,
+    /// Writes `Program::bytes` at the index.
+    WriteBytes(u32),
+    /// Runs `Program::mul_add` at the index.
+    MulAdd(u32),
+    /// Applies the idiom and jumps to the target, past its snippet. Runs the snippet if it does not apply.
+    Idiom(Idiom, u32),
+    /// Start of a loop or an `If`: jumps past its end if the current cell is zero.
+    JumpIfZero(u32),
+    /// End of a loop: jumps to the start of its body if the current cell is not zero.
+    JumpIfNotZero(u32),
+    // Superinstructions for common pairs
+    AddMove(i8, i32),
+    MoveAdd(i32, i8),
+}
+
+/// Flat bytecode for the `Vm`, compiled from an instruction tree.
+pub struct Program {
+    ops: Vec<Op>,
+    /// The output of every `Op::WriteBytes`.
+    bytes: Vec<Vec<u8>>,
+    /// The offset and terms of every `Op::MulAdd`.
+    mul_adds: Vec<(i32, Vec<Term>)>,
+}
+
+impl Program {
+    pub fn compile(nodes: &[Instruction]) -> Self {
+        let program = Self::unfused(nodes);
+        Program {
+            ops: fuse_pairs(program.ops),
+            ..program
+        }
+    }
+
+    /// Compiles without superinstructions, so that every instruction does a single thing.
+    pub fn unfused(nodes: &[Instruction]) -> Self {
+        let mut program = Program {
+            ops: Vec::new(),
+            bytes: Vec::new(),
+            mul_adds: Vec::new(),
+        };
+        compile_into(nodes, &mut program);
+        program
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    pub fn bytes(&self, index: u32) -> &[u8] {
+        &self.bytes[index as usize]
+    }
+
+    pub fn mul_add(&self, index: u32) -> (i32, &[Term]) {
+        let (offset, terms) = &self.mul_adds[index as usize];
+        (*offset, terms)
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, op) in self.ops.iter().enumerate() {
+            match op {
+                Op::WriteBytes(bytes) => writeln!(
+                    f,
+                    "{:6}: WriteBytes({:?})",
+                    index,
+                    String::from_utf8_lossy(self.bytes(*bytes))
+                )?,
+                Op::MulAdd(mul_add) => {
+                    let (offset, terms) = self.mul_add(*mul_add);
+                    let terms: Vec<_> = terms.iter().map(|term| term.to_string()).collect();
+                    writeln!(f, "{:6}: MulAdd({}, {})", index, offset, terms.join(" + "))?
+                }
+                op => writeln!(f, "{:6}: {:?}", index, op)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn compile_into(nodes: &[Instruction], program: &mut Program) {
+    for node in nodes {
+        let ops = &mut program.ops;
+        match node {
+            Instruction::Add { value } => ops.push(Op::Add(value.0)),
+            Instruction::Move { value } | Instruction::MoveUnchecked { value } => {
+                ops.push(Op::Move(*value))
+            }
+            Instruction::Set { value } => ops.push(Op::Set(*value)),
+            // Every cell of the range is in the tape, so the moves in between always succeed.
+            Instruction::SetRange { offset, values } => {
+                compile_offsets(*offset, values.iter().map(|value| Op::Set(*value)), ops)
+            }
+            Instruction::AddVector { offset, values } => {
+                compile_offsets(*offset, values.iter().map(|value| Op::Add(*value)), ops)
+            }
+            Instruction::Write => ops.push(Op::Write),
+            Instruction::WriteBytes { bytes } => {
+                ops.push(Op::WriteBytes(program.bytes.len() as u32));
+                program.bytes.push(bytes.clone());
+            }
+            Instruction::MulAdd { offset, terms } => {
+                ops.push(Op::MulAdd(program.mul_adds.len() as u32));
+                program.mul_adds.push((*offset, terms.clone()));
+            }
+            Instruction::Read => ops.push(Op::Read),
+            Instruction::Loop { nodes } => {
+                let start = ops.len();
+                ops.push(Op::JumpIfZero(0));
+                compile_into(nodes, program);
+                let ops = &mut program.ops;
+                let end = ops.len();
+                ops.push(Op::JumpIfNotZero(start as u32 + 1));
+                ops[start] = Op::JumpIfZero(end as u32 + 1);
+            }
+            Instruction::If { nodes } => {
+                let start = ops.len();
+                ops.push(Op::JumpIfZero(0));
+                compile_into(nodes, program);
+                let ops = &mut program.ops;
+                let end = ops.len();
+                ops[start] = Op::JumpIfZero(end as u32);
+            }
+            Instruction::Idiom { idiom, nodes } => {
+                let start = ops.len();
+                ops.push(Op::Idiom(*idiom, 0));
+                compile_into(nodes, program);
+                let ops = &mut program.ops;
+                let end = ops.len();
+                ops[start] = Op::Idiom(*idiom, end as u32);
+            }
+        }
+    }
+}
+
+/// Applies `cell_ops` to consecutive cells from `offset` on and moves the pointer back.
+fn compile_offsets(offset: i32, cell_ops: impl Iterator<Item = Op>, ops: &mut Vec<Op>) {
+    let mut pointer = 0;
+    for (index, op) in cell_ops.enumerate() {
+        if op == Op::Add(0) {
+            continue;
+        }
+        let cell = offset + index as i32;
+        if cell != pointer {
+            ops.push(Op::Move(cell - pointer));
+            pointer = cell;
+        }
+        ops.push(op);
+    }
+    if pointer != 0 {
+        ops.push(Op::Move(-pointer));
+    }
+}
+
+/// Replaces common pairs of instructions with superinstructions.
+/// Pairs never span a jump target, so fusing them keeps the targets intact apart from renumbering.
+fn fuse_pairs(ops: Vec<Op>) -> Vec<Op> {
+    let mut is_target = vec![false; ops.len() + 1];
+    for op in &ops {
+        if let Op::JumpIfZero(target) | Op::JumpIfNotZero(target) | Op::Idiom(_, target) = op {
+            is_target[*target as usize] = true;
+        }
+    }
+
+    let mut fused = Vec::with_capacity(ops.len());
+    // The new index of every old index
+    let mut new_index = vec![0; ops.len() + 1];
+    let mut index = 0;
+    while index < ops.len() {
+        new_index[index] = fused.len() as u32;
+        let pair = ops.get(index + 1).filter(|_| !is_target[index + 1]);
+        let op = match (ops[index], pair) {
+            (Op::Add(value), Some(Op::Move(offset))) => Some(Op::AddMove(value, *offset)),
+            (Op::Move(offset), Some(Op::Add(value))) => Some(Op::MoveAdd(offset, *value)),
+            _ => None,
+        };
+        match op {
+            Some(op) => {
+                fused.push(op);
+                index += 2;
+            }
+            None => {
+                fused.push(ops[index]);
+                index += 1;
+            }
+        }
+    }
+    new_index[ops.len()] = fused.len() as u32;
+
+    for op in &mut fused {
+        if let Op::JumpIfZero(target) | Op::JumpIfNotZero(target) | Op::Idiom(_, target) = op {
+            *target = new_index[*target as usize];
+        }
+    }
+    fused
+}
+
+/// Runs `Program`s in a flat dispatch loop.
+/// Like native code, the `Vm` counts loop iterations as steps.
+pub struct Vm<'a, T: Tape = DenseTape> {
+    input: Box<dyn Read + 'a>,
+    output: Box<dyn Write + 'a>,
+    tape: T,
+    pointer: usize,
+    bounds: TapeBounds,
+    bidirectional: bool,
+    limits: ExecutionLimits,
+    /// Steps left before execution stops with `OutOfFuel`.
+    fuel: u64,
+    /// Index of the next instruction. Kept across suspensions, so that `run` resumes there.
+    pc: usize,
+    interrupt: Interrupt,
+}
+
+impl<'a, T: Tape> Vm<'a, T> {
+    pub fn new(
+        input: Box<dyn Read + 'a>,
+        output: Box<dyn Write + 'a>,
+        tape: TapeConfig,
+        limits: ExecutionLimits,
+    ) -> Self {
+        Vm {
+            input,
+            output,
+            tape: T::with_size(tape.size),
+            pointer: tape.start_offset(),
+            bounds: tape.bounds,
+            bidirectional: tape.bidirectional,
+            limits,
+            fuel: limits.fuel(),
+            pc: 0,
+            interrupt: Interrupt::new(),
+        }
+    }
+
+    /// Runs `program` from the current instruction until it finishes or suspends.
+    pub fn run(&mut self, program: &Program) -> RuntimeResultCode {
+        let ops = program.ops();
+        let polls_interrupt = self.limits.polls_interrupt();
+        let mut slice = self.limits.slice.unwrap_or(u64::MAX);
+        while let Some(op) = ops.get(self.pc) {
+            self.pc += 1;
+            match *op {
+                Op::Add(value) => self.add(value),
+                Op::Move(value) => {
+                    if !self.move_pointer(value) {
+                        return RuntimeResultCode::CellOutOfBounds;
+                    }
+                }
+                Op::Set(value) => self.tape.set(self.pointer, value),
+                Op::Write => {
+                    if self
+                        .output
+                        .write_all(&[self.tape.get(self.pointer)])
+                        .is_err()
+                    {
+                        return RuntimeResultCode::IoError;
+                    }
+                }
+                Op::WriteBytes(bytes) => {
+                    if self.output.write_all(program.bytes(bytes)).is_err() {
+                        return RuntimeResultCode::IoError;
+                    }
+                }
+                Op::MulAdd(mul_add) => {
+                    let (offset, terms) = program.mul_add(mul_add);
+                    let sum = Term::sum(terms, |factor| {
+                        self.tape
+                            .get(self.pointer.wrapping_add_signed(factor as isize))
+                    });
+                    let cell = self.pointer.wrapping_add_signed(offset as isize);
+                    self.tape.set(cell, self.tape.get(cell).wrapping_add(sum));
+                }
+                Op::Idiom(idiom, target) => {
+                    let mut output = Vec::new();
+                    if self.tape.apply_idiom(idiom, self.pointer, &mut output) {
+                        if self.output.write_all(&output).is_err() {
+                            return RuntimeResultCode::IoError;
+                        }
+                        self.pc = target as usize;
+                    }
+                }
+                Op::Read => {
+                    let mut buffer = [0];
+                    match self.input.read_exact(&mut buffer) {
+                        Ok(_) => self.tape.set(self.pointer, buffer[0]),
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                            // Resuming repeats the read.
+                            self.pc -= 1;
+                            return RuntimeResultCode::InputPending;
+                        }
+                        Err(_) => return RuntimeResultCode::IoError,
+                    }
+                }
+                Op::JumpIfZero(target) => {
+                    if self.tape.get(self.pointer) == 0 {
+                        self.pc = target as usize;
+                    }
+                }
+                Op::JumpIfNotZero(target) => {
+                    if self.tape.get(self.pointer) != 0 {
+                        self.pc = target as usize;
+                        if self.fuel == 0 {
+                            return RuntimeResultCode::OutOfFuel;
+                        }
+                        self.fuel -= 1;
+                        if polls_interrupt {
+                            if let Some(reason) = self.interrupt.get() {
+                                return reason;
+                            }
+                        }
+                        // The iteration was already paid for, so resuming starts right in the loop body.
+                        if slice == 0 {
+                            return RuntimeResultCode::Yielded;
+                        }
+                        slice -= 1;
+                    }
+                }
+                Op::AddMove(value, offset) => {
+                    self.add(value);
+                    if !self.move_pointer(offset) {
+                        return RuntimeResultCode::CellOutOfBounds;
+                    }
+                }
+                Op::MoveAdd(offset, value) => {
+                    if !self.move_pointer(offset) {
+                        return RuntimeResultCode::CellOutOfBounds;
+                    }
+                    self.add(value);
+                }
+            }
+        }
+        RuntimeResultCode::Ok
+    }
+
+    #[inline(always)]
+    fn add(&mut self, value: i8) {
+        let cell = self.tape.get(self.pointer);
+        self.tape.set(self.pointer, cell.wrapping_add_signed(value));
+    }
+
+    /// Moves the pointer. Returns false if it left the tape.
+    #[inline(always)]
+    fn move_pointer(&mut self, value: i32) -> bool {
+        let target = self.pointer as isize + value as isize;
+        if target >= 0 && (target as usize) < self.tape.size() {
+            self.pointer = target as usize;
+        } else if self.bounds == TapeBounds::Wrap {
+            self.pointer = target.rem_euclid(self.tape.size() as isize) as usize;
+        } else if target < 0 && self.bidirectional {
+            let missing = target.unsigned_abs();
+            let grown = self.tape.grow_front(missing);
+            self.pointer = grown - missing;
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// The number of loop iterations executed so far.
+    pub fn steps(&self) -> u64 {
+        self.limits.fuel() - self.fuel
+    }
+
+    /// The cell index of the pointer.
+    pub fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    /// The index of the next instruction.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// A handle to stop the program from another thread.
+    pub fn interrupt(&self) -> Interrupt {
+        self.interrupt.clone()
+    }
+
+    pub fn tape(&self) -> &T {
+        &self.tape
+    }
+}
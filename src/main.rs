@@ -1,15 +1,44 @@
-use clap::{arg, Parser, ValueEnum};
+use clap::{Parser, ValueEnum};
 use execution::{
+    async_io::{block_on, AsyncAdapter, Blocking},
     interpreter::Interpreter,
-    native::{codegen::CodeGeneration, state::State},
+    interrupt::Interrupt,
+    native::{
+        cache::{CacheKey, CodeCache},
+        codegen::CodeGeneration,
+        executor::NativeExecutor,
+        memory::page_size,
+        state::{GuardFault, State},
+    },
+    suspend::{InterpreterRun, NativeRun, Resumable, VmRun},
+    tape::{DenseTape, SparseTape, StartOffset, Tape, TapeBounds, TapeConfig},
+    tiered::TieredExecutor,
+    trace::TracingVm,
+    vm::{Program, Vm},
+    ExecutionLimits, RuntimeResultCode,
+};
+use optimize::{
+    bounds::{tape_footprint, EliminateBoundsChecks},
+    closed_form::SolveCountedLoops,
+    coalesce::CoalesceStores,
+    constants::PropagateConstants,
+    idioms::MatchIdioms,
+    peephole::*,
+    prefix::EvaluatePrefix,
+    OptimizationPass,
 };
-use optimize::{peephole::*, OptimizationPass};
 pub mod execution;
 pub mod optimize;
 pub mod syntax;
 use std::{
-    io::{BufWriter, Write},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
     path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::Duration,
 };
 
 macro_rules! time {
@@ -38,73 +67,678 @@ struct Cli {
     optimize: bool,
 
     #[arg(short, long)]
-    #[clap(help = "Dump the binary to a file. Only works in compiled mode")]
+    #[clap(
+        help = "Dump the binary, the bytecode in VM mode or the traces in trace mode, to a file"
+    )]
     dump_binary: bool,
 
     #[arg(short, long, default_value = "30000")]
     #[clap(help = "The number of cells in the tape")]
     tape_size: usize,
+
+    #[arg(long)]
+    #[clap(
+        help = "Choose the tape size from the cells the program can reach, if they are statically known"
+    )]
+    auto_tape_size: bool,
+
+    #[arg(short, long, default_value = "0")]
+    #[clap(help = "The cell the pointer starts at. Either a cell index or `middle`")]
+    start_offset: StartOffset,
+
+    #[arg(long, value_enum, default_value_t = TapeBounds::Error)]
+    #[clap(help = "What happens when the pointer moves past either end of the tape")]
+    bounds: TapeBounds,
+
+    #[arg(short, long, conflicts_with = "bounds")]
+    #[clap(help = "Grow the tape when the pointer moves left of the first cell")]
+    bidirectional: bool,
+
+    #[arg(long)]
+    #[clap(
+        help = "Catch out of bounds accesses with guard pages instead of checking every move. The pointer may leave the tape as long as no cell is accessed there. The tape size must be a multiple of the page size; --auto-tape-size rounds up to one. A fault reports the cell that was accessed and the command whose code accessed it, with --optimize the instruction of the optimized program instead. Only works in compiled mode on Linux"
+    )]
+    guard_pages: bool,
+
+    #[arg(long)]
+    #[clap(
+        help = "Stop after this many steps. Interpreted mode counts instructions, compiled mode counts loop iterations"
+    )]
+    max_steps: Option<u64>,
+
+    #[arg(long)]
+    #[clap(
+        help = "Suspend every this many steps and resume through the async adapter. Counted like --max-steps"
+    )]
+    slice: Option<u64>,
+
+    #[arg(long, value_parser = parse_seconds)]
+    #[clap(help = "Stop after this many seconds")]
+    timeout: Option<Duration>,
+
+    #[arg(long)]
+    #[clap(help = "Stop the program on Ctrl-C and report where it was")]
+    interruptible: bool,
+
+    #[arg(long)]
+    #[clap(help = "Allocate tape pages on first touch. Only works in interpreted and VM mode")]
+    sparse: bool,
+
+    #[arg(long)]
+    #[clap(help = "Dump all non-zero tape rows to a file after execution")]
+    dump_tape: bool,
+
+    #[arg(long, default_value = "1000")]
+    #[clap(
+        help = "In auto mode, compile a loop once it was entered or iterated this many times. In trace mode, record a loop or a side exit once it was taken this many times"
+    )]
+    hot_threshold: u64,
+
+    #[arg(long, conflicts_with_all = ["slice", "inputs"])]
+    #[clap(
+        help = "Read stdin and write stdout with plain system calls on their file descriptors, bypassing Rust's stdio. Output is written as soon as the program produces it. Only works in compiled, auto and trace mode on Unix"
+    )]
+    raw_io: bool,
+
+    #[arg(long, value_enum, default_value_t = Backend::X86_64)]
+    #[clap(help = "The code generator for compiled mode")]
+    backend: Backend,
+
+    #[arg(long)]
+    #[clap(
+        help = "Keep compiled code in this directory and reuse it when the program and options match. Only works in compiled mode"
+    )]
+    cache: Option<PathBuf>,
+
+    #[arg(long, num_args = 1.., conflicts_with_all = ["slice", "interruptible", "dump_tape"])]
+    #[clap(
+        help = "Run the program once per input file, in parallel, and write each output next to its input with an `.out` extension. Only works in compiled mode"
+    )]
+    inputs: Vec<PathBuf>,
+
+    #[arg(long, requires = "optimize", conflicts_with_all = ["raw_io", "inputs", "cache", "slice", "max_steps"])]
+    #[clap(
+        help = "Specialize the program to the input in this file. The program then reads whatever part of the file was not consumed at compile time"
+    )]
+    assume_input: Option<PathBuf>,
 }
 
+/// The input left over from `--assume-input`, read in place of stdin.
+static ASSUMED_INPUT: OnceLock<Vec<u8>> = OnceLock::new();
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 enum Mode {
     Jit,
     Interpret,
+    Vm,
+    Auto,
+    /// Experimental: compile the paths hot loops take
+    Trace,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Backend {
+    /// Assemble x64 machine code directly
+    #[value(name = "x86-64")]
+    X86_64,
+    /// Lower to Cranelift IR and let Cranelift generate the code
+    Cranelift,
 }
 
 fn main() {
     let cli = Cli::parse();
 
     let s = std::fs::read_to_string(&cli.path).unwrap();
-    let mut nodes = time!("Parse", syntax::parse(&s).unwrap());
+    let mut nodes = match time!("Parse", syntax::parse(&s)) {
+        Ok(nodes) => nodes,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let limits = ExecutionLimits {
+        max_steps: cli.max_steps,
+        timeout: cli.timeout,
+        interruptible: cli.interruptible,
+        slice: cli.slice,
+    };
 
-    if cli.optimize {
+    // Cached code is looked up before optimizing, so a hit skips the passes as well.
+    let cache = match cli.mode {
+        Mode::Jit => cli.cache.as_ref().map(CodeCache::new),
+        _ => None,
+    };
+    let key = CacheKey::new(
+        &s,
+        &pipeline(&cli),
+        match cli.backend {
+            Backend::X86_64 => "x86-64",
+            Backend::Cranelift => "cranelift",
+        },
+        TapeConfig {
+            start: cli.start_offset,
+            bounds: cli.bounds,
+            bidirectional: cli.bidirectional,
+            guard_pages: cli.guard_pages,
+            ..TapeConfig::new(cli.tape_size)
+        },
+        limits,
+    );
+    let cached = cache
+        .as_ref()
+        .and_then(|cache| time!("LoadCachedCode", cache.load(&key)));
+    let optimize = cli.optimize && cached.is_none();
+
+    if optimize {
         nodes = time!(
             "OptimizeCombineIncrements",
             CombineIncrements.optimize(nodes)
         );
         nodes = time!("OptimizeReplaceSet", ReplaceSet.optimize(nodes));
         nodes = time!("OptimizeCombineSets", CombineSets.optimize(nodes));
+        // The loops of an idiom would not count towards the step limit.
+        if cli.max_steps.is_none() {
+            nodes = time!(
+                "OptimizeMatchIdioms",
+                MatchIdioms::default().optimize(nodes)
+            );
+        }
+    }
+
+    let mut tape_size = cli.tape_size;
+    if let Some((_, size)) = &cached {
+        tape_size = *size;
+    } else if cli.auto_tape_size {
+        let footprint = time!("AnalyzeFootprint", tape_footprint(&nodes));
+        match footprint.tape_size(cli.start_offset) {
+            Some(size) => {
+                // The program never reaches the extra cells, so rounding up is safe.
+                tape_size = if cli.guard_pages {
+                    size.next_multiple_of(page_size())
+                } else {
+                    size
+                };
+                println!("Tape size: {} cells", tape_size);
+            }
+            None => println!("Tape footprint is unbounded, using {} cells", tape_size),
+        }
+    }
+
+    let tape = TapeConfig {
+        start: cli.start_offset,
+        bounds: cli.bounds,
+        bidirectional: cli.bidirectional,
+        guard_pages: cli.guard_pages,
+        ..TapeConfig::new(tape_size)
+    };
+    if tape.guard_pages && std::env::consts::OS != "linux" {
+        eprintln!("Error: guard pages are only supported on Linux");
+        std::process::exit(1);
+    }
+    if tape.guard_pages && cli.mode != Mode::Jit {
+        eprintln!("Error: --guard-pages only works in compiled mode");
+        std::process::exit(1);
+    }
+    if tape.guard_pages && !tape.size.is_multiple_of(page_size()) {
+        eprintln!(
+            "Error: --guard-pages needs a tape size that is a multiple of the page size ({} bytes), e.g. {}",
+            page_size(),
+            tape.size.next_multiple_of(page_size())
+        );
+        std::process::exit(1);
+    }
+    if !cli.inputs.is_empty() && cli.mode != Mode::Jit {
+        eprintln!("Error: --inputs only works in compiled mode");
+        std::process::exit(1);
+    }
+    if cli.raw_io && (!matches!(cli.mode, Mode::Jit | Mode::Auto | Mode::Trace) || !cfg!(unix)) {
+        eprintln!("Error: --raw-io only works in compiled, auto and trace mode on Unix");
+        std::process::exit(1);
+    }
+    if cli.backend == Backend::Cranelift && cli.mode != Mode::Jit {
+        eprintln!("Error: --backend only works in compiled mode");
+        std::process::exit(1);
+    }
+    if cli.backend == Backend::Cranelift && tape.guard_pages {
+        eprintln!("Error: the Cranelift backend does not support guard pages");
+        std::process::exit(1);
+    }
+    if cli.slice.is_some() && cli.mode == Mode::Auto {
+        eprintln!("Error: --slice does not work in auto mode");
+        std::process::exit(1);
+    }
+    if cli.mode == Mode::Trace
+        && (cli.max_steps.is_some()
+            || cli.slice.is_some()
+            || cli.timeout.is_some()
+            || cli.interruptible)
+    {
+        eprintln!(
+            "Error: trace mode does not support --max-steps, --slice, --timeout or --interruptible"
+        );
+        std::process::exit(1);
+    }
+    if tape.start_offset() >= tape.size {
+        eprintln!(
+            "Error: start offset {} is outside of the tape",
+            tape.start_offset()
+        );
+        std::process::exit(1);
+    }
+
+    if optimize && limits.max_steps.is_none() {
+        // Steps spent at compile time would not count towards the limit.
+        let input = match &cli.assume_input {
+            Some(path) => std::fs::read(path).unwrap(),
+            None => Vec::new(),
+        };
+        let (evaluated, consumed) = time!(
+            "OptimizeEvaluatePrefix",
+            EvaluatePrefix {
+                tape,
+                input: &input,
+            }
+            .evaluate(nodes)
+        );
+        nodes = evaluated;
+        if cli.assume_input.is_some() {
+            ASSUMED_INPUT.set(input[consumed..].to_vec()).unwrap();
+        }
+    }
+
+    if optimize {
+        nodes = time!(
+            "OptimizePropagateConstants",
+            PropagateConstants { tape }.optimize(nodes)
+        );
+        nodes = time!(
+            "OptimizeEliminateBoundsChecks",
+            EliminateBoundsChecks { tape }.optimize(nodes)
+        );
+        // Iterations of solved loops would not count towards the step limit.
+        if limits.max_steps.is_none() {
+            nodes = time!(
+                "OptimizeSolveCountedLoops",
+                SolveCountedLoops.optimize(nodes)
+            );
+        }
+        nodes = time!("OptimizeCoalesceStores", CoalesceStores.optimize(nodes));
     }
 
-    if cli.dump_binary {
+    if cli.dump_binary && cached.is_none() {
         let mut file = std::fs::File::create("optimized.txt").unwrap();
         writeln!(file, "{}", syntax::indented(&nodes, 0)).unwrap();
     }
 
     match cli.mode {
         Mode::Interpret => {
-            time!(
-                "Execution finished in",
-                Interpreter::new(
-                    Box::new(std::io::stdin()),
-                    Box::new(std::io::stdout()),
-                    cli.tape_size
-                )
-                .interpret(&nodes)
+            if cli.sparse {
+                interpret::<SparseTape>(&nodes, tape, limits, cli.dump_tape);
+            } else {
+                interpret::<DenseTape>(&nodes, tape, limits, cli.dump_tape);
+            }
+        }
+        Mode::Vm => {
+            let program = time!("CompileBytecode", Program::compile(&nodes));
+            if cli.dump_binary {
+                let mut file = std::fs::File::create("bytecode.txt").unwrap();
+                write!(file, "{}", program).unwrap();
+            }
+            if cli.sparse {
+                run_vm::<SparseTape>(&program, tape, limits, cli.dump_tape);
+            } else {
+                run_vm::<DenseTape>(&program, tape, limits, cli.dump_tape);
+            }
+        }
+        Mode::Auto => {
+            if std::env::consts::ARCH != "x86_64" {
+                panic!("Only x86_64 is supported");
+            }
+
+            let state = if cli.raw_io {
+                raw_io_state(tape, limits)
+            } else {
+                State::new(stdin(), Box::new(std::io::stdout()), tape, limits)
+            };
+            let mut executor = TieredExecutor::new(state, tape, limits, cli.hot_threshold);
+            let result = supervised(executor.state.interrupt.clone(), limits, || {
+                time!("Execution finished in", {
+                    let result = executor.run(&nodes);
+                    match executor.state.flush_output() {
+                        Err(_) if !result.is_error() => RuntimeResultCode::IoError,
+                        _ => result,
+                    }
+                })
+            });
+            println!("Compiled loops: {}", executor.compiled_loops());
+            let state = &executor.state;
+            if cli.dump_tape {
+                let mut file = std::fs::File::create("tape.txt").unwrap();
+                state.tape.dump(&mut file).unwrap();
+            }
+            report_error(result, state.pointer, state.steps());
+        }
+        Mode::Trace => {
+            if std::env::consts::ARCH != "x86_64" {
+                panic!("Only x86_64 is supported");
+            }
+
+            let program = time!("CompileBytecode", Program::unfused(&nodes));
+            let state = if cli.raw_io {
+                raw_io_state(tape, limits)
+            } else {
+                State::new(stdin(), Box::new(std::io::stdout()), tape, limits)
+            };
+            let mut vm = TracingVm::new(state, tape, cli.hot_threshold);
+            let result = time!("Execution finished in", {
+                let result = vm.run(&program);
+                match vm.state.flush_output() {
+                    Err(_) if !result.is_error() => RuntimeResultCode::IoError,
+                    _ => result,
+                }
+            });
+            let branches: usize = vm.trees().iter().map(|tree| tree.branches.len()).sum();
+            println!(
+                "Compiled traces: {} loops, {} branches, {} side exits",
+                vm.trees().len(),
+                branches,
+                vm.side_exits()
             );
+            if cli.dump_binary {
+                let mut file = std::fs::File::create("traces.txt").unwrap();
+                for (index, tree) in vm.trees().iter().enumerate() {
+                    write!(file, "Tree {}\n{}", index, tree).unwrap();
+                }
+            }
+            if cli.dump_tape {
+                let mut file = std::fs::File::create("tape.txt").unwrap();
+                vm.state.tape.dump(&mut file).unwrap();
+            }
+            report_error(result, vm.state.pointer, None);
         }
         Mode::Jit => {
-            if std::env::consts::ARCH != "x86_64" {
+            if cli.backend == Backend::X86_64 && std::env::consts::ARCH != "x86_64" {
                 panic!("Only x86_64 is supported");
             }
 
-            let codegen = CodeGeneration::x86_x64();
-            let executor = codegen.generate(&nodes);
+            let hit = cached.is_some();
+            let executor = match cached {
+                Some((executor, _)) => executor,
+                None => {
+                    let executor = match cli.backend {
+                        Backend::X86_64 => {
+                            let codegen = CodeGeneration::x86_x64(tape, limits);
+                            time!("GenerateCode", codegen.generate(&nodes))
+                        }
+                        Backend::Cranelift => {
+                            let codegen = CodeGeneration::cranelift(tape, limits);
+                            time!("GenerateCode", codegen.generate(&nodes))
+                        }
+                    };
+                    if let Some(cache) = &cache {
+                        if let Err(e) = cache.store(&key, tape.size, &executor) {
+                            eprintln!("Warning: failed to cache the compiled code: {}", e);
+                        }
+                    }
+                    executor
+                }
+            };
 
             if cli.dump_binary {
                 executor.dump_binary("out.bin");
             }
 
-            let mut state = State::new(
-                Box::new(std::io::stdin()),
-                Box::new(std::io::stdout()),
-                cli.tape_size,
-            );
-            let result = time!("Execution finished in", executor.run(&mut state));
-            if result.is_error() {
-                eprintln!("Error: {:?}", result);
+            if !cli.inputs.is_empty() {
+                time!(
+                    "Batch finished in",
+                    run_batch(&executor, &cli.inputs, tape, limits)
+                );
+                return;
+            }
+
+            let adapter = limits.slice.map(|_| AsyncAdapter::new());
+            let program = NativeRun {
+                executor: &executor,
+                state: if cli.raw_io {
+                    raw_io_state(tape, limits)
+                } else {
+                    let (input, output) = io_streams(adapter.as_ref());
+                    State::new(input, output, tape, limits)
+                },
+            };
+            let (NativeRun { state, .. }, result) =
+                supervised(program.state.interrupt.clone(), limits, || {
+                    time!("Execution finished in", run(program, adapter))
+                });
+            if cli.dump_tape {
+                let mut file = std::fs::File::create("tape.txt").unwrap();
+                state.tape.dump(&mut file).unwrap();
+            }
+            if let Some(mut fault) = state.fault {
+                if hit && cli.optimize {
+                    // The optimized instructions the index refers to were never built.
+                    fault.instruction = None;
+                }
+                report_fault(result, fault, &s, cli.optimize.then_some(&nodes));
+            } else {
+                report_error(result, state.pointer, state.steps());
+            }
+        }
+    }
+}
+
+fn interpret<T: Tape>(
+    nodes: &[syntax::Instruction],
+    tape: TapeConfig,
+    limits: ExecutionLimits,
+    dump_tape: bool,
+) {
+    let adapter = limits.slice.map(|_| AsyncAdapter::new());
+    let (input, output) = io_streams(adapter.as_ref());
+    let program = InterpreterRun {
+        nodes,
+        interpreter: Interpreter::<T>::new(input, output, tape, limits),
+    };
+    let (InterpreterRun { interpreter, .. }, result) =
+        supervised(program.interpreter.interrupt(), limits, || {
+            time!("Execution finished in", run(program, adapter))
+        });
+    if dump_tape {
+        let mut file = std::fs::File::create("tape.txt").unwrap();
+        interpreter.tape().dump(&mut file).unwrap();
+    }
+    report_error(result, interpreter.pointer(), Some(interpreter.steps()));
+}
+
+fn run_vm<T: Tape>(program: &Program, tape: TapeConfig, limits: ExecutionLimits, dump_tape: bool) {
+    let adapter = limits.slice.map(|_| AsyncAdapter::new());
+    let (input, output) = io_streams(adapter.as_ref());
+    let run_program = VmRun {
+        program,
+        vm: Vm::<T>::new(input, output, tape, limits),
+    };
+    let (VmRun { vm, .. }, result) = supervised(run_program.vm.interrupt(), limits, || {
+        time!("Execution finished in", run(run_program, adapter))
+    });
+    if dump_tape {
+        let mut file = std::fs::File::create("tape.txt").unwrap();
+        vm.tape().dump(&mut file).unwrap();
+    }
+    report_error(result, vm.pointer(), Some(vm.steps()));
+}
+
+/// Runs the compiled program against every input file, sharing `executor` between worker threads.
+fn run_batch(
+    executor: &NativeExecutor,
+    inputs: &[PathBuf],
+    tape: TapeConfig,
+    limits: ExecutionLimits,
+) {
+    let next = AtomicUsize::new(0);
+    let workers = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(inputs.len());
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                while let Some(path) = inputs.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let files = File::open(path)
+                        .and_then(|input| Ok((input, File::create(path.with_extension("out"))?)));
+                    let (input, output) = match files {
+                        Ok(files) => files,
+                        Err(e) => {
+                            eprintln!("{}: {}", path.display(), e);
+                            continue;
+                        }
+                    };
+                    let mut state = State::new(
+                        Box::new(BufReader::new(input)),
+                        Box::new(BufWriter::new(output)),
+                        tape,
+                        limits,
+                    );
+                    let _watchdog = limits
+                        .timeout
+                        .map(|timeout| state.interrupt.watchdog(timeout));
+                    let result = executor.run(&mut state);
+                    println!("{}: {:?}", path.display(), result);
+                }
+            });
+        }
+    });
+}
+
+/// A state bound to the file descriptors of stdin and stdout.
+#[cfg(unix)]
+fn raw_io_state(tape: TapeConfig, limits: ExecutionLimits) -> State<'static> {
+    use std::os::fd::AsRawFd;
+    State::with_fds(
+        std::io::stdin().as_raw_fd(),
+        std::io::stdout().as_raw_fd(),
+        tape,
+        limits,
+    )
+}
+
+#[cfg(not(unix))]
+fn raw_io_state(_tape: TapeConfig, _limits: ExecutionLimits) -> State<'static> {
+    unreachable!("--raw-io is rejected on this platform")
+}
+
+/// The streams a program reads from and writes to, either stdin and stdout or the pipes of `adapter`.
+fn io_streams(adapter: Option<&AsyncAdapter>) -> (Box<dyn Read>, Box<dyn Write>) {
+    match adapter {
+        Some(adapter) => (adapter.input(), adapter.output()),
+        None => (stdin(), Box::new(std::io::stdout())),
+    }
+}
+
+/// The input of the program, either stdin or what is left of the assumed input.
+fn stdin() -> Box<dyn Read> {
+    match ASSUMED_INPUT.get() {
+        Some(input) => Box::new(input.as_slice()),
+        None => Box::new(std::io::stdin()),
+    }
+}
+
+/// Runs `program` to completion, through `adapter` if there is one.
+fn run<R: Resumable>(mut program: R, adapter: Option<AsyncAdapter>) -> (R, RuntimeResultCode) {
+    match adapter {
+        Some(adapter) => block_on(adapter.run(
+            program,
+            Blocking(std::io::stdin()),
+            Blocking(std::io::stdout()),
+        )),
+        None => {
+            let result = program.run_slice();
+            (program, result)
+        }
+    }
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    let seconds: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    Duration::try_from_secs_f64(seconds).map_err(|e| format!("{}", e))
+}
+
+/// Runs `run` with a watchdog for the timeout and, if requested, a Ctrl-C handler.
+fn supervised<R>(interrupt: Interrupt, limits: ExecutionLimits, run: impl FnOnce() -> R) -> R {
+    let _watchdog = limits.timeout.map(|timeout| interrupt.watchdog(timeout));
+    if limits.interruptible {
+        ctrlc::set_handler(move || interrupt.trigger(RuntimeResultCode::Interrupted))
+            .expect("Failed to install the Ctrl-C handler");
+    }
+    run()
+}
+
+fn report_error(result: RuntimeResultCode, pointer: usize, steps: Option<u64>) {
+    match result {
+        RuntimeResultCode::Ok => {}
+        RuntimeResultCode::OutOfFuel => {
+            eprintln!("Error: {:?} after {} steps", result, steps.unwrap_or(0))
+        }
+        RuntimeResultCode::Timeout | RuntimeResultCode::Interrupted => {
+            eprint!("Error: {:?} at cell {}", result, pointer);
+            match steps {
+                Some(steps) => eprintln!(" after {} steps", steps),
+                None => eprintln!(),
             }
         }
+        _ => eprintln!("Error: {:?}", result),
+    }
+}
+
+/// The optimization passes `main` runs for these options, in order. Part of the key for cached code.
+fn pipeline(cli: &Cli) -> Vec<&'static str> {
+    let mut pipeline = Vec::new();
+    if cli.optimize {
+        pipeline.extend(["CombineIncrements", "ReplaceSet", "CombineSets"]);
+        if cli.max_steps.is_none() {
+            pipeline.push("MatchIdioms");
+        }
+    }
+    if cli.auto_tape_size {
+        pipeline.push("AnalyzeFootprint");
+    }
+    if cli.optimize {
+        if cli.max_steps.is_none() {
+            pipeline.push("EvaluatePrefix");
+        }
+        pipeline.extend(["PropagateConstants", "EliminateBoundsChecks"]);
+        if cli.max_steps.is_none() {
+            pipeline.push("SolveCountedLoops");
+        }
+        pipeline.push("CoalesceStores");
+    }
+    pipeline
+}
+
+/// Reports an access to a guard page, with the instruction it happened in.
+/// Without optimizations the instructions are the commands of `source`, otherwise those of `optimized`.
+fn report_fault(
+    result: RuntimeResultCode,
+    fault: GuardFault,
+    source: &str,
+    optimized: Option<&Vec<syntax::Instruction>>,
+) {
+    eprint!("Error: {:?} accessing cell {}", result, fault.cell);
+    let Some(index) = fault.instruction else {
+        eprintln!();
+        return;
+    };
+    match optimized {
+        None => match syntax::source_position(source, index) {
+            Some((line, column)) => eprintln!(" at line {}, column {}", line, column),
+            None => eprintln!(),
+        },
+        Some(nodes) => match syntax::instruction_at(nodes, index) {
+            // `--dump-binary` writes one instruction per line in the same order.
+            Some(node) => eprintln!(
+                " in `{}` at line {} of optimized.txt, written by --dump-binary",
+                node,
+                index + 1
+            ),
+            None => eprintln!(),
+        },
     }
 }
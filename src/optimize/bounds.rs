@@ -0,0 +1,305 @@
+use super::rewrite::{fold_bodies, rewrite, Context, Rewrite, Rewriter};
+use super::OptimizationPass;
+use crate::execution::tape::{StartOffset, TapeBounds, TapeConfig};
+use crate::syntax::Instruction;
+use crate::syntax::Instruction::*;
+
+/// The cells the pointer may be at. `None` means unbounded in that direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PointerRange {
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+}
+
+impl PointerRange {
+    fn at(cell: i64) -> Self {
+        PointerRange {
+            min: Some(cell),
+            max: Some(cell),
+        }
+    }
+
+    fn shift(self, value: i64) -> Self {
+        PointerRange {
+            min: self.min.map(|min| min + value),
+            max: self.max.map(|max| max + value),
+        }
+    }
+
+    fn hull(self, other: Self) -> Self {
+        PointerRange {
+            min: self.min.zip(other.min).map(|(a, b)| a.min(b)),
+            max: self.max.zip(other.max).map(|(a, b)| a.max(b)),
+        }
+    }
+
+    /// The smallest tape that holds every cell of this footprint, relative to the starting cell.
+    pub fn tape_size(&self, start: StartOffset) -> Option<usize> {
+        let (min, max) = self.min.zip(self.max)?;
+        let size = match start {
+            StartOffset::Cell(cell) => cell as i64 + max + 1,
+            StartOffset::Middle => 2 * max.max(-min) + 1,
+        };
+        Some(size.max(1) as usize)
+    }
+
+    /// Adds every offset in `other` to every cell in this range.
+    fn offset(self, other: Self) -> Self {
+        PointerRange {
+            min: self.min.zip(other.min).map(|(a, b)| a + b),
+            max: self.max.zip(other.max).map(|(a, b)| a + b),
+        }
+    }
+}
+
+/// How a body moves the pointer on an unbounded tape, relative to the cell it starts at.
+#[derive(Clone, Copy)]
+struct BodyEffect {
+    /// Where the pointer may be after the body.
+    exit: PointerRange,
+    /// Every cell the pointer may visit.
+    footprint: PointerRange,
+}
+
+impl BodyEffect {
+    fn new() -> Self {
+        BodyEffect {
+            exit: PointerRange::at(0),
+            footprint: PointerRange::at(0),
+        }
+    }
+
+    /// Extends the effect by `node`. `body` is the effect of its body, if it has one.
+    fn apply(&mut self, node: &Instruction, body: Option<BodyEffect>) {
+        match (node, body) {
+            (Move { value } | MoveUnchecked { value }, _) => {
+                self.exit = self.exit.shift(*value as i64);
+            }
+            (Loop { .. }, Some(body)) => {
+                self.exit = self.exit.offset(iterations(body.exit));
+                self.footprint = self.footprint.hull(self.exit.offset(body.footprint));
+            }
+            (If { .. } | Idiom { .. }, Some(body)) => {
+                self.footprint = self.footprint.hull(self.exit.offset(body.footprint));
+                self.exit = self.exit.hull(self.exit.offset(body.exit));
+            }
+            _ => {}
+        }
+        self.footprint = self.footprint.hull(self.exit);
+    }
+}
+
+/// The offsets from its entry at which a loop starts an iteration, given where its body leaves the pointer.
+/// A bound stays only if no iteration can move the pointer past it.
+fn iterations(body_exit: PointerRange) -> PointerRange {
+    PointerRange {
+        min: body_exit.min.filter(|min| *min >= 0).map(|_| 0),
+        max: body_exit.max.filter(|max| *max <= 0).map(|_| 0),
+    }
+}
+
+/// The effect of `nodes`, and the effects of all bodies in them in the order their nodes start.
+/// Every body is analyzed once, so this is linear in the size of the program.
+fn body_effects(nodes: &[Instruction]) -> (BodyEffect, Vec<BodyEffect>) {
+    fold_bodies(nodes, BodyEffect::new(), |effect, node, body| {
+        effect.apply(node, body.copied())
+    })
+}
+
+/// Tracks the pointer through a program, given the number of cells in the tape.
+struct PointerAnalysis {
+    size: i64,
+    wrap: bool,
+}
+
+impl PointerAnalysis {
+    /// Applies a move to `range`. Returns the new range and whether the move stays in bounds
+    /// without a check. A checked move leaves the pointer in bounds, otherwise execution stops.
+    fn apply_move(&self, range: PointerRange, value: i32) -> (PointerRange, bool) {
+        let moved = range.shift(value as i64);
+        let size = self.size;
+        let wrapped = PointerRange {
+            min: Some(0),
+            max: Some(size - 1),
+        };
+        if value > 0 {
+            match moved.max {
+                Some(max) if max < size => (moved, true),
+                _ if self.wrap => (wrapped, false),
+                _ => (
+                    PointerRange {
+                        max: Some(size - 1),
+                        ..moved
+                    },
+                    false,
+                ),
+            }
+        } else {
+            match moved.min {
+                Some(min) if min >= 0 => (moved, true),
+                _ if self.wrap => (wrapped, false),
+                _ => (
+                    PointerRange {
+                        min: Some(0),
+                        ..moved
+                    },
+                    false,
+                ),
+            }
+        }
+    }
+
+    /// The range of the pointer at the start of every iteration of a loop entered with `entry`.
+    fn loop_entry(&self, entry: PointerRange, body: BodyEffect) -> PointerRange {
+        let iterations = iterations(body.exit);
+        // The pointer returns to the same cell after every iteration of a balanced loop.
+        if iterations == PointerRange::at(0) {
+            return entry;
+        }
+        // A wrapping pointer may come around from the other end.
+        let range = if self.wrap {
+            PointerRange {
+                min: None,
+                max: None,
+            }
+        } else {
+            entry.offset(iterations)
+        };
+        // Either way the pointer is on the tape when an iteration starts.
+        PointerRange {
+            min: range.min.or(Some(0)),
+            max: range.max.or(Some(self.size - 1)),
+        }
+    }
+}
+
+/// Replaces every `Move` that provably stays in bounds with a `MoveUnchecked`.
+struct Annotate<'a> {
+    analysis: PointerAnalysis,
+    /// The effects of the bodies that were not entered yet, see `body_effects`.
+    effects: std::slice::Iter<'a, BodyEffect>,
+    /// For the top level and every body being annotated, the range of the pointer
+    /// where it started and where the pointer is now.
+    ranges: Vec<(PointerRange, PointerRange)>,
+}
+
+impl Rewriter for Annotate<'_> {
+    fn enter(&mut self, node: Instruction, _: &Context) -> Rewrite {
+        let range = &mut self.ranges.last_mut().unwrap().1;
+        match node {
+            Move { value } | MoveUnchecked { value } => {
+                let (moved, in_bounds) = self.analysis.apply_move(*range, value);
+                *range = moved;
+                Rewrite::Keep(if in_bounds {
+                    MoveUnchecked { value }
+                } else {
+                    Move { value }
+                })
+            }
+            Loop { .. } | If { .. } | Idiom { .. } => {
+                let body = *self.effects.next().unwrap();
+                let entry = match node {
+                    Loop { .. } => self.analysis.loop_entry(*range, body),
+                    _ => *range,
+                };
+                self.ranges.push((entry, entry));
+                Rewrite::Keep(node)
+            }
+            other => Rewrite::Keep(other),
+        }
+    }
+
+    fn exit(&mut self, node: Instruction, _: &Context) -> Rewrite {
+        if node.body().is_some() {
+            let (entry, exit) = self.ranges.pop().unwrap();
+            self.ranges.last_mut().unwrap().1 = entry.hull(exit);
+        }
+        Rewrite::Keep(node)
+    }
+}
+
+/// The cells a program may visit on an unbounded tape, relative to the starting cell.
+pub fn tape_footprint(nodes: &[Instruction]) -> PointerRange {
+    body_effects(nodes).0.footprint
+}
+
+/// Removes the bounds checks of moves that can not leave the configured tape.
+/// Must run after all other passes but `CoalesceStores`, as they do not know about `MoveUnchecked`.
+pub struct EliminateBoundsChecks {
+    pub tape: TapeConfig,
+}
+
+impl OptimizationPass for EliminateBoundsChecks {
+    fn optimize(&self, nodes: Vec<Instruction>) -> Vec<Instruction> {
+        // Growing the tape moves every cell, so positions are not known statically.
+        if self.tape.bidirectional {
+            return nodes;
+        }
+        let (_, effects) = body_effects(&nodes);
+        let start = PointerRange::at(self.tape.start_offset() as i64);
+        let mut annotate = Annotate {
+            analysis: PointerAnalysis {
+                size: self.tape.size as i64,
+                wrap: self.tape.bounds == TapeBounds::Wrap,
+            },
+            effects: effects.iter(),
+            ranges: vec![(start, start)],
+        };
+        rewrite(nodes, &mut annotate).nodes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax::parse;
+    use std::num::Wrapping;
+
+    #[test]
+    fn deep_unbalanced_nest() {
+        // Every loop widens, but each body is analyzed only once.
+        let depth = 1000;
+        let source = format!("+{}{}", "[>+".repeat(depth), "]".repeat(depth));
+        let nodes = parse(&source).unwrap();
+        assert_eq!(
+            tape_footprint(&nodes),
+            PointerRange {
+                min: Some(0),
+                max: None
+            }
+        );
+        let pass = EliminateBoundsChecks {
+            tape: TapeConfig::new(30000),
+        };
+        // Every move goes right further than any bound, so they all stay checked.
+        assert!(pass.optimize(nodes.clone()) == nodes);
+    }
+
+    #[test]
+    fn balanced_loop_moves_are_unchecked() {
+        let pass = EliminateBoundsChecks {
+            tape: TapeConfig::new(30000),
+        };
+        let nodes = pass.optimize(parse(">[<+>-]<[>]").unwrap());
+        let add = |value| Add {
+            value: Wrapping(value),
+        };
+        let expected = vec![
+            MoveUnchecked { value: 1 },
+            Loop {
+                nodes: vec![
+                    MoveUnchecked { value: -1 },
+                    add(1),
+                    MoveUnchecked { value: 1 },
+                    add(-1),
+                ],
+            },
+            MoveUnchecked { value: -1 },
+            // The scan may run off the end of the tape.
+            Loop {
+                nodes: vec![Move { value: 1 }],
+            },
+        ];
+        assert!(nodes == expected);
+    }
+}
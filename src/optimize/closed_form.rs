@@ -0,0 +1,370 @@
+use super::rewrite::{rewrite, Context, Rewrite, Rewriter};
+use super::OptimizationPass;
+use crate::syntax::Instruction::*;
+use crate::syntax::{Instruction, Term};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Polynomials with more terms than this are not worth computing in closed form.
+const MAX_TERMS: usize = 8;
+
+/// A polynomial over the values cells had before some code, by their offset from the pointer.
+/// Maps every product of cells, as sorted offsets, to its coefficient. Arithmetic wraps like cells do.
+#[derive(Clone, Default, PartialEq, Eq)]
+struct Polynomial(BTreeMap<Vec<i32>, u8>);
+
+impl Polynomial {
+    fn constant(value: u8) -> Self {
+        let mut constant = Self::default();
+        constant.add_term(Vec::new(), value);
+        constant
+    }
+
+    fn cell(offset: i32) -> Self {
+        Polynomial(BTreeMap::from([(vec![offset], 1)]))
+    }
+
+    fn add_term(&mut self, factors: Vec<i32>, coefficient: u8) {
+        let sum = self
+            .0
+            .get(&factors)
+            .map_or(coefficient, |c| c.wrapping_add(coefficient));
+        if sum == 0 {
+            self.0.remove(&factors);
+        } else {
+            self.0.insert(factors, sum);
+        }
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        let mut sum = self.clone();
+        for (factors, coefficient) in &other.0 {
+            sum.add_term(factors.clone(), *coefficient);
+        }
+        sum
+    }
+
+    fn sub(&self, other: &Self) -> Self {
+        self.add(&other.mul(&Self::constant(u8::MAX)))
+    }
+
+    fn mul(&self, other: &Self) -> Self {
+        let mut product = Self::default();
+        for (a, x) in &self.0 {
+            for (b, y) in &other.0 {
+                let mut factors = [a.as_slice(), b.as_slice()].concat();
+                factors.sort_unstable();
+                product.add_term(factors, x.wrapping_mul(*y));
+            }
+        }
+        product
+    }
+
+    /// Replaces every cell with `value` of its offset.
+    fn substitute(&self, value: impl Fn(i32) -> Polynomial) -> Self {
+        let mut result = Self::default();
+        for (factors, coefficient) in &self.0 {
+            let term = factors
+                .iter()
+                .fold(Self::constant(*coefficient), |product, factor| {
+                    product.mul(&value(*factor))
+                });
+            result = result.add(&term);
+        }
+        result
+    }
+
+    /// The offsets of the cells the polynomial depends on.
+    fn cells(&self) -> impl Iterator<Item = i32> + '_ {
+        self.0.keys().flatten().copied()
+    }
+
+    fn as_constant(&self) -> Option<u8> {
+        match self.0.iter().next() {
+            None => Some(0),
+            Some((factors, coefficient)) if factors.is_empty() => Some(*coefficient),
+            Some(_) => None,
+        }
+    }
+
+    fn terms(self) -> Vec<Term> {
+        self.0
+            .into_iter()
+            .map(|(factors, coefficient)| Term {
+                coefficient,
+                factors,
+            })
+            .collect()
+    }
+}
+
+/// The values of cells after some code in terms of their values before. Cells not in `cells` did not change.
+#[derive(Default)]
+struct Effects {
+    cells: BTreeMap<i32, Polynomial>,
+}
+
+impl Effects {
+    fn get(&self, offset: i32) -> Polynomial {
+        self.cells
+            .get(&offset)
+            .cloned()
+            .unwrap_or_else(|| Polynomial::cell(offset))
+    }
+
+    /// Fails if the value got too large to be worth it.
+    fn set(&mut self, offset: i32, value: Polynomial) -> Option<()> {
+        (value.0.len() <= MAX_TERMS).then(|| {
+            self.cells.insert(offset, value);
+        })
+    }
+
+    /// The offsets of the cells that may end up with a different value.
+    fn changed(&self) -> BTreeSet<i32> {
+        self.cells
+            .iter()
+            .filter(|(offset, value)| **value != Polynomial::cell(**offset))
+            .map(|(offset, _)| *offset)
+            .collect()
+    }
+}
+
+/// Replaces loops that only do arithmetic and count their current cell down or up by one
+/// with the arithmetic all iterations do together, as products of cells in `MulAdd`s.
+/// Loops are solved innermost first, so the `MulAdd`s of nested loops become polynomials in the loops around them.
+/// Must run after `EliminateBoundsChecks`, as only loops that move with `MoveUnchecked` are solved.
+/// The closed form accesses the cells of the loop even if it does not run.
+pub struct SolveCountedLoops;
+
+impl OptimizationPass for SolveCountedLoops {
+    fn optimize(&self, nodes: Vec<Instruction>) -> Vec<Instruction> {
+        rewrite(nodes, &mut SolveCountedLoops).nodes
+    }
+}
+
+impl Rewriter for SolveCountedLoops {
+    fn exit(&mut self, node: Instruction, _: &Context) -> Rewrite {
+        let Loop { nodes } = node else {
+            return Rewrite::Keep(node);
+        };
+        let Some(iteration) = evaluate(&nodes) else {
+            return Rewrite::Keep(Loop { nodes });
+        };
+        if let Some(effects) = solve(&iteration) {
+            return Rewrite::Replace(closed_form(effects));
+        }
+        // Cells that every iteration sets to the same value only change in the first one.
+        match peel(&iteration) {
+            Some(effects) => {
+                let mut body = nodes;
+                body.extend(closed_form(effects));
+                Rewrite::Replace(vec![If { nodes: body }])
+            }
+            None => Rewrite::Keep(Loop { nodes }),
+        }
+    }
+}
+
+/// The effects of running `nodes` once, if they only do arithmetic and end where they started.
+/// Nested loops that could be solved are already replaced, so the others are not evaluated again.
+fn evaluate(nodes: &[Instruction]) -> Option<Effects> {
+    let mut effects = Effects::default();
+    let mut pointer = 0;
+    for node in nodes {
+        match node {
+            Add { value } => {
+                let cell = effects.get(pointer);
+                effects.set(pointer, cell.add(&Polynomial::constant(value.0 as u8)))?;
+            }
+            Set { value } => effects.set(pointer, Polynomial::constant(*value))?,
+            MoveUnchecked { value } => pointer += value,
+            SetRange { offset, values } => {
+                for (index, value) in values.iter().enumerate() {
+                    effects.set(
+                        pointer + offset + index as i32,
+                        Polynomial::constant(*value),
+                    )?;
+                }
+            }
+            AddVector { offset, values } => {
+                for (index, value) in values.iter().enumerate() {
+                    let cell = effects.get(pointer + offset + index as i32);
+                    let sum = cell.add(&Polynomial::constant(*value as u8));
+                    effects.set(pointer + offset + index as i32, sum)?;
+                }
+            }
+            MulAdd { offset, terms } => {
+                let mut sum = effects.get(pointer + offset);
+                for term in terms {
+                    let product = term
+                        .factors
+                        .iter()
+                        .fold(Polynomial::constant(term.coefficient), |product, factor| {
+                            product.mul(&effects.get(pointer + factor))
+                        });
+                    sum = sum.add(&product);
+                }
+                effects.set(pointer + offset, sum)?;
+            }
+            Move { .. }
+            | Write
+            | WriteBytes { .. }
+            | Read
+            | Loop { .. }
+            | If { .. }
+            | Idiom { .. } => return None,
+        }
+    }
+    (pointer == 0).then_some(effects)
+}
+
+/// The effects of all iterations of a loop together, given the effects of one.
+/// The loop must change its counter by one, and all other cells by amounts that do not change between iterations.
+fn solve(iteration: &Effects) -> Option<Effects> {
+    let step = iteration.get(0).sub(&Polynomial::cell(0)).as_constant()?;
+    if step != 1 && step != u8::MAX {
+        return None;
+    }
+    let trips = Polynomial::cell(0).mul(&Polynomial::constant(step.wrapping_neg()));
+    let changed = iteration.changed();
+
+    let mut effects = Effects::default();
+    for offset in &changed {
+        if *offset == 0 {
+            continue;
+        }
+        let delta = iteration.get(*offset).sub(&Polynomial::cell(*offset));
+        if delta.cells().any(|cell| changed.contains(&cell)) {
+            return None;
+        }
+        effects.set(*offset, Polynomial::cell(*offset).add(&delta.mul(&trips)))?;
+    }
+    effects.set(0, Polynomial::constant(0))?;
+    Some(effects)
+}
+
+/// The effects of all iterations but the first, if the first one sets cells to a value that stays the same.
+fn peel(iteration: &Effects) -> Option<Effects> {
+    let changed = iteration.changed();
+    let fixed: BTreeSet<_> = changed
+        .iter()
+        .filter(|offset| {
+            **offset != 0
+                && !iteration
+                    .get(**offset)
+                    .cells()
+                    .any(|cell| changed.contains(&cell))
+        })
+        .copied()
+        .collect();
+    if fixed.is_empty() {
+        return None;
+    }
+
+    let mut steady = Effects::default();
+    for (offset, value) in &iteration.cells {
+        if fixed.contains(offset) {
+            continue;
+        }
+        let value = value.substitute(|cell| {
+            if fixed.contains(&cell) {
+                iteration.get(cell)
+            } else {
+                Polynomial::cell(cell)
+            }
+        });
+        steady.set(*offset, value)?;
+    }
+    solve(&steady)
+}
+
+/// `MulAdd`s that apply `effects` of a solved loop, then clearing the counter.
+/// Every `MulAdd` only reads cells the others do not change.
+fn closed_form(effects: Effects) -> Vec<Instruction> {
+    let mut result = Vec::new();
+    for (offset, value) in effects.cells {
+        if offset == 0 {
+            continue;
+        }
+        let delta = value.sub(&Polynomial::cell(offset));
+        if !delta.0.is_empty() {
+            result.push(MulAdd {
+                offset,
+                terms: delta.terms(),
+            });
+        }
+    }
+    result.push(Set { value: 0 });
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::tape::TapeConfig;
+    use crate::optimize::bounds::EliminateBoundsChecks;
+    use crate::optimize::peephole::{CombineIncrements, CombineSets, ReplaceSet};
+    use crate::optimize::tests::{interpret, CELLS};
+    use crate::syntax::{instruction_at, parse};
+
+    /// Solves the loops of `source` and checks that the result ends like `source` on every input.
+    /// Returns how the solved instructions print, with bodies right after their loop.
+    fn solve(source: &str, inputs: &[&[u8]]) -> Vec<String> {
+        let nodes = parse(source).unwrap();
+        let tape = TapeConfig::new(CELLS);
+        let solved =
+            CombineSets.optimize(ReplaceSet.optimize(CombineIncrements.optimize(nodes.clone())));
+        let solved = EliminateBoundsChecks { tape }.optimize(solved);
+        let solved = SolveCountedLoops.optimize(solved);
+        for input in inputs {
+            assert_eq!(
+                interpret(&solved, input),
+                interpret(&nodes, input),
+                "{} on {:?}",
+                source,
+                input
+            );
+        }
+        let mut printed = Vec::new();
+        let mut index = 0;
+        while let Some(node) = instruction_at(&solved, index) {
+            printed.push(node.to_string());
+            index += 1;
+        }
+        printed
+    }
+
+    #[test]
+    fn nested_loop_multiplies() {
+        // Adds the product of the first two cells to the third, using the fourth as a temporary.
+        let solved = solve(
+            ",>,>,<<[>[>+>+<<-]>>[<<+>>-]<<<-]",
+            &[
+                b"\x00\x05\x01",
+                b"\x03\x00\x01",
+                b"\x06\x07\x08",
+                b"\x01\x01\x00",
+            ],
+        );
+        // The first iteration is peeled off, the others add the rest of the product.
+        assert!(solved.iter().any(|node| node == "MulAdd 2 1*[0]*[1]"));
+        assert!(!solved.iter().any(|node| node.starts_with("Loop")));
+    }
+
+    #[test]
+    fn products_wrap() {
+        let inputs: &[&[u8]] = &[
+            b"\x10\x10\x00",
+            b"\xc8\x03\x07",
+            b"\xff\xff\xff",
+            b"\x80\x02\x01",
+        ];
+        // 16 * 16 and 128 * 2 wrap to zero, the others wrap to what is left over.
+        solve(",>,>,<<[>[>+>+<<-]>>[<<+>>-]<<<-]", inputs);
+        // Coefficients wrap as well.
+        let solved = solve(",>,>,<<[>[>+++>+<<-]>>[<<+>>-]<<<-]", inputs);
+        assert!(!solved.iter().any(|node| node.starts_with("Loop")));
+        // Counting up runs 256 minus the counter times.
+        let solved = solve(",>,>,<<[>[>-->+<<-]>>[<<+>>-]<<<+]", inputs);
+        assert!(!solved.iter().any(|node| node.starts_with("Loop")));
+    }
+}
@@ -0,0 +1,184 @@
+use super::rewrite::{rewrite, Context, Rewrite, Rewriter};
+use super::OptimizationPass;
+use crate::syntax::Instruction;
+use crate::syntax::Instruction::*;
+use std::collections::BTreeMap;
+
+/// Cells at most this far apart share an `AddVector`, the cells between them add zero.
+const MAX_ADD_GAP: i32 = 4;
+
+/// What a run of straight-line code does to a single cell.
+#[derive(Clone, Copy)]
+enum Effect {
+    Set(u8),
+    Add(i8),
+}
+
+/// Merges runs of `Set` and `Add` across moves that stay within the tape into `SetRange` and `AddVector`,
+/// so that code generation can write several cells at once. The constant output of a run is written
+/// with a single `WriteBytes` at its end.
+/// Must run after `EliminateBoundsChecks`, as only `MoveUnchecked` is merged across.
+pub struct CoalesceStores;
+
+impl OptimizationPass for CoalesceStores {
+    fn optimize(&self, nodes: Vec<Instruction>) -> Vec<Instruction> {
+        rewrite(nodes, &mut CoalesceStores).nodes
+    }
+}
+
+impl Rewriter for CoalesceStores {
+    fn sequence(&mut self, nodes: Vec<Instruction>, _: &Context) -> Rewrite<Vec<Instruction>> {
+        let len = nodes.len();
+        let mut result = Vec::with_capacity(len);
+        let mut run = Run::default();
+        for node in nodes {
+            match node {
+                Add { .. } | Set { .. } | MoveUnchecked { .. } | WriteBytes { .. } => {
+                    run.push(node)
+                }
+                other => {
+                    run.flush(&mut result);
+                    result.push(other);
+                }
+            }
+        }
+        run.flush(&mut result);
+        Rewrite::resized(len, result)
+    }
+}
+
+/// Straight-line code that only changes cells, moves within the tape and writes constant output.
+#[derive(Default)]
+struct Run {
+    nodes: Vec<Instruction>,
+    /// The pointer relative to the start of the run.
+    offset: i32,
+    cells: BTreeMap<i32, Effect>,
+    /// Nothing in the run can fail or block, so the output can wait until its end.
+    output: Vec<u8>,
+}
+
+impl Run {
+    fn push(&mut self, node: Instruction) {
+        match &node {
+            Add { value } => {
+                let effect = match self.cells.get(&self.offset) {
+                    Some(Effect::Set(cell)) => Effect::Set(cell.wrapping_add_signed(value.0)),
+                    Some(Effect::Add(sum)) => Effect::Add(sum.wrapping_add(value.0)),
+                    None => Effect::Add(value.0),
+                };
+                self.cells.insert(self.offset, effect);
+            }
+            Set { value } => {
+                self.cells.insert(self.offset, Effect::Set(*value));
+            }
+            MoveUnchecked { value } => self.offset += value,
+            WriteBytes { bytes } => {
+                self.output.extend(bytes);
+                return;
+            }
+            _ => unreachable!(),
+        }
+        self.nodes.push(node);
+    }
+
+    /// Emits the run, merged if it changes more than one cell.
+    fn flush(&mut self, result: &mut Vec<Instruction>) {
+        self.flush_stores(result);
+        let bytes = std::mem::take(&mut self.output);
+        if !bytes.is_empty() {
+            result.push(WriteBytes { bytes });
+        }
+    }
+
+    fn flush_stores(&mut self, result: &mut Vec<Instruction>) {
+        let nodes = std::mem::take(&mut self.nodes);
+        let offset = std::mem::take(&mut self.offset);
+        let cells = std::mem::take(&mut self.cells);
+        if cells.len() < 2 {
+            result.extend(nodes);
+            return;
+        }
+
+        let mut current: Option<Instruction> = None;
+        for (cell, effect) in cells {
+            // Extend the current node if the cell continues it.
+            match (&mut current, effect) {
+                (
+                    Some(SetRange {
+                        offset: start,
+                        values,
+                    }),
+                    Effect::Set(value),
+                ) if *start + values.len() as i32 == cell => {
+                    values.push(value);
+                    continue;
+                }
+                (
+                    Some(AddVector {
+                        offset: start,
+                        values,
+                    }),
+                    Effect::Add(value),
+                ) if cell - (*start + values.len() as i32) < MAX_ADD_GAP => {
+                    values.resize((cell - *start) as usize, 0);
+                    values.push(value);
+                    continue;
+                }
+                _ => {}
+            }
+            result.extend(current.take());
+            current = match effect {
+                Effect::Add(0) => None,
+                Effect::Add(value) => Some(AddVector {
+                    offset: cell,
+                    values: vec![value],
+                }),
+                Effect::Set(value) => Some(SetRange {
+                    offset: cell,
+                    values: vec![value],
+                }),
+            };
+        }
+        result.extend(current);
+        if offset != 0 {
+            result.push(MoveUnchecked { value: offset });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::tape::TapeConfig;
+    use crate::optimize::bounds::EliminateBoundsChecks;
+    use crate::optimize::constants::PropagateConstants;
+    use crate::optimize::peephole::CombineIncrements;
+    use crate::optimize::tests::{interpret, CELLS};
+    use crate::syntax::parse;
+
+    #[test]
+    fn folded_writes_keep_the_output() {
+        // Known cells print "Hi\n" and a byte that is not UTF-8, then the input is echoed.
+        let source = format!(
+            ",>{}.{}.>{}.>-.<<<.",
+            "+".repeat(72),
+            "+".repeat(33),
+            "+".repeat(10)
+        );
+        let nodes = parse(&source).unwrap();
+        let tape = TapeConfig::new(CELLS);
+        let folded = CombineIncrements.optimize(nodes.clone());
+        let folded = PropagateConstants { tape }.optimize(folded);
+        let folded = EliminateBoundsChecks { tape }.optimize(folded);
+        let folded = CoalesceStores.optimize(folded);
+        let writes: Vec<_> = folded
+            .iter()
+            .filter(|node| matches!(node, Write | WriteBytes { .. }))
+            .map(|node| node.to_string())
+            .collect();
+        assert_eq!(writes, ["WriteBytes \"Hi\\n\\xff\"", "Write"]);
+        assert_eq!(interpret(&folded, b"x"), interpret(&nodes, b"x"));
+        assert_eq!(interpret(&nodes, b"x").1, b"Hi\n\xffx");
+    }
+}
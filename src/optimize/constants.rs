@@ -0,0 +1,434 @@
+use super::rewrite::{fold_bodies, rewrite, Context, Rewrite, Rewriter};
+use super::OptimizationPass;
+use crate::execution::tape::{TapeBounds, TapeConfig};
+use crate::syntax::Instruction::*;
+use crate::syntax::{drop_nodes, Instruction};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The values of the cells around the pointer, as far as they are known.
+#[derive(Clone)]
+struct KnownCells {
+    /// The pointer relative to where tracking started.
+    pointer: i64,
+    /// Cells relative to where tracking started. `None` if the value is unknown.
+    cells: BTreeMap<i64, Option<u8>>,
+    /// The value of every other cell, if known.
+    rest: Option<u8>,
+}
+
+impl KnownCells {
+    fn zeroed() -> Self {
+        KnownCells {
+            pointer: 0,
+            cells: BTreeMap::new(),
+            rest: Some(0),
+        }
+    }
+
+    fn unknown() -> Self {
+        KnownCells {
+            rest: None,
+            ..Self::zeroed()
+        }
+    }
+
+    /// The value of the cell at `offset` from the pointer.
+    fn get(&self, offset: i64) -> Option<u8> {
+        match self.cells.get(&(self.pointer + offset)) {
+            Some(value) => *value,
+            None => self.rest,
+        }
+    }
+
+    fn set(&mut self, offset: i64, value: Option<u8>) {
+        self.cells.insert(self.pointer + offset, value);
+    }
+
+    /// What is known after either `self` or `other`, which track the pointer from the same cell.
+    fn merge(&self, other: &Self) -> Self {
+        let value = |known: &Self, cell: &i64| known.cells.get(cell).copied().unwrap_or(known.rest);
+        let cells = self
+            .cells
+            .keys()
+            .chain(other.cells.keys())
+            .map(|cell| {
+                let value = value(self, cell);
+                (
+                    *cell,
+                    value.filter(|_| value == other.cells.get(cell).copied().unwrap_or(other.rest)),
+                )
+            })
+            .collect();
+        KnownCells {
+            pointer: self.pointer,
+            cells,
+            rest: self.rest.filter(|_| self.rest == other.rest),
+        }
+    }
+}
+
+/// Tracks the values of cells, starting from a zeroed tape. Removes loops that never run
+/// and `Set`s of the value a cell already has, and turns `Add`s to known cells into `Set`s
+/// and `Write`s of known cells into `WriteBytes`. Loops whose body ends on a zero cell where it started become `If`s.
+/// Like the other passes, it does not know about `MoveUnchecked`.
+pub struct PropagateConstants {
+    pub tape: TapeConfig,
+}
+
+impl OptimizationPass for PropagateConstants {
+    fn optimize(&self, nodes: Vec<Instruction>) -> Vec<Instruction> {
+        let (_, writes) = fold_bodies(&nodes, Some((0, BTreeSet::new())), |writes, node, body| {
+            self.add_writes(writes, node, body)
+        });
+        let mut propagate = Propagate {
+            pass: self,
+            writes: writes.into_iter(),
+            frames: vec![Frame::Body {
+                known: KnownCells::zeroed(),
+                before: None,
+            }],
+        };
+        rewrite(nodes, &mut propagate).nodes
+    }
+}
+
+impl PropagateConstants {
+    /// A wrapping move can end up on any cell, so offsets no longer tell cells apart.
+    fn wraps(&self) -> bool {
+        self.tape.bounds == TapeBounds::Wrap
+    }
+
+    /// Adds the cells `node` may change to `writes`, which holds where the pointer is relative to
+    /// the start of a body and the cells changed so far. `body` is the same for the node's body.
+    /// `None` if the changed cells are not known.
+    fn add_writes(&self, writes: &mut Writes, node: &Instruction, body: Option<&Writes>) {
+        let Some((pointer, cells)) = writes else {
+            return;
+        };
+        let mut range = |offset: i32, len: usize| {
+            cells.extend((0..len as i64).map(|index| *pointer + offset as i64 + index));
+        };
+        match node {
+            Add { .. } | Set { .. } | Read => range(0, 1),
+            Move { .. } if self.wraps() => *writes = None,
+            Move { value } | MoveUnchecked { value } => *pointer += *value as i64,
+            Loop { .. } | If { .. } | Idiom { .. } => match body.and_then(balanced) {
+                Some(body) => cells.extend(body.iter().map(|cell| *pointer + cell)),
+                None => *writes = None,
+            },
+            SetRange { offset, values } => range(*offset, values.len()),
+            AddVector { offset, values } => range(*offset, values.len()),
+            MulAdd { offset, .. } => range(*offset, 1),
+            Write | WriteBytes { .. } => {}
+        }
+        if writes
+            .as_ref()
+            .is_some_and(|(_, cells)| cells.len() > MAX_WRITES)
+        {
+            *writes = None;
+        }
+    }
+}
+
+/// Bodies changing more cells than this are treated as changing any cell.
+const MAX_WRITES: usize = 256;
+
+/// Where the pointer is relative to the start of a body, and the cells the body changed relative to its start.
+type Writes = Option<(i64, BTreeSet<i64>)>;
+
+/// The cells a body may change, if the pointer ends where it started.
+fn balanced(writes: &Writes) -> Option<&BTreeSet<i64>> {
+    writes
+        .as_ref()
+        .filter(|(pointer, _)| *pointer == 0)
+        .map(|(_, cells)| cells)
+}
+
+/// A body constants are propagated through.
+enum Frame {
+    /// The top level or the body of a loop or `If`. `before` is what was known before a loop
+    /// whose body only changes known cells.
+    Body {
+        known: KnownCells,
+        before: Option<KnownCells>,
+    },
+    /// The body of an idiom or anything nested in it, which is left as it is.
+    Idiom,
+}
+
+struct Propagate<'a> {
+    pass: &'a PropagateConstants,
+    /// The writes of the bodies that were not entered yet, see `PropagateConstants::add_writes`.
+    writes: std::vec::IntoIter<Writes>,
+    frames: Vec<Frame>,
+}
+
+impl Propagate<'_> {
+    /// What is known in the body being visited.
+    fn known(&mut self) -> &mut KnownCells {
+        match self.frames.last_mut() {
+            Some(Frame::Body { known, .. }) => known,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Skips the writes of the bodies in `nodes`, which are not visited.
+    fn skip(&mut self, nodes: &[Instruction]) {
+        let (_, bodies) = fold_bodies(nodes, (), |_, _, _| {});
+        for _ in 0..bodies.len() {
+            self.writes.next();
+        }
+    }
+
+    /// Enters a node with a body. Pushes the frame its body is visited with, unless the node is dropped.
+    fn enter_body(&mut self, node: Instruction) -> Rewrite {
+        if let Idiom { nodes, .. } = &node {
+            let writes = self.writes.next().unwrap();
+            self.skip(nodes);
+            self.forget(&writes);
+            self.frames.push(Frame::Idiom);
+            return Rewrite::Keep(node);
+        }
+        if self.known().get(0) == Some(0) {
+            self.skip(std::slice::from_ref(&node));
+            drop_nodes(vec![node]);
+            return Rewrite::Replace(Vec::new());
+        }
+        let writes = self.writes.next().unwrap();
+        let mut before = None;
+        if let Loop { .. } = node {
+            before = balanced(&writes).map(|_| self.known().clone());
+            // Every iteration starts from what was known before the loop, minus what the body changes.
+            self.forget(&writes);
+        }
+        let mut body = self.known().clone();
+        body.set(0, None);
+        self.frames.push(Frame::Body {
+            known: body,
+            before,
+        });
+        Rewrite::Keep(node)
+    }
+
+    /// Emits an `If` with the body `nodes`, which ends with `body` known. A body that is known to run
+    /// replaces the `If`. What is known before is updated to after.
+    fn push_if(&mut self, nodes: Vec<Instruction>, body: KnownCells) -> Rewrite {
+        let known = self.known();
+        // The cell is not known to be zero, so if it is known at all the body runs.
+        if known.get(0).is_some() {
+            *known = body;
+            Rewrite::Replace(nodes)
+        } else {
+            *known = known.merge(&body);
+            Rewrite::Replace(vec![If { nodes }])
+        }
+    }
+
+    /// Forgets the cells changed by a body with `writes`.
+    fn forget(&mut self, writes: &Writes) {
+        match balanced(writes) {
+            Some(cells) => {
+                for offset in cells {
+                    self.known().set(*offset, None);
+                }
+            }
+            None => *self.known() = KnownCells::unknown(),
+        }
+    }
+}
+
+impl Rewriter for Propagate<'_> {
+    fn enter(&mut self, node: Instruction, _: &Context) -> Rewrite {
+        if let Some(Frame::Idiom) = self.frames.last() {
+            if node.body().is_some() {
+                self.frames.push(Frame::Idiom);
+            }
+            return Rewrite::Keep(node);
+        }
+        if node.body().is_some() {
+            return self.enter_body(node);
+        }
+        let wraps = self.pass.wraps();
+        let known = self.known();
+        match node {
+            Add { value } => match known.get(0) {
+                Some(cell) => {
+                    let cell = cell.wrapping_add_signed(value.0);
+                    known.set(0, Some(cell));
+                    Rewrite::Replace(vec![Set { value: cell }])
+                }
+                None => Rewrite::Keep(Add { value }),
+            },
+            Set { value } => {
+                if known.get(0) == Some(value) {
+                    return Rewrite::Replace(Vec::new());
+                }
+                known.set(0, Some(value));
+                Rewrite::Keep(Set { value })
+            }
+            Move { value } => {
+                if wraps {
+                    *known = KnownCells::unknown();
+                } else {
+                    known.pointer += value as i64;
+                }
+                Rewrite::Keep(Move { value })
+            }
+            MoveUnchecked { value } => {
+                known.pointer += value as i64;
+                Rewrite::Keep(MoveUnchecked { value })
+            }
+            Read => {
+                known.set(0, None);
+                Rewrite::Keep(Read)
+            }
+            Write => match known.get(0) {
+                Some(cell) => Rewrite::Replace(vec![WriteBytes { bytes: vec![cell] }]),
+                None => Rewrite::Keep(Write),
+            },
+            other => {
+                match &other {
+                    MulAdd { offset, .. } => known.set(*offset as i64, None),
+                    SetRange { offset, values } => {
+                        for (index, value) in values.iter().enumerate() {
+                            known.set(*offset as i64 + index as i64, Some(*value));
+                        }
+                    }
+                    AddVector { offset, values } => {
+                        for (index, value) in values.iter().enumerate() {
+                            let cell = *offset as i64 + index as i64;
+                            let sum = known.get(cell).map(|cell| cell.wrapping_add_signed(*value));
+                            known.set(cell, sum);
+                        }
+                    }
+                    _ => {}
+                }
+                Rewrite::Keep(other)
+            }
+        }
+    }
+
+    fn exit(&mut self, node: Instruction, _: &Context) -> Rewrite {
+        if node.body().is_none() {
+            return Rewrite::Keep(node);
+        }
+        let (body, before) = match self.frames.pop().unwrap() {
+            Frame::Body { known, before } => (known, before),
+            Frame::Idiom => return Rewrite::Keep(node),
+        };
+        match node {
+            Loop { nodes } => match before {
+                // A balanced body that zeroes the cell never iterates a second time.
+                Some(before) if body.get(0) == Some(0) => {
+                    *self.known() = before;
+                    self.push_if(nodes, body)
+                }
+                _ => {
+                    self.known().set(0, Some(0));
+                    Rewrite::Keep(Loop { nodes })
+                }
+            },
+            If { nodes } => self.push_if(nodes, body),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimize::peephole::{CombineIncrements, CombineSets, ReplaceSet};
+    use crate::optimize::tests::{interpret, CELLS};
+    use crate::syntax::parse;
+    use std::num::Wrapping;
+
+    /// Runs the passes `main` runs before `PropagateConstants` and the pass itself on `source`,
+    /// and checks that the result ends like `source` on `input`.
+    fn propagate(source: &str, input: &[u8]) -> Vec<Instruction> {
+        let nodes = parse(source).unwrap();
+        let expected = interpret(&nodes, input);
+        let nodes = CombineSets.optimize(ReplaceSet.optimize(CombineIncrements.optimize(nodes)));
+        let pass = PropagateConstants {
+            tape: TapeConfig::new(CELLS),
+        };
+        let nodes = pass.optimize(nodes);
+        assert_eq!(interpret(&nodes, input), expected, "{}", source);
+        nodes
+    }
+
+    #[test]
+    fn leading_comment_loop_is_removed() {
+        let nodes = propagate("[Prints 2. Ignores input, -> and <.]++.", b"");
+        assert!(nodes == vec![Set { value: 2 }, WriteBytes { bytes: vec![2] }]);
+    }
+
+    #[test]
+    fn adds_to_known_cells_become_sets() {
+        let nodes = propagate("+++>++<+,+", b"a");
+        let expected = vec![
+            Set { value: 3 },
+            Move { value: 1 },
+            Set { value: 2 },
+            Move { value: -1 },
+            Set { value: 4 },
+            Read,
+            // The cell is unknown after the read.
+            Add { value: Wrapping(1) },
+        ];
+        assert!(nodes == expected);
+    }
+
+    #[test]
+    fn set_after_loop_is_removed() {
+        let nodes = propagate(",[>+<-][-]>.", b"\x05");
+        let expected = vec![
+            Read,
+            Loop {
+                nodes: vec![
+                    Move { value: 1 },
+                    Add { value: Wrapping(1) },
+                    Move { value: -1 },
+                    Add {
+                        value: Wrapping(-1),
+                    },
+                ],
+            },
+            Move { value: 1 },
+            Write,
+        ];
+        assert!(nodes == expected);
+    }
+
+    #[test]
+    fn loop_that_zeroes_its_cell_becomes_if() {
+        for input in [b"\x00", b"\x03"] {
+            let nodes = propagate(",[>+<[-]]", input);
+            let expected = vec![
+                Read,
+                If {
+                    nodes: vec![
+                        Move { value: 1 },
+                        Add { value: Wrapping(1) },
+                        Move { value: -1 },
+                        Set { value: 0 },
+                    ],
+                },
+            ];
+            assert!(nodes == expected);
+        }
+    }
+
+    #[test]
+    fn loop_that_may_leave_its_cell_non_zero_stays() {
+        // Counts the cell down instead of clearing it.
+        for input in [b"\x00", b"\x03"] {
+            let nodes = propagate(",[>+<-]", input);
+            assert!(matches!(nodes[..], [Read, Loop { .. }]));
+        }
+        // Clears the cell, but ends on another one.
+        for input in [b"\x00", b"\x03"] {
+            let nodes = propagate(",[[-]>]", input);
+            assert!(matches!(nodes[..], [Read, Loop { .. }]));
+        }
+    }
+}
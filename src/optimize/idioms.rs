@@ -0,0 +1,294 @@
+use super::peephole::{CombineIncrements, CombineSets, ReplaceSet};
+use super::rewrite::{rewrite, Context, Rewrite, Rewriter};
+use super::OptimizationPass;
+use crate::syntax::Instruction::*;
+use crate::syntax::{self, Idiom, Instruction};
+
+/// Replaces well-known snippets with an `Idiom` that runs them in a single step.
+/// Patterns match the structure of the code exactly, apart from moves into and out of them,
+/// which may be merged with the moves around them. Matches are not searched for inside other matches.
+/// Must run right after the peephole passes, as patterns are normalized the same way.
+pub struct MatchIdioms {
+    /// Patterns and their idioms, in the order they are tried.
+    patterns: Vec<(Vec<Instruction>, Idiom)>,
+}
+
+/// A pattern found at the start of some nodes.
+struct Match<'a> {
+    pattern: &'a [Instruction],
+    idiom: Idiom,
+    /// The part of the first and last node that is outside the pattern.
+    before: i32,
+    after: i32,
+}
+
+impl Default for MatchIdioms {
+    /// The common idioms from the esolangs wiki.
+    fn default() -> Self {
+        let mut idioms = MatchIdioms::empty();
+        // Print-decimal contains a divmod, so it must be tried first.
+        idioms.register_source(
+            ">>++++++++++<<[->+>-[>+>>]>[+[-<+>]>+>>]<<<<<<]>>[-]>>>++++++++++<[->-[>+>>]>[+[-<+>]>+>>]<<<<<]>[-]>>[>++++++[-<++++++++>]<.<<+>+>[-]]<[<[->-<]++++++[->++++++++<]>.[-]]<<++++++[-<++++++++>]<.[-]<<[-<+>]<",
+            Idiom::PrintDecimal,
+        );
+        idioms.register_source("[->-[>+>>]>[+[-<+>]>+>>]<<<<<]", Idiom::DivMod);
+        idioms.register_source(
+            ">>[-]>[-]<<<[>>>+<<<-]+>[>>-<+<-]>[<+>-]>[<<<->>>[-]]<<<",
+            Idiom::Equal,
+        );
+        idioms.register_source("[->+>+<<]>>[-<<+>>]<<", Idiom::Copy);
+        idioms
+    }
+}
+
+impl MatchIdioms {
+    pub fn empty() -> Self {
+        MatchIdioms {
+            patterns: Vec::new(),
+        }
+    }
+
+    /// Replaces `pattern` with `idiom` from now on. The pattern must end where it started
+    /// and only use the cells of the idiom. Patterns that contain others must be registered first.
+    pub fn register(&mut self, pattern: Vec<Instruction>, idiom: Idiom) {
+        assert!(!pattern.is_empty(), "Empty pattern for {:?}", idiom);
+        self.patterns.push((pattern, idiom));
+    }
+
+    /// Like `register`, with the pattern given as source code.
+    pub fn register_source(&mut self, source: &str, idiom: Idiom) {
+        let nodes = syntax::parse(source).unwrap();
+        let nodes = CombineIncrements.optimize(nodes);
+        let nodes = ReplaceSet.optimize(nodes);
+        let nodes = CombineSets.optimize(nodes);
+        self.register(nodes, idiom);
+    }
+
+    /// The first pattern that matches at the start of `nodes`.
+    fn find(&self, nodes: &[Instruction]) -> Option<Match<'_>> {
+        self.patterns.iter().find_map(|(pattern, idiom)| {
+            let candidate = nodes.get(..pattern.len())?;
+            let (before, after) = match pattern.as_slice() {
+                [node] => (node == &candidate[0]).then_some((0, 0))?,
+                [first, middle @ .., last] => {
+                    let before = split_move(first, &candidate[0])?;
+                    let after = split_move(last, &candidate[candidate.len() - 1])?;
+                    (middle == &candidate[1..candidate.len() - 1]).then_some((before, after))?
+                }
+                [] => unreachable!(),
+            };
+            Some(Match {
+                pattern,
+                idiom: *idiom,
+                before,
+                after,
+            })
+        })
+    }
+}
+
+/// Compares a node of a pattern with one of the program. A move of the pattern also matches
+/// any move of the program, which is split into the move of the pattern and the rest.
+/// That only adds a stop on the cell the pattern starts and ends at. Returns the rest of the move.
+fn split_move(pattern: &Instruction, node: &Instruction) -> Option<i32> {
+    match (pattern, node) {
+        (Move { value: part }, Move { value: whole }) => Some(whole - part),
+        (pattern, node) => (pattern == node).then_some(0),
+    }
+}
+
+impl OptimizationPass for MatchIdioms {
+    fn optimize(&self, nodes: Vec<Instruction>) -> Vec<Instruction> {
+        rewrite(nodes, &mut &*self).nodes
+    }
+}
+
+impl Rewriter for &MatchIdioms {
+    fn sequence(
+        &mut self,
+        nodes: Vec<Instruction>,
+        context: &Context,
+    ) -> Rewrite<Vec<Instruction>> {
+        if let Some(Instruction::Idiom { .. }) = context.parent {
+            return Rewrite::Keep(nodes);
+        }
+        // Find all matches first, so that the nodes can be moved into the result afterwards.
+        let mut matches = Vec::new();
+        let mut index = 0;
+        while index < nodes.len() {
+            match self.find(&nodes[index..]) {
+                Some(found) => {
+                    let end = index + found.pattern.len();
+                    matches.push((index, found));
+                    index = end;
+                }
+                None => index += 1,
+            }
+        }
+        if matches.is_empty() {
+            return Rewrite::Keep(nodes);
+        }
+
+        let mut result = Vec::with_capacity(nodes.len());
+        let mut matches = matches.into_iter().peekable();
+        let mut nodes = nodes.into_iter().enumerate();
+        while let Some((index, node)) = nodes.next() {
+            let Some((_, found)) = matches.next_if(|(start, _)| *start == index) else {
+                result.push(node);
+                continue;
+            };
+            if found.before != 0 {
+                result.push(Move {
+                    value: found.before,
+                });
+            }
+            result.push(Instruction::Idiom {
+                idiom: found.idiom,
+                nodes: found.pattern.to_vec(),
+            });
+            if found.after != 0 {
+                result.push(Move { value: found.after });
+            }
+            nodes.by_ref().take(found.pattern.len() - 1).for_each(drop);
+        }
+        Rewrite::Replace(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution::interpreter::Interpreter;
+    use crate::execution::tape::{Tape, TapeConfig};
+    use crate::execution::{ExecutionLimits, RuntimeResultCode};
+
+    /// Interprets `nodes` on a tape of exactly `cells`, returning the tape and the output
+    /// if they finish within a step limit.
+    fn interpret(nodes: &[Instruction], cells: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        // Set the cells from the last one back to the first, so the pointer never leaves the tape.
+        let mut program = vec![Move {
+            value: cells.len() as i32 - 1,
+        }];
+        for (index, &value) in cells.iter().enumerate().rev() {
+            program.push(Set { value });
+            if index > 0 {
+                program.push(Move { value: -1 });
+            }
+        }
+        program.extend_from_slice(nodes);
+        let mut output = Vec::new();
+        let limits = ExecutionLimits {
+            max_steps: Some(1_000_000),
+            ..Default::default()
+        };
+        let mut interpreter: Interpreter = Interpreter::new(
+            Box::new(std::io::empty()),
+            Box::new(&mut output),
+            TapeConfig::new(cells.len()),
+            limits,
+        );
+        let result = interpreter.interpret(&program);
+        let tape = (0..cells.len())
+            .map(|index| interpreter.tape().get(index))
+            .collect();
+        drop(interpreter);
+        (result == RuntimeResultCode::Ok).then_some((tape, output))
+    }
+
+    /// Compares the registered snippet of `idiom` with `Idiom::apply` on `cells`.
+    fn check(idiom: Idiom, cells: &[u8]) {
+        let idioms = MatchIdioms::default();
+        let (pattern, _) = idioms.patterns.iter().find(|(_, i)| *i == idiom).unwrap();
+        let expected = interpret(pattern, cells);
+        let mut applied = cells.to_vec();
+        let mut output = Vec::new();
+        if idiom.apply(&mut applied, &mut output) {
+            assert_eq!(
+                expected,
+                Some((applied, output)),
+                "{:?} on {:?}",
+                idiom,
+                cells
+            );
+        } else {
+            assert_eq!(applied, cells, "{:?} on {:?}", idiom, cells);
+            assert!(output.is_empty());
+        }
+    }
+
+    #[test]
+    fn copy() {
+        check(Idiom::Copy, &[0, 0, 0]);
+        check(Idiom::Copy, &[5, 7, 0]);
+        check(Idiom::Copy, &[200, 100, 0]);
+        check(Idiom::Copy, &[3, 4, 9]);
+    }
+
+    #[test]
+    fn equal() {
+        check(Idiom::Equal, &[0, 0, 0, 0]);
+        check(Idiom::Equal, &[3, 3, 0, 0]);
+        check(Idiom::Equal, &[3, 4, 0, 0]);
+        check(Idiom::Equal, &[255, 0, 0, 0]);
+        check(Idiom::Equal, &[1, 1, 7, 9]);
+    }
+
+    #[test]
+    fn div_mod() {
+        check(Idiom::DivMod, &[17, 5, 0, 0, 0, 0]);
+        check(Idiom::DivMod, &[255, 255, 0, 0, 0, 0]);
+        check(Idiom::DivMod, &[100, 7, 0, 3, 0, 0]);
+        check(Idiom::DivMod, &[5, 3, 1, 0, 0, 0]);
+        // Dividing by zero divides by 256.
+        check(Idiom::DivMod, &[0, 0, 0, 0, 0, 0]);
+        check(Idiom::DivMod, &[17, 0, 0, 0, 0, 0]);
+        // Dividing anything but zero by one never ends.
+        check(Idiom::DivMod, &[0, 1, 0, 0, 0, 0]);
+        check(Idiom::DivMod, &[17, 1, 0, 0, 0, 0]);
+        assert!(!Idiom::DivMod.apply(&mut [17, 1, 0, 0, 0, 0], &mut Vec::new()));
+    }
+
+    #[test]
+    fn print_decimal() {
+        for value in [0, 7, 42, 100, 255] {
+            let mut cells = [0; 10];
+            cells[0] = value;
+            check(Idiom::PrintDecimal, &cells);
+        }
+        check(Idiom::PrintDecimal, &[42, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+        check(Idiom::PrintDecimal, &[42, 0, 0, 0, 0, 0, 0, 0, 0, 3]);
+    }
+
+    #[test]
+    fn surrounding_moves() {
+        let snippets = [
+            (">[->+>+<<]>>[-<<+>>]<<<", Idiom::Copy),
+            (
+                ">>>[-]>[-]<<<[>>>+<<<-]+>[>>-<+<-]>[<+>-]>[<<<->>>[-]]<<<<",
+                Idiom::Equal,
+            ),
+        ];
+        for (source, idiom) in snippets {
+            let nodes = syntax::parse(source).unwrap();
+            let nodes = CombineIncrements.optimize(nodes);
+            let nodes = ReplaceSet.optimize(nodes);
+            let nodes = CombineSets.optimize(nodes);
+            let optimized = MatchIdioms::default().optimize(nodes.clone());
+            assert!(matches!(
+                &optimized[..],
+                [Move { value: 1 }, Instruction::Idiom { idiom: found, .. }, Move { value: -1 }]
+                    if *found == idiom
+            ));
+            for cells in [
+                [0, 0, 0, 0, 0],
+                [0, 3, 3, 0, 0],
+                [0, 3, 4, 0, 0],
+                [0, 9, 1, 2, 0],
+            ] {
+                let expected = interpret(&nodes, &cells);
+                assert!(expected.is_some());
+                assert_eq!(interpret(&optimized, &cells), expected, "{:?}", cells);
+            }
+        }
+    }
+}
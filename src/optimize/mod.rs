@@ -1,4 +1,11 @@
+pub mod bounds;
+pub mod closed_form;
+pub mod coalesce;
+pub mod constants;
+pub mod idioms;
 pub mod peephole;
+pub mod prefix;
+pub mod rewrite;
 
 use crate::syntax::Instruction;
 
@@ -6,42 +13,76 @@ pub trait OptimizationPass {
     fn optimize(&self, nodes: Vec<Instruction>) -> Vec<Instruction>;
 }
 
-trait MapLoopsExt: Iterator<Item = Instruction> {
-    fn map_loops<O>(self, optimizer: O) -> MapLoops<Self, O>
-    where
-        O: OptimizationPass,
-        Self: Sized,
-    {
-        MapLoops {
-            iter: self,
-            optimizer,
-        }
+#[cfg(test)]
+mod tests {
+    use super::bounds::{tape_footprint, EliminateBoundsChecks};
+    use super::closed_form::SolveCountedLoops;
+    use super::coalesce::CoalesceStores;
+    use super::constants::PropagateConstants;
+    use super::idioms::MatchIdioms;
+    use super::peephole::*;
+    use super::prefix::EvaluatePrefix;
+    use super::*;
+    use crate::execution::interpreter::Interpreter;
+    use crate::execution::tape::{Tape, TapeConfig};
+    use crate::execution::{ExecutionLimits, RuntimeResultCode};
+    use crate::syntax::{drop_nodes, parse};
+
+    /// The tape size `interpret` runs with.
+    pub(super) const CELLS: usize = 16;
+
+    /// Interprets `nodes` on a zeroed tape, returning the tape and the output.
+    pub(super) fn interpret(nodes: &[Instruction], input: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut output = Vec::new();
+        let mut interpreter: Interpreter = Interpreter::new(
+            Box::new(input),
+            Box::new(&mut output),
+            TapeConfig::new(CELLS),
+            ExecutionLimits::default(),
+        );
+        assert_eq!(interpreter.interpret(nodes), RuntimeResultCode::Ok);
+        let tape = (0..CELLS)
+            .map(|index| interpreter.tape().get(index))
+            .collect();
+        drop(interpreter);
+        (tape, output)
     }
-}
 
-struct MapLoops<I, O> {
-    iter: I,
-    optimizer: O,
-}
+    /// Runs every pass of `-o` in the order `main` does.
+    fn optimize(nodes: Vec<Instruction>) -> Vec<Instruction> {
+        let tape = TapeConfig::new(30000);
+        let nodes = CombineIncrements.optimize(nodes);
+        let nodes = ReplaceSet.optimize(nodes);
+        let nodes = CombineSets.optimize(nodes);
+        let nodes = MatchIdioms::default().optimize(nodes);
+        tape_footprint(&nodes);
+        let nodes = EvaluatePrefix { tape, input: &[] }.optimize(nodes);
+        let nodes = PropagateConstants { tape }.optimize(nodes);
+        let nodes = EliminateBoundsChecks { tape }.optimize(nodes);
+        let nodes = SolveCountedLoops.optimize(nodes);
+        CoalesceStores.optimize(nodes)
+    }
+
+    #[test]
+    fn deep_nest() {
+        let depth = 100_000;
+        let nest = format!("{}-{}.", "[".repeat(depth), "]".repeat(depth));
 
-impl<I, O> Iterator for MapLoops<I, O>
-where
-    I: Iterator<Item = Instruction>,
-    O: OptimizationPass,
-{
-    type Item = Instruction;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|instr| match instr {
-            Instruction::Loop { nodes } => {
-                let mapped_nodes = self.optimizer.optimize(nodes);
-                Instruction::Loop {
-                    nodes: mapped_nodes,
-                }
-            }
-            other => other,
-        })
+        // Reading input keeps the nest; its body always ends at zero so the
+        // outer loop becomes an `If`.
+        let nodes = optimize(parse(&format!(",{}", nest)).unwrap());
+        let kept = matches!(
+            nodes[..],
+            [
+                Instruction::Read,
+                Instruction::If { .. },
+                Instruction::Write
+            ]
+        );
+        drop_nodes(nodes);
+        assert!(kept);
+        // A known cell lets `EvaluatePrefix` run all of it.
+        let nodes = optimize(parse(&format!("+{}", nest)).unwrap());
+        assert!(nodes == vec![Instruction::WriteBytes { bytes: vec![0] }]);
     }
 }
-
-impl<I> MapLoopsExt for I where I: Iterator<Item = Instruction> {}
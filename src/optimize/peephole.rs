@@ -1,4 +1,5 @@
-use super::{MapLoopsExt, OptimizationPass};
+use super::rewrite::{rewrite, Context, Rewrite, Rewriter};
+use super::OptimizationPass;
 use crate::syntax::Instruction;
 use crate::syntax::Instruction::*;
 use itertools::Itertools;
@@ -7,15 +8,22 @@ pub struct CombineIncrements;
 
 impl OptimizationPass for CombineIncrements {
     fn optimize(&self, nodes: Vec<Instruction>) -> Vec<Instruction> {
-        nodes
+        rewrite(nodes, &mut CombineIncrements).nodes
+    }
+}
+
+impl Rewriter for CombineIncrements {
+    fn sequence(&mut self, nodes: Vec<Instruction>, _: &Context) -> Rewrite<Vec<Instruction>> {
+        let len = nodes.len();
+        let nodes = nodes
             .into_iter()
             .coalesce(|prev, current| match (prev, current) {
                 (Add { value: a }, Add { value: b }) => Ok(Add { value: a + b }),
                 (Move { value: a }, Move { value: b }) => Ok(Move { value: a + b }),
                 (a, b) => Err((a, b)),
             })
-            .map_loops(Self)
-            .collect()
+            .collect();
+        Rewrite::resized(len, nodes)
     }
 }
 
@@ -23,24 +31,24 @@ pub struct ReplaceSet;
 
 impl OptimizationPass for ReplaceSet {
     fn optimize(&self, nodes: Vec<Instruction>) -> Vec<Instruction> {
-        nodes
-            .into_iter()
-            .map(|instr| {
-                if let Loop { ref nodes } = instr {
-                    if nodes.len() == 1 {
-                        let inner = &nodes[0];
-                        if let Add { value } = inner {
-                            let value = value.0;
-                            if value == -1 || value == 1 {
-                                return Set { value: 0 };
-                            }
-                        }
+        rewrite(nodes, &mut ReplaceSet).nodes
+    }
+}
+
+impl Rewriter for ReplaceSet {
+    fn enter(&mut self, node: Instruction, _: &Context) -> Rewrite {
+        if let Loop { ref nodes } = node {
+            if nodes.len() == 1 {
+                let inner = &nodes[0];
+                if let Add { value } = inner {
+                    let value = value.0;
+                    if value == -1 || value == 1 {
+                        return Rewrite::Replace(vec![Set { value: 0 }]);
                     }
                 }
-                instr
-            })
-            .map_loops(Self)
-            .collect()
+            }
+        }
+        Rewrite::Keep(node)
     }
 }
 
@@ -51,7 +59,14 @@ pub struct CombineSets;
 
 impl OptimizationPass for CombineSets {
     fn optimize(&self, nodes: Vec<Instruction>) -> Vec<Instruction> {
-        nodes
+        rewrite(nodes, &mut CombineSets).nodes
+    }
+}
+
+impl Rewriter for CombineSets {
+    fn sequence(&mut self, nodes: Vec<Instruction>, _: &Context) -> Rewrite<Vec<Instruction>> {
+        let len = nodes.len();
+        let nodes = nodes
             .into_iter()
             .coalesce(|prev, current| match (prev, current) {
                 (Set { value: a }, Set { value: b }) => Ok(Set { value: a + b }),
@@ -61,7 +76,7 @@ impl OptimizationPass for CombineSets {
                 (Add { value: _ }, Set { value: b }) => Ok(Set { value: b }),
                 (a, b) => Err((a, b)),
             })
-            .map_loops(Self)
-            .collect()
+            .collect();
+        Rewrite::resized(len, nodes)
     }
 }
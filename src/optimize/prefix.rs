@@ -0,0 +1,301 @@
+use super::OptimizationPass;
+use crate::execution::tape::{TapeBounds, TapeConfig};
+use crate::syntax::Instruction::*;
+use crate::syntax::{drop_nodes, Instruction};
+use std::collections::HashMap;
+
+/// The number of instructions evaluated before giving up on the rest of the program.
+const STEP_BUDGET: u64 = 1_000_000;
+
+/// Zero cells at most this long are written as part of a `SetRange` instead of splitting it.
+const MAX_ZERO_GAP: usize = 16;
+
+/// Why evaluation stopped before the end of the program.
+struct Stop;
+
+/// Runs the start of the program at compile time, up to the first instruction that depends on
+/// input not in `input` or that would fail at runtime. The evaluated prefix is replaced by
+/// the tape it leaves behind and the output it writes.
+/// Must run before all other passes that need to know the tape, as it expects the initial, zeroed tape.
+pub struct EvaluatePrefix<'a> {
+    pub tape: TapeConfig,
+    /// The input the program is assumed to read, from the beginning.
+    pub input: &'a [u8],
+}
+
+impl OptimizationPass for EvaluatePrefix<'_> {
+    fn optimize(&self, nodes: Vec<Instruction>) -> Vec<Instruction> {
+        self.evaluate(nodes).0
+    }
+}
+
+impl EvaluatePrefix<'_> {
+    /// Returns the program with its prefix evaluated and the number of input bytes the prefix read.
+    pub fn evaluate(&self, nodes: Vec<Instruction>) -> (Vec<Instruction>, usize) {
+        let start = self.tape.start_offset();
+        let mut machine = Machine {
+            tape: self.tape,
+            input: self.input,
+            cells: HashMap::new(),
+            pointer: start,
+            input_position: 0,
+            output: Vec::new(),
+            steps: 0,
+            undo: Vec::new(),
+        };
+
+        // The nodes left to run. A loop stays in front if only some of its iterations were evaluated.
+        let mut rest = nodes.into_iter().peekable();
+        let mut evaluated = false;
+        while let Some(node) = rest.peek() {
+            let checkpoint = machine.checkpoint();
+            let result = match node {
+                Loop { nodes } => machine.iteration(nodes),
+                node => machine.run(std::slice::from_ref(node)).map(|_| false),
+            };
+            match result {
+                Ok(true) => {}
+                Ok(false) => {
+                    drop_nodes(rest.next().into_iter().collect());
+                }
+                Err(Stop) => {
+                    machine.rollback(checkpoint);
+                    break;
+                }
+            }
+            machine.undo.clear();
+            evaluated = true;
+        }
+        if !evaluated {
+            return (rest.collect(), 0);
+        }
+
+        let mut result = machine.initialization(start);
+        if !machine.output.is_empty() {
+            result.push(WriteBytes {
+                bytes: machine.output,
+            });
+        }
+        let delta = machine.pointer as i64 - start as i64;
+        if delta != 0 {
+            result.push(MoveUnchecked {
+                value: delta as i32,
+            });
+        }
+        result.extend(rest);
+        (result, machine.input_position)
+    }
+}
+
+/// Where evaluation can be rolled back to.
+struct Checkpoint {
+    pointer: usize,
+    input_position: usize,
+    output_len: usize,
+}
+
+/// What is left to run of a body in `Machine::run`.
+enum Frame<'a> {
+    /// The nodes of a body that did not run yet.
+    Nodes(std::slice::Iter<'a, Instruction>),
+    /// A loop that checks its cell for the next iteration.
+    Loop(&'a [Instruction]),
+}
+
+/// A concrete execution of the program on a tape that starts zeroed.
+struct Machine<'a> {
+    tape: TapeConfig,
+    input: &'a [u8],
+    /// The cells that were written. Every other cell is zero.
+    cells: HashMap<usize, u8>,
+    pointer: usize,
+    input_position: usize,
+    output: Vec<u8>,
+    steps: u64,
+    /// The previous values of the cells written since the last checkpoint.
+    undo: Vec<(usize, u8)>,
+}
+
+impl Machine<'_> {
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            pointer: self.pointer,
+            input_position: self.input_position,
+            output_len: self.output.len(),
+        }
+    }
+
+    fn rollback(&mut self, checkpoint: Checkpoint) {
+        for (cell, value) in self.undo.drain(..).rev() {
+            self.cells.insert(cell, value);
+        }
+        self.pointer = checkpoint.pointer;
+        self.input_position = checkpoint.input_position;
+        self.output.truncate(checkpoint.output_len);
+    }
+
+    fn get(&self, offset: i32) -> Result<u8, Stop> {
+        let cell = self.cell(offset)?;
+        Ok(self.cells.get(&cell).copied().unwrap_or(0))
+    }
+
+    fn set(&mut self, offset: i32, value: u8) -> Result<(), Stop> {
+        let cell = self.cell(offset)?;
+        let previous = self.cells.insert(cell, value).unwrap_or(0);
+        self.undo.push((cell, previous));
+        Ok(())
+    }
+
+    /// The cell at `offset` from the pointer, if it is on the tape.
+    fn cell(&self, offset: i32) -> Result<usize, Stop> {
+        let cell = self.pointer as i64 + offset as i64;
+        if (0..self.tape.size as i64).contains(&cell) {
+            Ok(cell as usize)
+        } else {
+            Err(Stop)
+        }
+    }
+
+    /// Moves the pointer, unless it would leave the tape. Wrapping moves wrap around the tape,
+    /// growing the tape to the left is left to runtime.
+    fn shift(&mut self, value: i32) -> Result<(), Stop> {
+        let size = self.tape.size as i64;
+        let moved = self.pointer as i64 + value as i64;
+        self.pointer = match self.tape.bounds {
+            TapeBounds::Wrap => moved.rem_euclid(size) as usize,
+            TapeBounds::Error if (0..size).contains(&moved) => moved as usize,
+            TapeBounds::Error => return Err(Stop),
+        };
+        Ok(())
+    }
+
+    /// Runs one iteration of a loop. Returns whether the loop body ran.
+    fn iteration(&mut self, nodes: &[Instruction]) -> Result<bool, Stop> {
+        self.step()?;
+        if self.get(0)? == 0 {
+            return Ok(false);
+        }
+        self.run(nodes)?;
+        Ok(true)
+    }
+
+    fn step(&mut self) -> Result<(), Stop> {
+        self.steps += 1;
+        if self.steps > STEP_BUDGET {
+            return Err(Stop);
+        }
+        Ok(())
+    }
+
+    /// Runs `nodes`. Keeps its own stack of the bodies being run, so deep nesting does not overflow.
+    fn run(&mut self, nodes: &[Instruction]) -> Result<(), Stop> {
+        let mut stack = vec![Frame::Nodes(nodes.iter())];
+        while let Some(frame) = stack.last_mut() {
+            let node = match frame {
+                Frame::Nodes(pending) => match pending.next() {
+                    Some(node) => node,
+                    None => {
+                        stack.pop();
+                        continue;
+                    }
+                },
+                Frame::Loop(nodes) => {
+                    let nodes = *nodes;
+                    self.step()?;
+                    if self.get(0)? == 0 {
+                        stack.pop();
+                    } else {
+                        stack.push(Frame::Nodes(nodes.iter()));
+                    }
+                    continue;
+                }
+            };
+            self.step()?;
+            match node {
+                Add { value } => {
+                    let cell = self.get(0)?;
+                    self.set(0, cell.wrapping_add_signed(value.0))?;
+                }
+                Set { value } => self.set(0, *value)?,
+                Move { value } | MoveUnchecked { value } => self.shift(*value)?,
+                Loop { nodes } => stack.push(Frame::Loop(nodes)),
+                If { nodes } => {
+                    if self.get(0)? != 0 {
+                        stack.push(Frame::Nodes(nodes.iter()));
+                    }
+                }
+                Idiom { nodes, .. } => stack.push(Frame::Nodes(nodes.iter())),
+                Write => {
+                    let cell = self.get(0)?;
+                    self.output.push(cell);
+                }
+                WriteBytes { bytes } => self.output.extend(bytes),
+                Read => {
+                    let Some(byte) = self.input.get(self.input_position) else {
+                        return Err(Stop);
+                    };
+                    self.input_position += 1;
+                    self.set(0, *byte)?;
+                }
+                MulAdd { offset, terms } => {
+                    let mut sum = 0u8;
+                    for term in terms {
+                        let mut product = term.coefficient;
+                        for factor in &term.factors {
+                            product = product.wrapping_mul(self.get(*factor)?);
+                        }
+                        sum = sum.wrapping_add(product);
+                    }
+                    let cell = self.get(*offset)?;
+                    self.set(*offset, cell.wrapping_add(sum))?;
+                }
+                SetRange { offset, values } => {
+                    for (index, value) in values.iter().enumerate() {
+                        self.set(offset + index as i32, *value)?;
+                    }
+                }
+                AddVector { offset, values } => {
+                    for (index, value) in values.iter().enumerate() {
+                        let cell = self.get(offset + index as i32)?;
+                        self.set(offset + index as i32, cell.wrapping_add_signed(*value))?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// `SetRange`s that write the non-zero cells, relative to `start`.
+    fn initialization(&self, start: usize) -> Vec<Instruction> {
+        let mut cells: Vec<_> = self
+            .cells
+            .iter()
+            .filter(|(_, value)| **value != 0)
+            .map(|(cell, value)| (*cell, *value))
+            .collect();
+        cells.sort_unstable();
+
+        let mut result = Vec::new();
+        let mut current: Option<(usize, Vec<u8>)> = None;
+        for (cell, value) in cells {
+            match &mut current {
+                Some((first, values)) if cell - (*first + values.len()) <= MAX_ZERO_GAP => {
+                    values.resize(cell - *first, 0);
+                    values.push(value);
+                }
+                _ => {
+                    result.extend(current.take().map(|(first, values)| SetRange {
+                        offset: (first as i64 - start as i64) as i32,
+                        values,
+                    }));
+                    current = Some((cell, vec![value]));
+                }
+            }
+        }
+        result.extend(current.map(|(first, values)| SetRange {
+            offset: (first as i64 - start as i64) as i32,
+            values,
+        }));
+        result
+    }
+}
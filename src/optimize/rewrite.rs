@@ -0,0 +1,209 @@
+use crate::syntax::Instruction;
+
+/// Where the nodes being rewritten are in the program.
+pub struct Context<'a> {
+    /// The node whose body the nodes are in, or `None` at the top level.
+    /// Its body is taken out while it is rewritten, so it is empty here.
+    pub parent: Option<&'a Instruction>,
+    /// The number of bodies the nodes are nested in.
+    pub depth: usize,
+}
+
+/// What a `Rewriter` does with a node, or with a sequence of nodes.
+pub enum Rewrite<T = Instruction> {
+    /// Leaves it unchanged.
+    Keep(T),
+    /// Replaces it with any number of nodes. Counts as a change.
+    Replace(Vec<Instruction>),
+}
+
+impl Rewrite<Vec<Instruction>> {
+    /// `Replace` if `nodes` has a different length than the `len` nodes it was made from.
+    /// Enough for rewrites that only merge or drop nodes.
+    pub fn resized(len: usize, nodes: Vec<Instruction>) -> Self {
+        if nodes.len() == len {
+            Rewrite::Keep(nodes)
+        } else {
+            Rewrite::Replace(nodes)
+        }
+    }
+}
+
+/// Hooks into a traversal of the program by `rewrite`. Every hook keeps what it is given by default.
+pub trait Rewriter {
+    /// Rewrites the nodes of a body, or the top level, before any of them is visited.
+    fn sequence(&mut self, nodes: Vec<Instruction>, _: &Context) -> Rewrite<Vec<Instruction>> {
+        Rewrite::Keep(nodes)
+    }
+
+    /// Rewrites a node before its body is visited. The nodes it is replaced with are not entered again,
+    /// but their bodies are visited instead.
+    fn enter(&mut self, node: Instruction, _: &Context) -> Rewrite {
+        Rewrite::Keep(node)
+    }
+
+    /// Rewrites a node after its body was visited. The nodes it is replaced with are not visited again.
+    fn exit(&mut self, node: Instruction, _: &Context) -> Rewrite {
+        Rewrite::Keep(node)
+    }
+}
+
+/// The result of `rewrite`.
+pub struct Rewritten {
+    pub nodes: Vec<Instruction>,
+    /// The number of times a hook replaced something.
+    pub changes: usize,
+}
+
+/// A body that is being rewritten.
+struct Frame {
+    /// The node the body belongs to, with the body taken out. `None` for the top level.
+    parent: Option<Instruction>,
+    /// Nodes that were not visited yet.
+    pending: std::vec::IntoIter<Instruction>,
+    /// Nodes that were entered, in reverse, whose bodies are visited before the next pending node.
+    entered: Vec<Instruction>,
+    rewritten: Vec<Instruction>,
+}
+
+/// Visits every node of `nodes` with `rewriter`, bodies included, in pre-order with `Rewriter::enter`
+/// and in post-order with `Rewriter::exit`. Keeps its own stack, so deep nesting does not overflow.
+pub fn rewrite(nodes: Vec<Instruction>, rewriter: &mut impl Rewriter) -> Rewritten {
+    let mut changes = 0;
+    let context = Context {
+        parent: None,
+        depth: 0,
+    };
+    let nodes = apply(rewriter.sequence(nodes, &context), &mut changes);
+    let mut stack = vec![Frame {
+        parent: None,
+        pending: nodes.into_iter(),
+        entered: Vec::new(),
+        rewritten: Vec::new(),
+    }];
+
+    loop {
+        let depth = stack.len() - 1;
+        let frame = stack.last_mut().unwrap();
+        let mut node = match frame.entered.pop() {
+            Some(node) => node,
+            None => match frame.pending.next() {
+                Some(node) => {
+                    let context = Context {
+                        parent: frame.parent.as_ref(),
+                        depth,
+                    };
+                    match rewriter.enter(node, &context) {
+                        Rewrite::Keep(node) => node,
+                        Rewrite::Replace(nodes) => {
+                            changes += 1;
+                            frame.entered.extend(nodes.into_iter().rev());
+                            continue;
+                        }
+                    }
+                }
+                // The body is done, so the node it belongs to can be exited.
+                None => {
+                    let frame = stack.pop().unwrap();
+                    let Some(mut node) = frame.parent else {
+                        return Rewritten {
+                            nodes: frame.rewritten,
+                            changes,
+                        };
+                    };
+                    *node.body_mut().unwrap() = frame.rewritten;
+                    exit(
+                        rewriter,
+                        node,
+                        stack.last_mut().unwrap(),
+                        depth - 1,
+                        &mut changes,
+                    );
+                    continue;
+                }
+            },
+        };
+
+        match node.body_mut().map(std::mem::take) {
+            Some(body) => {
+                let context = Context {
+                    parent: Some(&node),
+                    depth: depth + 1,
+                };
+                let body = apply(rewriter.sequence(body, &context), &mut changes);
+                stack.push(Frame {
+                    parent: Some(node),
+                    pending: body.into_iter(),
+                    entered: Vec::new(),
+                    rewritten: Vec::new(),
+                });
+            }
+            None => exit(rewriter, node, frame, depth, &mut changes),
+        }
+    }
+}
+
+/// Exits `node`, whose body is done, and adds the result to `frame`.
+fn exit(
+    rewriter: &mut impl Rewriter,
+    node: Instruction,
+    frame: &mut Frame,
+    depth: usize,
+    changes: &mut usize,
+) {
+    let context = Context {
+        parent: frame.parent.as_ref(),
+        depth,
+    };
+    match rewriter.exit(node, &context) {
+        Rewrite::Keep(node) => frame.rewritten.push(node),
+        Rewrite::Replace(nodes) => {
+            *changes += 1;
+            frame.rewritten.extend(nodes);
+        }
+    }
+}
+
+/// The nodes of a sequence after its rewrite.
+fn apply(rewrite: Rewrite<Vec<Instruction>>, changes: &mut usize) -> Vec<Instruction> {
+    match rewrite {
+        Rewrite::Keep(nodes) => nodes,
+        Rewrite::Replace(nodes) => {
+            *changes += 1;
+            nodes
+        }
+    }
+}
+
+/// Computes a value for `nodes` and for every body in them, bottom-up without recursion.
+/// Every value starts as `init`, and `apply` adds a node to it, given the value of the node's body if it has one.
+/// Returns the value of `nodes` and the values of all bodies in the order their nodes start.
+pub fn fold_bodies<T: Clone>(
+    nodes: &[Instruction],
+    init: T,
+    mut apply: impl FnMut(&mut T, &Instruction, Option<&T>),
+) -> (T, Vec<T>) {
+    let mut values = Vec::new();
+    // The bodies being folded, with the node they belong to and its index in `values`.
+    let mut stack = vec![(None, nodes.iter(), init.clone())];
+    loop {
+        let (_, pending, value) = stack.last_mut().unwrap();
+        match pending.next() {
+            Some(node) => match node.body() {
+                Some(body) => {
+                    stack.push((Some((node, values.len())), body.iter(), init.clone()));
+                    values.push(None);
+                }
+                None => apply(value, node, None),
+            },
+            None => {
+                let (parent, _, body) = stack.pop().unwrap();
+                let Some((node, index)) = parent else {
+                    return (body, values.into_iter().map(Option::unwrap).collect());
+                };
+                apply(&mut stack.last_mut().unwrap().2, node, Some(&body));
+                values[index] = Some(body);
+            }
+        }
+    }
+}
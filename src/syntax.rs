@@ -1,22 +1,231 @@
-use std::num::Wrapping;
+use std::{fmt, num::Wrapping};
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum Instruction {
-    Add { value: Wrapping<i8> },
-    Move { value: i32 },
-    Loop { nodes: Vec<Instruction> },
+    Add {
+        value: Wrapping<i8>,
+    },
+    Move {
+        value: i32,
+    },
+    Loop {
+        nodes: Vec<Instruction>,
+    },
     Write,
     Read,
 
     // Optimization nodes
-    Set { value: u8 },
+    Set {
+        value: u8,
+    },
+    /// A move that is known to stay within the tape.
+    MoveUnchecked {
+        value: i32,
+    },
+    /// Sets the cells from `offset` relative to the pointer on to `values`. They are known to be in the tape.
+    SetRange {
+        offset: i32,
+        values: Vec<u8>,
+    },
+    /// Adds `values` to the cells from `offset` relative to the pointer on. They are known to be in the tape.
+    AddVector {
+        offset: i32,
+        values: Vec<i8>,
+    },
+    /// Writes constant output.
+    WriteBytes {
+        bytes: Vec<u8>,
+    },
+    /// A loop whose body always leaves the pointer on a zero cell where it started, so it runs at most once.
+    If {
+        nodes: Vec<Instruction>,
+    },
+    /// Adds the sum of `terms` to the cell at `offset` relative to the pointer. All cells are known to be in the tape.
+    MulAdd {
+        offset: i32,
+        terms: Vec<Term>,
+    },
+    /// Does what `nodes` do in a single step, unless `idiom` does not apply to the cells.
+    /// `nodes` end where they started and only use the cells of the idiom.
+    Idiom {
+        idiom: Idiom,
+        nodes: Vec<Instruction>,
+    },
 }
 
-pub fn parse(str: &str) -> Result<Vec<Instruction>, ()> {
+impl Instruction {
+    /// The nodes nested in this one, for the kinds of nodes that have a body.
+    pub fn body(&self) -> Option<&Vec<Instruction>> {
+        match self {
+            Instruction::Loop { nodes }
+            | Instruction::If { nodes }
+            | Instruction::Idiom { nodes, .. } => Some(nodes),
+            _ => None,
+        }
+    }
+
+    /// Like `body`, but mutable.
+    pub fn body_mut(&mut self) -> Option<&mut Vec<Instruction>> {
+        match self {
+            Instruction::Loop { nodes }
+            | Instruction::If { nodes }
+            | Instruction::Idiom { nodes, .. } => Some(nodes),
+            _ => None,
+        }
+    }
+}
+
+/// A constant times the product of the cells at `factors` relative to the pointer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Term {
+    pub coefficient: u8,
+    pub factors: Vec<i32>,
+}
+
+impl Term {
+    /// The sum of `terms`, given the cell at every offset.
+    pub fn sum(terms: &[Term], mut cell: impl FnMut(i32) -> u8) -> u8 {
+        terms
+            .iter()
+            .fold(0, |sum, term| sum.wrapping_add(term.evaluate(&mut cell)))
+    }
+
+    /// The value of the term, given the cell at every offset.
+    pub fn evaluate(&self, mut cell: impl FnMut(i32) -> u8) -> u8 {
+        self.factors
+            .iter()
+            .fold(self.coefficient, |product, factor| {
+                product.wrapping_mul(cell(*factor))
+            })
+    }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.coefficient)?;
+        for factor in &self.factors {
+            write!(f, "*[{}]", factor)?;
+        }
+        Ok(())
+    }
+}
+
+/// A well-known snippet of code that can run as a single step.
+/// Passed to the runtime by generated code, hence the fixed representation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Idiom {
+    /// Adds the cell to the next one without clearing it, using the one after that as a temporary.
+    Copy,
+    /// Sets the cell to whether it equals the next one, using the two after that as temporaries.
+    Equal,
+    /// Divides the cell by the next one, the esolangs divmod algorithm.
+    DivMod,
+    /// Writes the cell in decimal, using the nine after it as temporaries.
+    PrintDecimal,
+}
+
+impl Idiom {
+    /// The largest width of any idiom.
+    pub const MAX_WIDTH: usize = 10;
+
+    /// The number of cells from the pointer on that the idiom uses.
+    pub fn width(self) -> usize {
+        match self {
+            Idiom::Copy => 3,
+            Idiom::Equal => 4,
+            Idiom::DivMod => 6,
+            Idiom::PrintDecimal => 10,
+        }
+    }
+
+    /// Applies the idiom to `cells`, which start at the pointer and span its width, and appends its output.
+    /// Returns false without changing anything if the snippet would do something else with these cells.
+    pub fn apply(self, cells: &mut [u8], output: &mut Vec<u8>) -> bool {
+        match self {
+            Idiom::Copy => {
+                let value = cells[0];
+                cells[0] = value.wrapping_add(cells[2]);
+                cells[1] = cells[1].wrapping_add(value);
+                cells[2] = 0;
+            }
+            Idiom::Equal => {
+                cells[0] = (cells[0] == cells[1]) as u8;
+                cells[2] = 0;
+                cells[3] = 0;
+            }
+            Idiom::DivMod => {
+                let (dividend, divisor) = (cells[0] as u16, cells[1] as u16);
+                // Dividing by one never ends, the quotient is kept in a single cell.
+                if cells[2] != 0
+                    || cells[4] != 0
+                    || cells[5] != 0
+                    || (divisor == 1 && dividend != 0)
+                {
+                    return false;
+                }
+                // Counting the divisor down from zero wraps, so it divides by 256.
+                let divisor = if divisor == 0 { 256 } else { divisor };
+                cells[0] = 0;
+                cells[1] = (divisor - dividend % divisor) as u8;
+                cells[2] = (dividend % divisor) as u8;
+                cells[3] = cells[3].wrapping_add((dividend / divisor) as u8);
+            }
+            Idiom::PrintDecimal => {
+                if cells[1..].iter().any(|cell| *cell != 0) {
+                    return false;
+                }
+                let value = cells[0];
+                if value >= 100 {
+                    output.push(b'0' + value / 100);
+                }
+                if value >= 10 {
+                    output.push(b'0' + value / 10 % 10);
+                }
+                output.push(b'0' + value % 10);
+            }
+        }
+        true
+    }
+}
+
+/// Drops `nodes` one body at a time. Dropping them as usual recurses once per level of nesting,
+/// which overflows the stack on deeply nested programs.
+pub fn drop_nodes(mut nodes: Vec<Instruction>) {
+    while let Some(mut node) = nodes.pop() {
+        if let Some(body) = node.body_mut() {
+            nodes.append(body);
+        }
+    }
+}
+
+/// A bracket without a partner, with its 1-based source position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    UnmatchedOpen { line: usize, column: usize },
+    UnmatchedClose { line: usize, column: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnmatchedOpen { line, column } => {
+                write!(f, "unmatched [ at line {}, column {}", line, column)
+            }
+            ParseError::UnmatchedClose { line, column } => {
+                write!(f, "unmatched ] at line {}, column {}", line, column)
+            }
+        }
+    }
+}
+
+pub fn parse(str: &str) -> Result<Vec<Instruction>, ParseError> {
     let mut nodes = Vec::new();
     let mut stack = Vec::new();
+    let (mut line, mut column) = (1, 0);
 
     for char in str.chars() {
+        column += 1;
         match char {
             '+' => {
                 nodes.push(Instruction::Add { value: Wrapping(1) });
@@ -39,54 +248,160 @@ pub fn parse(str: &str) -> Result<Vec<Instruction>, ()> {
                 nodes.push(Instruction::Read);
             }
             '[' => {
-                stack.push(nodes);
+                stack.push((nodes, line, column));
                 nodes = vec![]
             }
             ']' => {
                 match stack.pop() {
-                    Some(mut parent) => {
+                    Some((mut parent, _, _)) => {
                         parent.push(Instruction::Loop { nodes });
                         nodes = parent;
                     }
-                    None => panic!("No matching ["),
+                    None => return Err(ParseError::UnmatchedClose { line, column }),
                 };
             }
+            '\n' => {
+                line += 1;
+                column = 0;
+            }
             _ => {
                 // Comment
             }
         }
     }
 
-    Ok(nodes)
+    match stack.pop() {
+        Some((_, line, column)) => Err(ParseError::UnmatchedOpen { line, column }),
+        None => Ok(nodes),
+    }
 }
 
 pub fn indented(instrs: &Vec<Instruction>, indent: usize) -> String {
     let mut result = String::new();
 
     for instr in instrs {
-        result.push_str(&" ".repeat(indent));
-        match instr {
-            Instruction::Add { value } => {
-                result.push_str(&format!("Add {}\n", value.0));
+        result.push_str(&format!("{}{}\n", " ".repeat(indent), instr));
+        if let Some(nodes) = instr.body() {
+            result.push_str(&indented(nodes, indent + 4));
+        }
+    }
+
+    result
+}
+
+/// The instruction on a single line, without its body.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Add { value } => write!(f, "Add {}", value.0),
+            Instruction::Move { value } => write!(f, "Move {}", value),
+            Instruction::Loop { .. } => write!(f, "Loop"),
+            Instruction::If { .. } => write!(f, "If"),
+            Instruction::MulAdd { offset, terms } => {
+                let terms: Vec<_> = terms.iter().map(|term| term.to_string()).collect();
+                write!(f, "MulAdd {} {}", offset, terms.join(" + "))
             }
-            Instruction::Move { value } => {
-                result.push_str(&format!("Move {}\n", value));
+            Instruction::Idiom { idiom, .. } => write!(f, "Idiom {:?}", idiom),
+            Instruction::Write => write!(f, "Write"),
+            Instruction::Read => write!(f, "Read"),
+            Instruction::Set { value } => write!(f, "Set {}", value),
+            Instruction::MoveUnchecked { value } => write!(f, "MoveUnchecked {}", value),
+            Instruction::SetRange { offset, values } => {
+                write!(f, "SetRange {} {:?}", offset, values)
             }
-            Instruction::Loop { nodes } => {
-                result.push_str("Loop\n");
-                result.push_str(&indented(nodes, indent + 4));
+            Instruction::AddVector { offset, values } => {
+                write!(f, "AddVector {} {:?}", offset, values)
             }
-            Instruction::Write => {
-                result.push_str("Write\n");
+            Instruction::WriteBytes { bytes } => {
+                write!(f, "WriteBytes \"{}\"", bytes.escape_ascii())
             }
-            Instruction::Read => {
-                result.push_str("Read\n");
+        }
+    }
+}
+
+/// The instruction at `index`, counting all instructions in order with loop bodies right after their loop.
+pub fn instruction_at(nodes: &[Instruction], mut index: usize) -> Option<&Instruction> {
+    let mut stack = vec![nodes.iter()];
+    while let Some(nodes) = stack.last_mut() {
+        let Some(node) = nodes.next() else {
+            stack.pop();
+            continue;
+        };
+        if index == 0 {
+            return Some(node);
+        }
+        index -= 1;
+        if let Some(body) = node.body() {
+            stack.push(body.iter());
+        }
+    }
+    None
+}
+
+/// The 1-based line and column of the instruction at `index` of `parse(source)`, counted like `instruction_at`.
+/// Every command but `]` is an instruction of its own.
+pub fn source_position(source: &str, mut index: usize) -> Option<(usize, usize)> {
+    let (mut line, mut column) = (1, 0);
+    for char in source.chars() {
+        column += 1;
+        match char {
+            '+' | '-' | '>' | '<' | '.' | ',' | '[' => {
+                if index == 0 {
+                    return Some((line, column));
+                }
+                index -= 1;
             }
-            Instruction::Set { value } => {
-                result.push_str(&format!("Set {}\n", value));
+            '\n' => {
+                line += 1;
+                column = 0;
             }
+            _ => {}
         }
     }
+    None
+}
 
-    result
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_unmatched_brackets() {
+        assert_eq!(
+            parse("+[\n>[-]").err(),
+            Some(ParseError::UnmatchedOpen { line: 1, column: 2 })
+        );
+        assert_eq!(
+            parse("[-]\n  -]").err(),
+            Some(ParseError::UnmatchedClose { line: 2, column: 4 })
+        );
+    }
+
+    #[test]
+    fn finds_instructions_in_order() {
+        let source = "+[>[-]\n<]x.";
+        let nodes = parse(source).unwrap();
+        let found: Vec<_> = (0..7)
+            .map(|index| instruction_at(&nodes, index).unwrap().to_string())
+            .collect();
+        assert_eq!(
+            found,
+            ["Add 1", "Loop", "Move 1", "Loop", "Add -1", "Move -1", "Write"]
+        );
+        assert!(instruction_at(&nodes, 7).is_none());
+        let positions: Vec<_> = (0..8).map(|index| source_position(source, index)).collect();
+        assert_eq!(
+            positions,
+            [
+                Some((1, 1)),
+                Some((1, 2)),
+                Some((1, 3)),
+                Some((1, 4)),
+                Some((1, 5)),
+                Some((2, 1)),
+                Some((2, 4)),
+                None
+            ]
+        );
+    }
 }